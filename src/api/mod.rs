@@ -1,19 +1,25 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use crate::blockchain::Blockchain;
-use crate::miner::Handle as MinerHandle;
+use crate::miner::{self, Handle as MinerHandle};
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::worker::OrphanCounter;
 use crate::generator::generator::TransactionGenerator;
+use crate::metrics::{self, TimedLock, TimedRwLock, BLOCKCHAIN_LOCK, MEMPOOL_LOCK};
+use crate::types::address::Address;
+use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
 use crate::types::state::State;
+use crate::types::transaction::{Mempool, SignedTransaction};
 //use crate::blockchain::Blockchain;
 
 use log::info;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::hash::Hash;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -24,25 +30,215 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
     transaction_generator: TransactionGenerator, // Add transaction generator
+    mempool: Arc<Mutex<Mempool>>,
+    shared: Shared, // State shared by every listener
 }
 
+/// Number of issued block templates remembered for `/miner/submit-solution`
+const MAX_TEMPLATES: usize = 16;
+
+/// Block templates handed to external miners, keyed by template id
+#[derive(Default)]
+struct IssuedTemplates {
+    blocks: HashMap<H256, Block>,
+    order: VecDeque<H256>, // Oldest first
+}
+
+impl IssuedTemplates {
+    fn remember(&mut self, id: H256, block: Block) {
+        if self.blocks.insert(id, block).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > MAX_TEMPLATES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+
+    fn take(&mut self, id: &H256) -> Option<Block> {
+        self.order.retain(|issued| issued != id);
+        self.blocks.remove(id)
+    }
+}
+
+/// Options for the API server
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ApiConfig {
+    /// Serve endpoints that override consensus, such as `/debug/set-tip`
+    pub debug_api: bool,
+}
+
+#[derive(Clone, Default)]
+struct Shared {
+    request_counts: Arc<Mutex<BTreeMap<&'static str, u64>>>,
+    templates: Arc<Mutex<IssuedTemplates>>,
+    orphans: OrphanCounter, // Orphan blocks held by the network worker, for `/metrics`
+    config: ApiConfig,
+}
+
+/// Routes served by the API, used to key request counts; anything else is counted as "404"
+const ENDPOINTS: &[&str] = &[
+    "/miner/start",
+    "/miner/pause",
+    "/miner/resume",
+    "/miner/get-template",
+    "/miner/submit-solution",
+    "/miner/mine-on",
+    "/tx-generator/start",
+    "/tx-generator/stop",
+    "/network/ping",
+    "/blockchain/longest-chain",
+    "/blockchain/longest-chain-tx",
+    "/blockchain/longest-chain-tx-count",
+    "/blockchain/state",
+    "/blockchain/genesis-state",
+    "/blockchain/chainwork",
+    "/blockchain/height",
+    "/blockchain/tip",
+    "/blockchain/block",
+    "/blockchain/mempool",
+    "/blockchain/stats",
+    "/block/economics",
+    "/address/last-active",
+    "/tx/estimate-fee",
+    "/tx/submit",
+    "/blockchain/search",
+    "/blockchain/export",
+    "/mempool/rejections",
+    "/mempool/stats",
+    "/mempool/changes",
+    "/mempool/dropped",
+    "/debug/locks",
+    "/debug/set-tip",
+    "/debug/api-stats",
+    "/metrics",
+];
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+/// Blocks `/blockchain/stats` measures the recent block rate over unless told otherwise
+const STATS_WINDOW: usize = 10;
+
+/// Most transactions returned by one `/blockchain/search` request
+const SEARCH_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+struct SearchHit {
+    height: usize,
+    block: String,
+    tx: String,
+    sender: String,
+    receiver: String,
+    value: u64,
+    nonce: u64,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    offset: usize,
+    next_offset: Option<usize>, // Pass as `offset` to fetch the next page
+    results: Vec<SearchHit>,
+}
+
+#[derive(Serialize)]
+struct TemplateResponse {
+    template_id: String, // Echo back with the nonce to /miner/submit-solution
+    parent: String,
+    nonce: u32, // Starting nonce; any value may be submitted
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    transactions: Vec<SignedTransaction>,
+}
+
+#[derive(Deserialize)]
+struct SubmitSolution {
+    template_id: String,
+    nonce: u32,
+}
+
+#[derive(Serialize)]
+struct ChainworkResponse {
+    block: String,
+    chainwork: String,
+    chainwork_hex: String,
+}
+
+#[derive(Serialize)]
+struct FeeEstimateResponse {
+    blocks: usize,
+    samples: usize,
+    fee: u64,
+}
+
+#[derive(Serialize)]
+struct LastActiveResponse {
+    address: String,
+    last_active_height: Option<usize>, // None if the address never sent on the longest chain
+    nonce: u64,
+}
+
+impl TxResponse {
+    fn new(tx: &SignedTransaction) -> Self {
+        TxResponse {
+            hash: tx.hash().to_string(),
+            sender: (!tx.is_coinbase()).then(|| tx.sender_address().to_string()),
+            receiver: tx.transaction.receiver.to_string(),
+            value: tx.transaction.value,
+            fee: tx.transaction.fee,
+            nonce: tx.transaction.nonce,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TxResponse {
+    hash: String,
+    sender: Option<String>, // None for the coinbase, which has no signer
+    receiver: String,
+    value: u64,
+    fee: u64,
+    nonce: u64,
+}
+
+#[derive(Serialize)]
+struct BlockResponse {
+    hash: String,
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    transactions: Vec<TxResponse>,
+}
+
+#[derive(Serialize)]
+struct EconomicsResponse {
+    fees_collected: u64,
+    reward: u64,
+    coinbase_recipient: Option<String>,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
+        respond_result!($req, $success, $message, 200)
+    }};
+    ( $req:expr, $success:expr, $message:expr, $status:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
         let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
+            .with_header(content_type)
+            .with_status_code($status);
         $req.respond(resp).unwrap();
     }};
 }
@@ -55,21 +251,56 @@ macro_rules! respond_json {
     }};
 }
 
+fn parse_hash(input: &str) -> Result<H256, String> {
+    H256::from_hex(input).map_err(|_| format!("invalid hash: {}", input))
+}
+
+fn parse_address(input: &str) -> Result<Address, String> {
+    input.parse().map_err(|_| format!("invalid address: {}", input))
+}
+
 impl Server {
+    /// Start one HTTP listener per address, all sharing the same handlers.
+    /// Returns the bound addresses in the same order as `addrs`.
     pub fn start(
-        addr: std::net::SocketAddr,
+        addrs: &[std::net::SocketAddr],
         miner: &MinerHandle,
         network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
         transaction_generator: &TransactionGenerator, // Pass transaction generator here 
-    ) {
+        mempool: &Arc<Mutex<Mempool>>,
+        orphans: &OrphanCounter,
+        config: ApiConfig,
+    ) -> Vec<std::net::SocketAddr> {
+        let shared = Shared { orphans: orphans.clone(), config, ..Default::default() };
+        addrs
+            .iter()
+            .map(|addr| {
+                Self::start_listener(*addr, miner, network, blockchain, transaction_generator, mempool, &shared)
+            })
+            .collect()
+    }
+
+    fn start_listener(
+        addr: std::net::SocketAddr,
+        miner: &MinerHandle,
+        network: &NetworkServerHandle,
+        blockchain: &Arc<RwLock<Blockchain>>,
+        transaction_generator: &TransactionGenerator,
+        mempool: &Arc<Mutex<Mempool>>,
+        shared: &Shared,
+    ) -> std::net::SocketAddr {
         let handle = HTTPServer::http(&addr).unwrap();
+        // The bound address differs from `addr` when binding to port 0
+        let addr = handle.server_addr();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
             transaction_generator: transaction_generator.clone(), // Clone transaction generator 
+            mempool: Arc::clone(mempool),
+            shared: shared.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -77,6 +308,11 @@ impl Server {
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let transaction_generator = server.transaction_generator.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let orphans = server.shared.orphans.clone();
+                let request_counts = Arc::clone(&server.shared.request_counts);
+                let templates = Arc::clone(&server.shared.templates);
+                let config = server.shared.config;
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -87,6 +323,8 @@ impl Server {
                             return;
                         }
                     };
+                    let endpoint = ENDPOINTS.iter().find(|e| **e == url.path()).copied().unwrap_or("404");
+                    *request_counts.lock().unwrap().entry(endpoint).or_insert(0) += 1;
                     match url.path() {
                         "/miner/start" => {
                             let params = url.query_pairs();
@@ -94,7 +332,7 @@ impl Server {
                             let lambda = match params.get("lambda") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing lambda");
+                                    respond_result!(req, false, "missing lambda", 400);
                                     return;
                                 }
                             };
@@ -104,14 +342,165 @@ impl Server {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("error parsing lambda: {}", e)
+                                        format!("error parsing lambda: {}", e),
+                                        400
                                     );
                                     return;
                                 }
                             };
-                            miner.start(lambda);
+                            // With a count the miner pauses on its own after that many blocks
+                            match params.get("count").map(|count| count.parse::<u64>()) {
+                                None => miner.start(lambda),
+                                Some(Ok(0)) => {
+                                    respond_result!(req, false, "count must be positive");
+                                    return;
+                                }
+                                Some(Ok(count)) => miner.start_count(lambda, count),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing count: {}", e));
+                                    return;
+                                }
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/resume" => {
+                            miner.resume();
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/get-template" => {
+                            let block = match miner::assemble_block(&blockchain, &mempool) {
+                                Some(block) => block,
+                                None => {
+                                    respond_result!(req, false, "no valid transactions to mine");
+                                    return;
+                                }
+                            };
+                            let template_id = block.hash();
+                            let payload = TemplateResponse {
+                                template_id: template_id.to_string(),
+                                parent: block.header.parent.to_string(),
+                                nonce: block.header.nonce,
+                                difficulty: block.get_difficulty().to_string(),
+                                timestamp: block.header.timestamp,
+                                merkle_root: block.header.merkle_root.to_string(),
+                                transactions: block.content.transactions.clone(),
+                            };
+                            templates.lock().unwrap().remember(template_id, block);
+                            respond_json!(req, payload);
+                        }
+                        "/miner/submit-solution" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let solution: SubmitSolution = match serde_json::from_str(&body) {
+                                Ok(s) => s,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing solution: {}", e));
+                                    return;
+                                }
+                            };
+                            let template_id = match parse_hash(&solution.template_id) {
+                                Ok(id) => id,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+
+                            let mut templates = templates.lock().unwrap();
+                            let mut block = match templates.blocks.get(&template_id) {
+                                Some(block) => block.clone(),
+                                None => {
+                                    respond_result!(req, false, "unknown template");
+                                    return;
+                                }
+                            };
+                            block.header.nonce = solution.nonce;
+                            let block_hash = block.hash();
+                            if block_hash > block.get_difficulty() {
+                                respond_result!(req, false, "block does not meet difficulty target");
+                                return;
+                            }
+                            templates.take(&template_id);
+                            drop(templates);
+
+                            let mut chain = blockchain.write_timed(&BLOCKCHAIN_LOCK);
+                            let (abandoned, adopted) = match chain.try_insert(&block) {
+                                Ok(Some(reorg)) => (chain.block_transactions(&reorg.removed), chain.block_transactions(&reorg.added)),
+                                Ok(None) => (Vec::new(), block.content.transactions.clone()),
+                                Err(reason) => {
+                                    respond_result!(req, false, format!("block rejected by the blockchain: {}", reason));
+                                    return;
+                                }
+                            };
+                            // The tip may have moved since the template was handed out
+                            let tip_state = (chain.tip() == block_hash).then(|| chain.get_state(&block_hash).unwrap());
+                            drop(chain);
+                            if let Some(tip_state) = tip_state {
+                                mempool.lock_timed(&MEMPOOL_LOCK).reorganize(abandoned, &adopted, &tip_state);
+                            }
+                            network.broadcast(Message::NewBlockHashes(vec![block_hash]));
+                            respond_result!(req, true, block_hash.to_string());
+                        }
+                        "/miner/mine-on" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let parent = match params.get("parent").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing parent");
+                                    return;
+                                }
+                            };
+                            if !blockchain.read_timed(&BLOCKCHAIN_LOCK).contains(&parent) {
+                                respond_result!(req, false, format!("block not found: {}", parent));
+                                return;
+                            }
+                            let block = match miner::mine_on(&blockchain, &mempool, parent) {
+                                Some(block) => block,
+                                None => {
+                                    respond_result!(req, false, "no valid transactions to mine on this parent");
+                                    return;
+                                }
+                            };
+                            let block_hash = block.hash();
+                            let mut chain = blockchain.write_timed(&BLOCKCHAIN_LOCK);
+                            let (abandoned, adopted) = match chain.try_insert(&block) {
+                                Ok(Some(reorg)) => (chain.block_transactions(&reorg.removed), chain.block_transactions(&reorg.added)),
+                                Ok(None) => (Vec::new(), block.content.transactions.clone()),
+                                Err(reason) => {
+                                    respond_result!(req, false, format!("block rejected by the blockchain: {}", reason));
+                                    return;
+                                }
+                            };
+                            let tip_state = (chain.tip() == block_hash).then(|| chain.get_state(&block_hash).unwrap());
+                            drop(chain);
+                            // Transactions on a side branch stay pooled until a block with them wins
+                            if let Some(tip_state) = tip_state {
+                                mempool.lock_timed(&MEMPOOL_LOCK).reorganize(abandoned, &adopted, &tip_state);
+                            }
+                            network.broadcast(Message::NewBlockHashes(vec![block_hash]));
+                            respond_result!(req, true, block_hash.to_string());
+                        }
                         "/tx-generator/start" => {
                             // unimplemented!()
                             let params = url.query_pairs();
@@ -119,7 +508,7 @@ impl Server {
                             let theta = match params.get("theta") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing theta");
+                                    respond_result!(req, false, "missing theta", 400);
                                     return;
                                 }
                             };
@@ -130,35 +519,57 @@ impl Server {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("error parsing theta: {}", e)
+                                        format!("error parsing theta: {}", e),
+                                        400
                                     );
                                     return;
                                 }
                             };
 
-                            transaction_generator.start(theta);
+                            if !transaction_generator.start(theta) {
+                                respond_result!(req, false, "transaction generator already running");
+                                return;
+                            }
                             //respond_result!(req, false, "unimplemented!");
                             respond_result!(req, true, "Transaction generator started");
                         }
+                        "/tx-generator/stop" => {
+                            transaction_generator.stop();
+                            respond_result!(req, true, "ok");
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
                         "/blockchain/longest-chain" => {
-                            let blockchain = blockchain.lock().unwrap();
-                            let v = blockchain.all_blocks_in_longest_chain();
-                            let v_string: Vec<String> = v.into_iter().map(|h|h.to_string()).collect();
-                            respond_json!(req, v_string);
+                            // Optional `start`/`end` heights select part of the chain, end exclusive
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut bounds = [0, usize::MAX];
+                            for (bound, name) in bounds.iter_mut().zip(["start", "end"]) {
+                                match params.get(name).map(|v| v.parse::<usize>()) {
+                                    Some(Ok(value)) => *bound = value,
+                                    Some(Err(e)) => {
+                                        respond_result!(req, false, format!("error parsing {}: {}", name, e));
+                                        return;
+                                    }
+                                    None => {}
+                                }
+                            }
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let v = blockchain.blocks_in_longest_chain_range(bounds[0], bounds[1]);
+                            let v_string: Vec<String> = v.iter().map(|h|h.to_string()).collect();
                             drop(blockchain);
+                            respond_json!(req, v_string);
                         }
                         "/blockchain/longest-chain-tx" => {
                             // unimplemented!()
-                            let blockchain = blockchain.lock().unwrap();
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
                             let longest_chain = blockchain.all_blocks_in_longest_chain();
                             let mut tx_chain: Vec<Vec<String>> = Vec::new();
 
                             for block_hash in longest_chain {
-                                if let Some(block) = blockchain.blocks.get(&block_hash) {
+                                if let Some(block) = blockchain.get_block(&block_hash) {
                                     let tx_hashes: Vec<String> = block
                                         .content
                                         .transactions
@@ -184,7 +595,7 @@ impl Server {
                             let block_param = match params.get("block") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing block parameter");
+                                    respond_result!(req, false, "missing block parameter", 400);
                                     return;
                                 }
                             };
@@ -195,7 +606,8 @@ impl Server {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("Invalid block index: {}", e)
+                                        format!("Invalid block index: {}", e),
+                                        400
                                     );
                                     return;
                                 }
@@ -203,40 +615,451 @@ impl Server {
                             };
 
 
-                            let blockchain = blockchain.lock().unwrap();
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
                             let longest_chain = blockchain.all_blocks_in_longest_chain();
 
                             if block_index >= longest_chain.len() {
                                 respond_result!(
                                     req,
                                     false,
-                                    format!("block index {} exceeds the longest chain length", block_index)
+                                    format!("block index {} exceeds the longest chain length", block_index),
+                                    404
                                 );
                                 return;
                             }
 
                             let block_hash = longest_chain[block_index];
 
-                            let state_map = blockchain.states.clone();
-
-                            if let Some(state) = state_map.get(&block_hash) {
-                                let state = state.lock().unwrap();
+                            if let Some(state) = blockchain.state_at(&block_hash) {
                                 let state_representation: Vec<String> = state
                                     .get_state_snapshot()
                                     .into_iter()
                                     .map(|(address, (nonce, balance))| format!("({}, {}, {})", address, nonce, balance))
                                     .collect();
                                 respond_json!(req, state_representation);
-                                drop(state);
                             } else {
                                 respond_result!(
                                     req,
                                     false,
-                                    format!("State not found for block: {}", block_hash)
+                                    format!("State not found for block: {}", block_hash),
+                                    500
                                 );
                             }
                             drop(blockchain);
                         }
+                        "/blockchain/genesis-state" => {
+                            let state = blockchain.read_timed(&BLOCKCHAIN_LOCK).genesis_state();
+                            let state_representation: Vec<String> = state
+                                .get_state_snapshot()
+                                .into_iter()
+                                .map(|(address, (nonce, balance))| format!("({}, {}, {})", address, nonce, balance))
+                                .collect();
+                            respond_json!(req, state_representation);
+                        }
+                        "/blockchain/height" => {
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let height = blockchain.height_of(&blockchain.tip()).unwrap();
+                            drop(blockchain);
+                            respond_json!(req, height);
+                        }
+                        "/blockchain/tip" => {
+                            let tip = blockchain.read_timed(&BLOCKCHAIN_LOCK).tip();
+                            respond_json!(req, tip.to_string());
+                        }
+                        "/blockchain/chainwork" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            // Default to the tip when no block is given
+                            let block_hash = match params.get("block").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => blockchain.tip(),
+                            };
+                            match blockchain.chainwork(&block_hash) {
+                                Some(work) => {
+                                    let payload = ChainworkResponse {
+                                        block: block_hash.to_string(),
+                                        chainwork: work.to_string(),
+                                        chainwork_hex: format!("{:#x}", work),
+                                    };
+                                    respond_json!(req, payload);
+                                }
+                                None => {
+                                    respond_result!(req, false, format!("block not found: {}", block_hash));
+                                }
+                            }
+                            drop(blockchain);
+                        }
+                        "/blockchain/block" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block_hash = match params.get("hash").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e, 404);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing hash", 404);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let payload = blockchain.get_block(&block_hash).map(|block| BlockResponse {
+                                hash: block_hash.to_string(),
+                                parent: block.header.parent.to_string(),
+                                nonce: block.header.nonce,
+                                difficulty: block.get_difficulty().to_string(),
+                                timestamp: block.header.timestamp,
+                                merkle_root: block.header.merkle_root.to_string(),
+                                transactions: block.content.transactions.iter().map(TxResponse::new).collect(),
+                            });
+                            drop(blockchain);
+                            match payload {
+                                Some(payload) => {
+                                    respond_json!(req, payload);
+                                }
+                                None => {
+                                    respond_result!(req, false, format!("block not found: {}", block_hash), 404);
+                                }
+                            }
+                        }
+                        "/blockchain/mempool" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+                                Some(Ok(limit)) => limit,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing limit: {}", e));
+                                    return;
+                                }
+                                None => usize::MAX,
+                            };
+                            // In the order the miner would take them, highest fee first
+                            let pending = mempool.lock_timed(&MEMPOOL_LOCK).get_transactions_for_block(limit);
+                            let payload: Vec<TxResponse> = pending.iter().map(TxResponse::new).collect();
+                            respond_json!(req, payload);
+                        }
+                        "/blockchain/stats" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let window = match params.get("window").map(|v| v.parse::<usize>()) {
+                                Some(Ok(window)) => window,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing window: {}", e), 400);
+                                    return;
+                                }
+                                None => STATS_WINDOW,
+                            };
+                            let stats = blockchain.read_timed(&BLOCKCHAIN_LOCK).chain_stats(window);
+                            respond_json!(req, stats);
+                        }
+                        "/block/economics" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block_hash = match params.get("hash").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            match blockchain.economics(&block_hash) {
+                                Some(economics) => {
+                                    let payload = EconomicsResponse {
+                                        fees_collected: economics.fees_collected,
+                                        reward: economics.reward,
+                                        coinbase_recipient: economics.coinbase_recipient.map(|a| a.to_string()),
+                                    };
+                                    respond_json!(req, payload);
+                                }
+                                None => {
+                                    respond_result!(req, false, format!("block not found: {}", block_hash));
+                                }
+                            }
+                            drop(blockchain);
+                        }
+                        "/address/last-active" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| parse_address(v)) {
+                                Some(Ok(address)) => address,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let nonce = blockchain
+                                .state_at(&blockchain.tip())
+                                .and_then(|state| state.accounts.get(&address).map(|(nonce, _)| *nonce))
+                                .unwrap_or(0);
+                            let payload = LastActiveResponse {
+                                address: address.to_string(),
+                                last_active_height: blockchain.last_active_height(&address),
+                                nonce,
+                            };
+                            drop(blockchain);
+                            respond_json!(req, payload);
+                        }
+                        "/tx/submit" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let tx: SignedTransaction = match serde_json::from_str(&body) {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing transaction: {}", e));
+                                    return;
+                                }
+                            };
+                            let tx_hash = tx.hash();
+                            if let Err(e) = mempool.lock_timed(&MEMPOOL_LOCK).add_transaction(tx) {
+                                respond_result!(req, false, format!("transaction rejected by the mempool: {}", e));
+                                return;
+                            }
+                            network.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
+                            respond_result!(req, true, tx_hash.to_string());
+                        }
+                        "/tx/estimate-fee" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks").map(|v| v.parse::<usize>()) {
+                                Some(Ok(blocks)) => blocks,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing blocks: {}", e));
+                                    return;
+                                }
+                                None => 10,
+                            };
+                            let mut fees = blockchain.read_timed(&BLOCKCHAIN_LOCK).recent_fees(blocks);
+                            fees.sort_unstable();
+                            // Median fee; nothing to go on means any fee will do
+                            let fee = fees.get(fees.len() / 2).copied().unwrap_or(0);
+                            let payload = FeeEstimateResponse { blocks, samples: fees.len(), fee };
+                            respond_json!(req, payload);
+                        }
+                        "/blockchain/search" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let parse_u64 = |name: &str| -> Result<Option<u64>, String> {
+                                params
+                                    .get(name)
+                                    .map(|v| v.parse::<u64>().map_err(|e| format!("error parsing {}: {}", name, e)))
+                                    .transpose()
+                            };
+                            let filters = parse_u64("min_value").and_then(|min_value| {
+                                let max_value = parse_u64("max_value")?;
+                                let offset = parse_u64("offset")?.unwrap_or(0) as usize;
+                                let sender = params.get("sender").map(|v| parse_address(v)).transpose()?;
+                                let receiver = params.get("receiver").map(|v| parse_address(v)).transpose()?;
+                                Ok((min_value, max_value, sender, receiver, offset))
+                            });
+                            let (min_value, max_value, sender, receiver, offset) = match filters {
+                                Ok(f) => f,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let matches = blockchain
+                                .all_blocks_in_longest_chain()
+                                .into_iter()
+                                .enumerate()
+                                .flat_map(|(height, block_hash)| {
+                                    blockchain.get_block(&block_hash).unwrap()
+                                        .content
+                                        .transactions
+                                        .iter()
+                                        .map(move |tx| (height, block_hash, tx))
+                                })
+                                .filter(|(_, _, tx)| {
+                                    min_value.is_none_or(|min| tx.transaction.value >= min)
+                                        && max_value.is_none_or(|max| tx.transaction.value <= max)
+                                        && receiver.is_none_or(|r| tx.transaction.receiver == r)
+                                        && sender.is_none_or(|s| tx.sender_address() == s)
+                                });
+                            // Take one extra match to learn whether another page exists
+                            let mut results: Vec<SearchHit> = matches
+                                .skip(offset)
+                                .take(SEARCH_LIMIT + 1)
+                                .map(|(height, block_hash, tx)| SearchHit {
+                                    height,
+                                    block: block_hash.to_string(),
+                                    tx: tx.hash().to_string(),
+                                    sender: tx.sender_address().to_string(),
+                                    receiver: tx.transaction.receiver.to_string(),
+                                    value: tx.transaction.value,
+                                    nonce: tx.transaction.nonce,
+                                })
+                                .collect();
+                            drop(blockchain);
+                            let next_offset = if results.len() > SEARCH_LIMIT {
+                                results.truncate(SEARCH_LIMIT);
+                                Some(offset + SEARCH_LIMIT)
+                            } else {
+                                None
+                            };
+                            respond_json!(req, SearchResponse { offset, next_offset, results });
+                        }
+                        "/blockchain/export" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let blocks: Vec<Block> = blockchain
+                                .all_blocks_in_longest_chain()
+                                .iter()
+                                .filter_map(|hash| blockchain.get_block(hash).cloned())
+                                .collect();
+                            drop(blockchain);
+                            match params.get("format").map(|f| f.as_str()) {
+                                None | Some("json") => {
+                                    respond_json!(req, blocks);
+                                }
+                                Some("bin") => {
+                                    // Raw bincode of Vec<Block>, for clients built against this crate
+                                    let content_type = "Content-Type: application/octet-stream".parse::<Header>().unwrap();
+                                    let resp = Response::from_data(bincode::serialize(&blocks).unwrap())
+                                        .with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                }
+                                Some(other) => {
+                                    respond_result!(req, false, format!("unknown format: {}", other));
+                                }
+                            }
+                        }
+                        "/mempool/rejections" => {
+                            let rejections = mempool.lock_timed(&MEMPOOL_LOCK).recent_rejections();
+                            respond_json!(req, rejections);
+                        }
+                        "/mempool/stats" => {
+                            let stats = mempool.lock_timed(&MEMPOOL_LOCK).stats();
+                            respond_json!(req, stats);
+                        }
+                        "/mempool/dropped" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+                                Some(Ok(limit)) => limit,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing limit: {}", e));
+                                    return;
+                                }
+                                None => 100,
+                            };
+                            let dropped = mempool.lock_timed(&MEMPOOL_LOCK).recent_drops(limit);
+                            respond_json!(req, dropped);
+                        }
+                        "/mempool/changes" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let since = match params.get("since").map(|v| v.parse::<u64>()) {
+                                Some(Ok(since)) => since,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing since: {}", e));
+                                    return;
+                                }
+                                None => 0,
+                            };
+                            let changes = mempool.lock_timed(&MEMPOOL_LOCK).changes_since(since);
+                            respond_json!(req, changes);
+                        }
+                        "/debug/set-tip" => {
+                            if !config.debug_api {
+                                respond_result!(req, false, "debug API is disabled; start the node with --debug-api");
+                                return;
+                            }
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let new_tip = match params.get("hash").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let mut chain = blockchain.write_timed(&BLOCKCHAIN_LOCK);
+                            let old_chain = chain.all_blocks_in_longest_chain();
+                            if !chain.force_tip(&new_tip) {
+                                respond_result!(req, false, format!("block not found: {}", new_tip));
+                                return;
+                            }
+                            let new_chain = chain.all_blocks_in_longest_chain();
+                            let transactions_of = |hashes: &[H256], other: &[H256]| -> Vec<SignedTransaction> {
+                                hashes
+                                    .iter()
+                                    .filter(|hash| !other.contains(hash))
+                                    .flat_map(|hash| chain.get_block(hash).unwrap().content.transactions.clone())
+                                    .collect()
+                            };
+                            let abandoned = transactions_of(&old_chain, &new_chain);
+                            let adopted = transactions_of(&new_chain, &old_chain);
+                            let tip_state = chain.state_at(&new_tip).unwrap();
+                            drop(chain);
+
+                            // Keep only what the new tip accepts, then return abandoned transactions to the pool
+                            mempool.lock_timed(&MEMPOOL_LOCK).reorganize(abandoned, &adopted, &tip_state);
+                            respond_result!(req, true, new_tip.to_string());
+                        }
+                        "/debug/locks" => {
+                            let reports: Vec<metrics::LockReport> = metrics::all_lock_stats()
+                                .iter()
+                                .map(|stats| stats.report())
+                                .collect();
+                            respond_json!(req, reports);
+                        }
+                        "/debug/api-stats" => {
+                            let counts = request_counts.lock().unwrap().clone();
+                            respond_json!(req, counts);
+                        }
+                        "/metrics" => {
+                            // Prometheus text format: one `name value` line per gauge
+                            let chain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                            let tip_height = chain.height_of(&chain.tip()).unwrap();
+                            let total_blocks = chain.block_count();
+                            drop(chain);
+                            let mempool_size = mempool.lock_timed(&MEMPOOL_LOCK).stats().count;
+                            let body = format!(
+                                "longest_chain_blocks {}\nblocks_seen_total {}\norphan_blocks {}\nmempool_transactions {}\ntip_height {}\n",
+                                tip_height + 1,
+                                total_blocks,
+                                orphans.count(),
+                                mempool_size,
+                                tip_height,
+                            );
+                            let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(body).with_header(content_type)).unwrap();
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -256,5 +1079,703 @@ impl Server {
             }
         });
         info!("API server listening at {}", &addr);
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner;
+    use crate::types::block::generate_random_block;
+    use crate::types::merkle::MerkleTree;
+    use ntest::timeout;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use crate::network::server::TestReceiver as NetworkTestReceiver;
+    use crate::types::block::Header as BlockHeader;
+
+    /// Start an API server on an ephemeral port backed by `blockchain`
+    fn start_test_server(blockchain: &Arc<RwLock<Blockchain>>) -> std::net::SocketAddr {
+        start_test_servers(blockchain, 1)[0]
+    }
+
+    fn start_test_servers(blockchain: &Arc<RwLock<Blockchain>>, count: usize) -> Vec<std::net::SocketAddr> {
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        start_test_servers_with(blockchain, &mempool, count).0
+    }
+
+    /// Like `start_test_servers`, but with a caller-owned mempool and the network receiver kept alive
+    fn start_test_servers_with(
+        blockchain: &Arc<RwLock<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        count: usize,
+    ) -> (Vec<std::net::SocketAddr>, NetworkTestReceiver) {
+        start_test_servers_with_config(blockchain, mempool, count, ApiConfig::default())
+    }
+
+    fn start_test_servers_with_config(
+        blockchain: &Arc<RwLock<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        count: usize,
+        config: ApiConfig,
+    ) -> (Vec<std::net::SocketAddr>, NetworkTestReceiver) {
+        let (network, network_receiver) = NetworkServerHandle::new_for_test();
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(blockchain, mempool, &network, miner::MinerConfig::default(), Address::default());
+        let key_pair = Arc::new(Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap());
+        let generator = TransactionGenerator::new(mempool.clone(), network.clone(), key_pair, blockchain.clone());
+        let addrs = vec!["127.0.0.1:0".parse().unwrap(); count];
+        (Server::start(&addrs, &miner, &network, blockchain, &generator, mempool, &OrphanCounter::default(), config), network_receiver)
+    }
+
+    /// Issue a GET request and return the status code, Content-Type, and body
+    fn http_get(addr: std::net::SocketAddr, path: &str) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr).unwrap();
+        read_response(stream)
+    }
+
+    /// Issue a POST request with a JSON body and return the status code, Content-Type, and body
+    fn http_post(addr: std::net::SocketAddr, path: &str, body: &str) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            addr,
+            body.len(),
+            body
+        )
+        .unwrap();
+        read_response(stream)
+    }
+
+    fn read_response(mut stream: TcpStream) -> (u16, String, Vec<u8>) {
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+
+        let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&raw[..split]).to_string();
+        let body = raw[split + 4..].to_vec();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let content_type = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                if name.eq_ignore_ascii_case("content-type") { Some(value.trim().to_string()) } else { None }
+            })
+            .unwrap_or_default();
+        (status, content_type, body)
+    }
+
+    fn chain_of(length: usize) -> Arc<RwLock<Blockchain>> {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        for _ in 0..length {
+            let block = generate_random_block(&blockchain.tip());
+            assert!(blockchain.insert(&block));
+        }
+        Arc::new(RwLock::new(blockchain))
+    }
+
+    #[test]
+    fn height_and_tip_endpoints_follow_the_chain() {
+        let blockchain = chain_of(5);
+        let addr = start_test_server(&blockchain);
+
+        let (status, _, body) = http_get(addr, "/blockchain/height");
+        assert_eq!(status, 200);
+        assert_eq!(serde_json::from_slice::<usize>(&body).unwrap(), 5);
+        let (status, _, body) = http_get(addr, "/blockchain/tip");
+        assert_eq!(status, 200);
+        let tip = blockchain.read().unwrap().tip();
+        assert_eq!(serde_json::from_slice::<String>(&body).unwrap(), tip.to_string());
+
+        let block = generate_random_block(&tip);
+        assert!(blockchain.write().unwrap().insert(&block));
+        let (_, _, body) = http_get(addr, "/blockchain/height");
+        assert_eq!(serde_json::from_slice::<usize>(&body).unwrap(), 6);
+        let (_, _, body) = http_get(addr, "/blockchain/tip");
+        assert_eq!(serde_json::from_slice::<String>(&body).unwrap(), block.hash().to_string());
+    }
+
+    #[test]
+    fn metrics_report_chain_and_mempool_gauges() {
+        let blockchain = chain_of(3);
+        // A side block counts as seen but not as part of the longest chain
+        let genesis_hash = blockchain.read().unwrap().genesis().hash();
+        assert!(blockchain.write().unwrap().insert(&generate_random_block(&genesis_hash)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::default(), 1, 0, &key);
+        mempool.lock().unwrap().add_transaction(tx).unwrap();
+        let (addrs, _network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+
+        let (status, content_type, body) = http_get(addrs[0], "/metrics");
+        assert_eq!(status, 200);
+        assert!(content_type.starts_with("text/plain"));
+        let metrics: HashMap<String, u64> = String::from_utf8(body)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (name, value) = line.split_once(' ').unwrap();
+                (name.to_string(), value.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(metrics["longest_chain_blocks"], 4);
+        assert_eq!(metrics["blocks_seen_total"], 5);
+        assert_eq!(metrics["orphan_blocks"], 0);
+        assert_eq!(metrics["mempool_transactions"], 1);
+        assert_eq!(metrics["tip_height"], 3);
+    }
+
+    #[test]
+    fn export_binary_round_trips() {
+        let blockchain = chain_of(3);
+        let addr = start_test_server(&blockchain);
+        let (status, content_type, body) = http_get(addr, "/blockchain/export?format=bin");
+        assert_eq!(status, 200);
+        assert_eq!(content_type, "application/octet-stream");
+
+        let blocks: Vec<Block> = bincode::deserialize(&body).unwrap();
+        let hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        assert_eq!(hashes, blockchain.read().unwrap().all_blocks_in_longest_chain());
+        assert_eq!(bincode::serialize(&blocks).unwrap(), body);
+    }
+
+    #[test]
+    fn export_defaults_to_json() {
+        let blockchain = chain_of(2);
+        let addr = start_test_server(&blockchain);
+        let (status, content_type, body) = http_get(addr, "/blockchain/export");
+        assert_eq!(status, 200);
+        assert_eq!(content_type, "application/json");
+        let blocks: Vec<Block> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].hash(), blockchain.read().unwrap().tip());
+    }
+
+    #[test]
+    fn listeners_share_handlers() {
+        let blockchain = chain_of(2);
+        let addrs = start_test_servers(&blockchain, 2);
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+
+        let (status_a, _, body_a) = http_get(addrs[0], "/blockchain/longest-chain");
+        let (status_b, _, body_b) = http_get(addrs[1], "/blockchain/longest-chain");
+        assert_eq!((status_a, status_b), (200, 200));
+        assert_eq!(body_a, body_b);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reads_proceed_during_inserts() {
+        let blockchain = chain_of(0);
+        let addr = start_test_server(&blockchain);
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut seen = Vec::new();
+                    for _ in 0..25 {
+                        let (status, _, body) = http_get(addr, "/blockchain/longest-chain");
+                        assert_eq!(status, 200);
+                        let chain: Vec<String> = serde_json::from_slice(&body).unwrap();
+                        seen.push(chain);
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        for _ in 0..50 {
+            let mut chain = blockchain.write_timed(&BLOCKCHAIN_LOCK);
+            let block = generate_random_block(&chain.tip());
+            assert!(chain.insert(&block));
+        }
+
+        let full: Vec<String> = blockchain
+            .read()
+            .unwrap()
+            .all_blocks_in_longest_chain()
+            .into_iter()
+            .map(|h| h.to_string())
+            .collect();
+        for reader in readers {
+            let seen = reader.join().unwrap();
+            // Every snapshot is a prefix of the final chain, and snapshots never shrink
+            for window in seen.windows(2) {
+                assert!(window[0].len() <= window[1].len());
+            }
+            for chain in seen {
+                assert_eq!(chain[..], full[..chain.len()]);
+            }
+        }
+    }
+
+    /// Chain of three blocks, each carrying one ICO transfer of 10, 20, and 30 to alternating receivers
+    fn chain_with_transfers() -> Arc<RwLock<Blockchain>> {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        for nonce in 1..=3u64 {
+            let receiver = Address::from([nonce as u8 % 2; 20]);
+            let tx = SignedTransaction::create(receiver, nonce * 10, nonce, &key);
+            let mut block = generate_random_block(&blockchain.tip());
+            block.header.merkle_root = MerkleTree::new(std::slice::from_ref(&tx)).root();
+            block.content.transactions = vec![tx];
+            assert!(blockchain.insert(&block));
+        }
+        Arc::new(RwLock::new(blockchain))
+    }
+
+    #[test]
+    fn block_endpoint_returns_full_block() {
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+        let (tip, block) = {
+            let blockchain = blockchain.read().unwrap();
+            let tip = blockchain.tip();
+            (tip, blockchain.get_block(&tip).unwrap().clone())
+        };
+
+        let (status, _, body) = http_get(addr, &format!("/blockchain/block?hash={}", tip));
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["hash"], tip.to_string());
+        assert_eq!(response["parent"], block.header.parent.to_string());
+        assert_eq!(response["nonce"], block.header.nonce);
+        assert_eq!(response["difficulty"], block.header.difficulty.to_string());
+        assert_eq!(response["timestamp"], block.header.timestamp as u64);
+        assert_eq!(response["merkle_root"], block.header.merkle_root.to_string());
+        let tx = &block.content.transactions[0];
+        let transactions = response["transactions"].as_array().unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0]["sender"], tx.sender_address().to_string());
+        assert_eq!(transactions[0]["receiver"], tx.transaction.receiver.to_string());
+        assert_eq!(transactions[0]["value"], 30);
+        assert_eq!(transactions[0]["nonce"], 3);
+
+        for query in [format!("hash={}", H256::from([7u8; 32])), "hash=zz".to_string(), String::new()] {
+            let (status, _, body) = http_get(addr, &format!("/blockchain/block?{}", query));
+            assert_eq!(status, 404);
+            let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(response["success"], false);
+        }
+    }
+
+    #[test]
+    fn mempool_endpoint_lists_pending_transactions() {
+        let blockchain = chain_of(0);
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (addrs, _network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let receiver = Address::from([1u8; 20]);
+        let txs: Vec<SignedTransaction> =
+            (1..=3).map(|nonce| SignedTransaction::create_with_fee(receiver, 5, nonce, nonce, &key)).collect();
+        for tx in &txs {
+            mempool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        }
+
+        let (status, _, body) = http_get(addrs[0], "/blockchain/mempool");
+        assert_eq!(status, 200);
+        let pending: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let hashes: Vec<&str> = pending.as_array().unwrap().iter().map(|tx| tx["hash"].as_str().unwrap()).collect();
+        let expected: Vec<String> = txs.iter().rev().map(|tx| tx.hash().to_string()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(pending[0]["sender"], txs[2].sender_address().to_string());
+        assert_eq!(pending[0]["receiver"], receiver.to_string());
+        assert_eq!(pending[0]["value"], 5);
+        assert_eq!(pending[0]["nonce"], 3);
+
+        let (_, _, body) = http_get(addrs[0], "/blockchain/mempool?limit=1");
+        let pending: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(pending.as_array().unwrap().len(), 1);
+        let (_, _, body) = http_get(addrs[0], "/blockchain/mempool?limit=x");
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn economics_reports_block_totals() {
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+        let tip = blockchain.read().unwrap().tip();
+        assert_eq!(blockchain.read().unwrap().get_block(&tip).unwrap().content.transactions.len(), 1);
+
+        let (status, _, body) = http_get(addr, &format!("/block/economics?hash={}", tip));
+        assert_eq!(status, 200);
+        let economics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(economics["fees_collected"], 0);
+        assert_eq!(economics["reward"], 0);
+        assert!(economics["coinbase_recipient"].is_null());
+
+        let (_, _, body) = http_get(addr, &format!("/block/economics?hash={}", H256::from([7u8; 32])));
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn estimate_fee_uses_recent_blocks() {
+        // Three blocks with one transaction each, none of which pays a fee
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+
+        let (status, _, body) = http_get(addr, "/tx/estimate-fee?blocks=2");
+        assert_eq!(status, 200);
+        let estimate: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(estimate["blocks"], 2);
+        assert_eq!(estimate["samples"], 2);
+        assert_eq!(estimate["fee"], 0);
+
+        let (_, _, body) = http_get(addr, "/tx/estimate-fee");
+        let estimate: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(estimate["samples"], 3);
+
+        let (_, _, body) = http_get(addr, "/tx/estimate-fee?blocks=x");
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn last_active_reports_height_and_nonce() {
+        // The ICO account spends once in each of three blocks
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let ico = Address::from_public_key_bytes(key.public_key().as_ref());
+
+        let (status, _, body) = http_get(addr, &format!("/address/last-active?address={}", ico));
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["last_active_height"], 3);
+        assert_eq!(response["nonce"], 3);
+
+        // A receiver has never sent anything
+        let (_, _, body) = http_get(addr, &format!("/address/last-active?address={}", Address::from([1u8; 20])));
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(response["last_active_height"].is_null());
+        assert_eq!(response["nonce"], 0);
+    }
+
+    fn search(addr: std::net::SocketAddr, query: &str) -> serde_json::Value {
+        let (status, _, body) = http_get(addr, &format!("/blockchain/search?{}", query));
+        assert_eq!(status, 200);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn search_combines_filters() {
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+
+        let values = |response: &serde_json::Value| -> Vec<u64> {
+            response["results"].as_array().unwrap().iter().map(|hit| hit["value"].as_u64().unwrap()).collect()
+        };
+        assert_eq!(values(&search(addr, "min_value=15")), vec![20, 30]);
+        assert_eq!(values(&search(addr, "min_value=15&max_value=25")), vec![20]);
+        let receiver = Address::from([1u8; 20]);
+        assert_eq!(values(&search(addr, &format!("receiver={}", receiver))), vec![10, 30]);
+        assert_eq!(values(&search(addr, &format!("receiver={}&min_value=15", receiver))), vec![30]);
+
+        let first = &search(addr, "max_value=10")["results"][0];
+        assert_eq!(first["height"], 1);
+        assert_eq!(first["block"], blockchain.read().unwrap().all_blocks_in_longest_chain()[1].to_string());
+    }
+
+    #[test]
+    fn search_pages_with_offset() {
+        let blockchain = chain_with_transfers();
+        let addr = start_test_server(&blockchain);
+        let page = search(addr, "offset=1");
+        assert_eq!(page["offset"], 1);
+        assert!(page["next_offset"].is_null());
+        let heights: Vec<u64> = page["results"].as_array().unwrap().iter().map(|hit| hit["height"].as_u64().unwrap()).collect();
+        assert_eq!(heights, vec![2, 3]);
+
+        let (_, _, body) = http_get(addr, "/blockchain/search?sender=zz");
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["success"], false);
+    }
+
+    #[test]
+    fn failures_carry_http_status_codes() {
+        let blockchain = chain_of(2);
+        let addr = start_test_server(&blockchain);
+        let cases = [
+            ("/miner/start", 400),
+            ("/miner/start?lambda=fast", 400),
+            ("/tx-generator/start", 400),
+            ("/tx-generator/start?theta=-1", 400),
+            ("/blockchain/state", 400),
+            ("/blockchain/state?block=tip", 400),
+            ("/blockchain/state?block=3", 404),
+            ("/blockchain/state?block=2", 200),
+        ];
+        for (path, expected) in cases {
+            let (status, _, body) = http_get(addr, path);
+            assert_eq!(status, expected, "{}", path);
+            if expected != 200 {
+                let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response["success"], false);
+            }
+        }
+    }
+
+    #[test]
+    fn chain_stats_endpoint_takes_a_window() {
+        let blockchain = chain_of(4);
+        let addr = start_test_server(&blockchain);
+
+        let (status, _, body) = http_get(addr, "/blockchain/stats?window=2");
+        assert_eq!(status, 200);
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["height"], 4);
+        assert_eq!(stats["transaction_count"], 0);
+        assert_eq!(stats["window_blocks"], 2);
+        assert!(stats["avg_block_interval_ms"].is_number());
+
+        let (status, _, _) = http_get(addr, "/blockchain/stats?window=some");
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn longest_chain_takes_a_height_range() {
+        let blockchain = chain_of(4);
+        let addr = start_test_server(&blockchain);
+        let chain: Vec<String> = blockchain.read().unwrap().all_blocks_in_longest_chain()
+            .iter().map(|h| h.to_string()).collect();
+        let listing = |query: &str| -> Vec<String> {
+            let (status, _, body) = http_get(addr, &format!("/blockchain/longest-chain{}", query));
+            assert_eq!(status, 200);
+            serde_json::from_slice(&body).unwrap()
+        };
+
+        assert_eq!(listing(""), chain);
+        assert_eq!(listing("?start=1&end=3"), &chain[1..3]);
+        assert_eq!(listing("?start=2&end=99"), &chain[2..]);
+        assert_eq!(listing("?end=1"), &chain[..1]);
+        assert!(listing("?start=3&end=1").is_empty());
+
+        let (_, _, body) = http_get(addr, "/blockchain/longest-chain?start=x");
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["success"], false);
+    }
+
+    #[test]
+    fn api_stats_count_requests_per_endpoint() {
+        let blockchain = chain_of(1);
+        let addrs = start_test_servers(&blockchain, 2);
+        http_get(addrs[0], "/blockchain/longest-chain");
+        http_get(addrs[1], "/blockchain/longest-chain?ignored=1");
+        http_get(addrs[0], "/debug/locks");
+        http_get(addrs[1], "/no/such/endpoint");
+
+        let (status, _, body) = http_get(addrs[0], "/debug/api-stats");
+        assert_eq!(status, 200);
+        let counts: HashMap<String, u64> = serde_json::from_slice(&body).unwrap();
+        // Both listeners share one set of counters, which includes this request
+        assert_eq!(counts["/blockchain/longest-chain"], 2);
+        assert_eq!(counts["/debug/locks"], 1);
+        assert_eq!(counts["404"], 1);
+        assert_eq!(counts["/debug/api-stats"], 1);
+        assert_eq!(counts.len(), 4);
+    }
+
+    fn ico_transfer(value: u64) -> SignedTransaction {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        SignedTransaction::create(Address::from([7u8; 20]), value, 1, &key)
+    }
+
+    /// Fetch a template and rebuild its header the way an external miner would
+    fn fetch_template(addr: std::net::SocketAddr) -> (String, BlockHeader) {
+        let (status, _, body) = http_get(addr, "/miner/get-template");
+        assert_eq!(status, 200);
+        let template: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let header = BlockHeader {
+            parent: parse_hash(template["parent"].as_str().unwrap()).unwrap(),
+            nonce: template["nonce"].as_u64().unwrap() as u32,
+            difficulty: parse_hash(template["difficulty"].as_str().unwrap()).unwrap(),
+            timestamp: template["timestamp"].as_u64().unwrap() as u128,
+            merkle_root: parse_hash(template["merkle_root"].as_str().unwrap()).unwrap(),
+            bits: None,
+        };
+        (template["template_id"].as_str().unwrap().to_string(), header)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_on_builds_a_sibling_fork() {
+        let blockchain = chain_of(2);
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (addrs, _network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        mempool.lock().unwrap().add_transaction(SignedTransaction::create(Address::from([1u8; 20]), 5, 1, &key)).unwrap();
+        let chain = blockchain.read().unwrap().all_blocks_in_longest_chain();
+        let (old_tip, fork_parent) = (chain[2], chain[1]);
+
+        let mine_on = |parent: &H256| -> serde_json::Value {
+            let (status, _, body) = http_post(addrs[0], &format!("/miner/mine-on?parent={}", parent), "");
+            assert_eq!(status, 200);
+            serde_json::from_slice(&body).unwrap()
+        };
+
+        let response = mine_on(&fork_parent);
+        assert_eq!(response["success"], true);
+        let sibling = parse_hash(response["message"].as_str().unwrap()).unwrap();
+        assert_eq!(blockchain.read().unwrap().get_block(&sibling).unwrap().get_parent(), fork_parent);
+        // Equal work, so the smaller hash takes the tip; the transaction stays pooled unless the sibling won
+        let sibling_won = sibling < old_tip;
+        assert_eq!(blockchain.read().unwrap().tip(), if sibling_won { sibling } else { old_tip });
+        assert_eq!(mempool.lock().unwrap().get_all_transactions().len(), if sibling_won { 0 } else { 1 });
+
+        // The transaction was spent on the sibling branch, so a child needs the next nonce
+        let spent: Vec<H256> = mempool.lock().unwrap().get_all_transactions().iter().map(|tx| tx.hash()).collect();
+        mempool.lock().unwrap().remove_transactions(spent);
+        mempool.lock().unwrap().add_transaction(SignedTransaction::create(Address::from([1u8; 20]), 5, 2, &key)).unwrap();
+        let response = mine_on(&sibling);
+        assert_eq!(response["success"], true);
+        let new_tip = parse_hash(response["message"].as_str().unwrap()).unwrap();
+        assert_eq!(blockchain.read().unwrap().tip(), new_tip);
+        assert_eq!(blockchain.read().unwrap().all_blocks_in_longest_chain()[2], sibling);
+
+        let response = mine_on(&H256::from([7u8; 32]));
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn set_tip_reorgs_to_shorter_fork() {
+        // Three blocks of ICO spends, plus a one-block fork off genesis spending nonce 1 differently
+        let blockchain = chain_with_transfers();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let old_chain = blockchain.read().unwrap().all_blocks_in_longest_chain();
+        let fork_tx = SignedTransaction::create(Address::from([5u8; 20]), 7, 1, &key);
+        let mut fork = generate_random_block(&old_chain[0]);
+        fork.header.merkle_root = MerkleTree::new(std::slice::from_ref(&fork_tx)).root();
+        fork.content.transactions = vec![fork_tx];
+        assert!(blockchain.write().unwrap().insert(&fork));
+
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let config = ApiConfig { debug_api: true };
+        let (addrs, _network_receiver) = start_test_servers_with_config(&blockchain, &mempool, 1, config);
+        let (status, _, body) = http_post(addrs[0], &format!("/debug/set-tip?hash={}", fork.hash()), "");
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], true);
+
+        let chain = blockchain.read().unwrap();
+        assert_eq!(chain.tip(), fork.hash());
+        assert_eq!(chain.all_blocks_in_longest_chain(), vec![old_chain[0], fork.hash()]);
+        let state = chain.state_at(&chain.tip()).unwrap();
+        assert_eq!(state.accounts.get(&Address::from([5u8; 20])), Some(&(0, 7)));
+        // Of the abandoned spends, nonce 2 follows the fork's state and nonce 3 queues behind it
+        let mut pooled: Vec<u64> = mempool.lock().unwrap().get_all_transactions().iter().map(|tx| tx.transaction.nonce).collect();
+        pooled.sort_unstable();
+        assert_eq!(pooled, vec![2, 3]);
+    }
+
+    #[test]
+    fn set_tip_requires_debug_api() {
+        let blockchain = chain_of(2);
+        let addr = start_test_server(&blockchain);
+        let genesis = blockchain.read().unwrap().genesis().hash();
+        let (_, _, body) = http_post(addr, &format!("/debug/set-tip?hash={}", genesis), "");
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], false);
+        assert_ne!(blockchain.read().unwrap().tip(), genesis);
+    }
+
+    fn submit(addr: std::net::SocketAddr, template_id: &str, nonce: u32) -> serde_json::Value {
+        let body = format!("{{\"template_id\": \"{}\", \"nonce\": {}}}", template_id, nonce);
+        let (status, _, body) = http_post(addr, "/miner/submit-solution", &body);
+        assert_eq!(status, 200);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn submitted_solution_is_inserted_and_broadcast() {
+        let blockchain = chain_of(0);
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let tx = ico_transfer(5);
+        mempool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        let (addrs, network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+
+        let (template_id, mut header) = fetch_template(addrs[0]);
+        assert_eq!(header.merkle_root, MerkleTree::new(std::slice::from_ref(&tx)).root());
+        while header.hash() > header.difficulty {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+
+        let response = submit(addrs[0], &template_id, header.nonce);
+        assert_eq!(response["success"], true);
+        assert_eq!(blockchain.read().unwrap().tip(), header.hash());
+        assert!(!mempool.lock().unwrap().contains_transactions(&tx.hash()));
+        match network_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![header.hash()]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn submitted_transaction_is_pooled_and_announced() {
+        let blockchain = chain_of(0);
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (addrs, network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+        let tx = ico_transfer(5);
+
+        let (status, _, body) = http_post(addrs[0], "/tx/submit", &serde_json::to_string(&tx).unwrap());
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["success"], true);
+        assert_eq!(response["message"], tx.hash().to_string());
+        assert!(mempool.lock().unwrap().contains_transactions(&tx.hash()));
+        match network_receiver.recv() {
+            Some(Message::NewTransactionHashes(v)) => assert_eq!(v, vec![tx.hash()]),
+            _ => panic!(),
+        }
+
+        // The same transaction again, or a body that is not a transaction, is refused
+        for body in [serde_json::to_string(&tx).unwrap(), "{}".to_string()] {
+            let (_, _, body) = http_post(addrs[0], "/tx/submit", &body);
+            let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(response["success"], false);
+        }
+        assert_eq!(mempool.lock().unwrap().stats().count, 1);
+    }
+
+    #[test]
+    fn submitted_invalid_nonce_is_rejected() {
+        let blockchain = chain_of(0);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        mempool.lock().unwrap().add_transaction(ico_transfer(5)).unwrap();
+        let (addrs, _network_receiver) = start_test_servers_with(&blockchain, &mempool, 1);
+
+        let (template_id, mut header) = fetch_template(addrs[0]);
+        while header.hash() <= header.difficulty {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+
+        let response = submit(addrs[0], &template_id, header.nonce);
+        assert_eq!(response["success"], false);
+        assert_eq!(blockchain.read().unwrap().tip(), genesis_hash);
+        assert_eq!(submit(addrs[0], &"00".repeat(32), 0)["message"], "unknown template");
+    }
+
+    #[test]
+    fn genesis_state_reports_ico_allocation() {
+        let mut blockchain = Blockchain::with_ico_balance(&[0u8; 32], 4242);
+        // Later blocks must not change what is reported for genesis
+        let block = generate_random_block(&blockchain.tip());
+        assert!(blockchain.insert(&block));
+        let addr = start_test_server(&Arc::new(RwLock::new(blockchain)));
+
+        let (status, _, body) = http_get(addr, "/blockchain/genesis-state");
+        assert_eq!(status, 200);
+        let accounts: Vec<String> = serde_json::from_slice(&body).unwrap();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let ico_address = Address::from_public_key_bytes(key.public_key().as_ref());
+        assert_eq!(accounts, vec![format!("({}, 0, 4242)", ico_address)]);
     }
 }
@@ -1,34 +1,241 @@
+pub mod state_cache;
+
 use crate::types::block::Block;
 use crate::types::hash::H256;
 use std::collections::HashMap;
 use crate::types::block::{Header, Content};
 use crate::types::hash::Hashable;
-use crate::types::transaction::SignedTransaction;
-use crate::types::state::State; // Import the updated state
+use crate::types::transaction::{verify, SignedTransaction};
+use crate::types::address::Address;
+use crate::types::state::{State, DEFAULT_ICO_BALANCE}; // Import the updated state
+use crate::types::merkle::MerkleTree;
+use crate::types::ledger::Ledger;
 use log::info;
+use serde::{Serialize, Deserialize};
 use stderrlog::new;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use state_cache::StateCache;
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+/// On-disk representation of the block tree
+#[derive(Serialize, Deserialize)]
+struct SavedChain {
+    blocks: Vec<Block>,
+    heights: HashMap<H256, usize>,
+    tip: H256,
+}
+
+/// Node-wide consensus parameters the genesis block is built from
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Balance credited to the ICO account in the genesis state
+    pub ico_balance: u64,
+    /// Target every block must meet; recorded in the genesis header
+    pub consensus_difficulty: H256,
+    /// Separates independent networks; stored as the genesis nonce so each id has its own genesis
+    pub network_id: u32,
+    /// Average time between blocks that difficulty retargeting aims for; 0 keeps the genesis difficulty
+    pub target_block_interval_ms: u64,
+    /// Most a block's coinbase may mint for its miner, on top of its transactions' fees
+    pub block_reward: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            ico_balance: DEFAULT_ICO_BALANCE,
+            consensus_difficulty: hex_literal::hex!("0005ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            network_id: 0,
+            target_block_interval_ms: 0,
+            block_reward: DEFAULT_BLOCK_REWARD,
+        }
+    }
+}
+
+/// The genesis block and the state it starts from, for running networks that must not sync
+/// with each other. Chains built from different configs have different genesis hashes.
+#[derive(Debug, Clone)]
+pub struct GenesisConfig {
+    /// Target every block must meet; recorded in the genesis header
+    pub difficulty: H256,
+    pub timestamp: u128,
+    /// Accounts funded at genesis, which the genesis merkle root commits to. When None only the
+    /// ICO account of the all-zero seed is funded, and the root stays zero as in `Blockchain::new`.
+    pub ico_allocations: Option<Vec<(Address, u64)>>,
+}
 
-pub struct Blockchain {
-    pub blocks: HashMap<H256, Block>, // Store blocks by their hash
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        GenesisConfig {
+            difficulty: NodeConfig::default().consensus_difficulty,
+            timestamp: 0,
+            ico_allocations: None,
+        }
+    }
+}
+
+/// Fees collected and reward paid by a block
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct BlockEconomics {
+    pub fees_collected: u64,
+    pub reward: u64,
+    pub coinbase_recipient: Option<Address>,
+}
+
+/// Block timing and throughput of the longest chain. Genesis is left out of the timings,
+/// since its timestamp is not a real time.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub height: usize,
+    pub transaction_count: usize, // Transfers confirmed on the longest chain, not counting coinbases
+    pub avg_block_interval_ms: Option<f64>, // None until two blocks follow genesis
+    pub window_blocks: usize, // Most recent blocks `blocks_per_minute` is measured over
+    pub blocks_per_minute: Option<f64>, // None without two blocks in the window, or time between them
+}
+
+/// How the longest chain changed when the tip moved off it: `removed` blocks left it and
+/// `added` blocks joined it, both oldest first, on top of `common_ancestor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorg {
+    pub common_ancestor: H256,
+    pub removed: Vec<H256>,
+    pub added: Vec<H256>,
+}
+
+/// Why `Blockchain::try_insert` refused a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    UnknownParent,
+    BelowFinalized, // Forks off below the finalized height
+    ReplaysConfirmedTx, // Re-includes a transaction already confirmed in its ancestry
+    InvalidSignature,
+    InvalidTransaction, // Not valid against the state left by the parent and earlier transactions
+    InvalidCoinbase, // Not first, not at the block's height, or minting more than the reward
+    TimestampNotAfterParent,
+    TimestampTooFarAhead, // More than `MAX_FUTURE_BLOCK_TIME_MS` past the local clock
+    InsufficientWork, // Header hash above its target
+    TooManyTransactions, // More than `MAX_TX_PER_BLOCK`
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            BlockError::UnknownParent => "Parent block is unknown",
+            BlockError::BelowFinalized => "Block forks below the finalized height",
+            BlockError::ReplaysConfirmedTx => "Block re-includes a confirmed transaction",
+            BlockError::InvalidSignature => "Block contains a transaction with an invalid signature",
+            BlockError::InvalidTransaction => "Block contains a transaction invalid against its parent state",
+            BlockError::InvalidCoinbase => "Block contains an invalid coinbase",
+            BlockError::TimestampNotAfterParent => "Block timestamp is not after its parent's",
+            BlockError::TimestampTooFarAhead => "Block timestamp is too far in the future",
+            BlockError::InsufficientWork => "Block hash does not meet its target",
+            BlockError::TooManyTransactions => "Block carries more transactions than allowed",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+pub struct Blockchain<L: Ledger = State> {
+    blocks: HashMap<H256, Block>, // Store blocks by their hash
     heights: HashMap<H256, usize>, // Store heights of each block
-    tip: H256, // Keep track of the last block's hash (tip of longest chain)
-    pub states: HashMap<H256, Arc<Mutex<State>>>, // Store the state for each block
+    tip: H256, // Keep track of the last block's hash (tip of the longest chain, i.e. the one with the most work)
+    total_work: HashMap<H256, u128>, // Cumulative work of the chain ending at each block
+    main_chain: Vec<H256>, // Hashes of the longest chain, indexed by height
+    confirmed_txs: HashMap<H256, H256>, // Transactions on the longest chain -> block containing them
+    pub states: Mutex<StateCache<L>>, // Recently used block states; others are replayed on demand
+    economics: HashMap<H256, BlockEconomics>, // Fees and reward tallied while applying each block
+    finality_depth: usize, // Blocks this far below the tip are final; 0 disables finality
+    compress_on_save: bool, // Gzip the file written by `save_to_file`
+    verify_signatures: bool, // Only turned off for benchmarking
+    target_block_interval_ms: u64, // 0 disables difficulty retargeting
+    block_reward: u64, // Most a coinbase may mint
+    tip_subscribers: Vec<Sender<H256>>, // Told the new tip whenever it changes
+    pending_headers: HashMap<H256, Header>, // Headers accepted during header sync whose bodies have not arrived
 }
 
+/// First bytes of a gzip stream, used to detect compressed chain files on load
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Blocks between difficulty retargets, and the number of intervals each retarget looks back over
+pub const DIFFICULTY_WINDOW: usize = 16;
+
+/// Most a single retarget may scale the target by, in either direction
+const MAX_DIFFICULTY_ADJUSTMENT: u64 = 4;
+
+/// Coinbase amount a miner may claim per block when none is configured
+pub const DEFAULT_BLOCK_REWARD: u64 = 50;
+
+/// Default number of blocks below the tip after which a block is treated as final
+pub const DEFAULT_FINALITY_DEPTH: usize = 100;
+
+/// Most transactions one block may carry, its coinbase included
+pub const MAX_TX_PER_BLOCK: usize = 2048;
+
+/// How far a block's timestamp may run ahead of the local clock, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+
+
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new(seed: &[u8; 32]) -> Self {
-        let genesis_state = Arc::new(Mutex::new(State::new(seed)));
+        Self::with_ico_balance(seed, DEFAULT_ICO_BALANCE)
+    }
+
+    /// Create a new blockchain whose genesis state credits `ico_balance` to the ICO account
+    pub fn with_ico_balance(seed: &[u8; 32], ico_balance: u64) -> Self {
+        Self::with_config(seed, &NodeConfig { ico_balance, ..Default::default() })
+    }
+
+    /// Create a new blockchain whose genesis block and state follow `config`
+    pub fn with_config(seed: &[u8; 32], config: &NodeConfig) -> Self {
+        Self::with_genesis_ledger(config, State::with_ico_balance(seed, config.ico_balance))
+    }
+
+    /// Create a new blockchain from an explicit genesis; other parameters keep their defaults
+    pub fn with_genesis(config: GenesisConfig) -> Self {
+        Self::with_config_and_genesis(&NodeConfig::default(), config)
+    }
+
+    /// Create a new blockchain from an explicit genesis, taking the other parameters from
+    /// `config`; its difficulty and ICO balance give way to those of `genesis`
+    pub fn with_config_and_genesis(config: &NodeConfig, genesis: GenesisConfig) -> Self {
+        let node_config = NodeConfig { consensus_difficulty: genesis.difficulty, ..config.clone() };
+        let (genesis_ledger, merkle_root) = match &genesis.ico_allocations {
+            Some(allocations) => {
+                let serialized = bincode::serialize(allocations).expect("Serialization should not fail");
+                let commitment = ring::digest::digest(&ring::digest::SHA256, &serialized).into();
+                (State::with_allocations(allocations), commitment)
+            }
+            None => (State::new(&[0u8; 32]), H256::from([0x00; 32])),
+        };
+        Self::with_genesis_header(&node_config, genesis.timestamp, merkle_root, genesis_ledger)
+    }
+}
+
+impl<L: Ledger> Blockchain<L> {
+    /// Create a new blockchain over any ledger model, starting from `genesis_ledger`.
+    /// `config.ico_balance` is left to the caller, who built the genesis ledger.
+    pub fn with_genesis_ledger(config: &NodeConfig, genesis_ledger: L) -> Self {
+        Self::with_genesis_header(config, 0, H256::from([0x00; 32]), genesis_ledger)
+    }
+
+    fn with_genesis_header(config: &NodeConfig, timestamp: u128, merkle_root: H256, genesis_ledger: L) -> Self {
         // Create a genesis block with fixed values for the fields
         let genesis_block = Block {
             // Define the genesis block's header and content 
             header: Header {
                 parent: H256::from([0x00; 32]),
-                nonce: 0,
-                difficulty: hex_literal::hex!("0005ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
-                timestamp: 0,
-                merkle_root: H256::from([0x00; 32]),
+                nonce: config.network_id,
+                difficulty: config.consensus_difficulty,
+                timestamp,
+                merkle_root,
+                bits: None,
             },
             content: Content{
                 transactions: vec![],
@@ -36,6 +243,7 @@ impl Blockchain {
         };
 
         let genesis_hash = genesis_block.hash();
+        let genesis_work = block_work(&genesis_block.get_difficulty());
 
         let mut blocks = HashMap::new();
         blocks.insert(genesis_hash, genesis_block);
@@ -43,82 +251,570 @@ impl Blockchain {
         let mut heights = HashMap::new();
         heights.insert(genesis_hash, 0); // Genesis block is at height 0
 
-        let mut states = HashMap::new();
-        states.insert(genesis_hash, genesis_state); // Create initial state for genesis block
+        let mut total_work = HashMap::new();
+        total_work.insert(genesis_hash, genesis_work);
+
+        let states = Mutex::new(StateCache::new(genesis_hash, genesis_ledger));
+
+        let mut economics = HashMap::new();
+        economics.insert(genesis_hash, BlockEconomics::default());
 
 
         Self {
             blocks,
             heights,
             tip: genesis_hash, // Genesis block is the tip at creation
+            total_work,
+            main_chain: vec![genesis_hash],
+            confirmed_txs: HashMap::new(),
             states,
+            economics,
+            finality_depth: DEFAULT_FINALITY_DEPTH,
+            compress_on_save: false,
+            verify_signatures: true,
+            target_block_interval_ms: config.target_block_interval_ms,
+            block_reward: config.block_reward,
+            tip_subscribers: Vec::new(),
+            pending_headers: HashMap::new(),
         }
 
     }
 
-    /// Insert a block into blockchain
+    /// Insert a block into blockchain, returning whether it was stored
     pub fn insert(&mut self, block: &Block) -> bool {
         //unimplemented!()
+        match self.try_insert(block) {
+            Ok(_) => true,
+            Err(reason) => {
+                info!("Block {:?} rejected: {}", block.hash(), reason);
+                false
+            }
+        }
+    }
+
+    /// Insert a block into blockchain, or say why it was refused. A refused block is not
+    /// stored and leaves the tip unchanged. If the block makes a competing fork the longest
+    /// chain, the returned `Reorg` says which blocks were swapped out.
+    pub fn try_insert(&mut self, block: &Block) -> Result<Option<Reorg>, BlockError> {
         let block_hash = block.hash();
         let parent_hash = block.get_parent();
 
+        if block.content.transactions.len() > MAX_TX_PER_BLOCK {
+            return Err(BlockError::TooManyTransactions);
+        }
+
         // Ensure parent block is already in the blockchain
-        if let Some(parent_height) = self.heights.get(&parent_hash) {
-            info!("Inserting block: {:?} with parent: {:?}", block_hash, parent_hash);
+        let parent_height = *self.heights.get(&parent_hash).ok_or(BlockError::UnknownParent)?;
+        info!("Inserting block: {:?} with parent: {:?}", block_hash, parent_hash);
+
+        check_timestamp(block.header.timestamp, self.blocks[&parent_hash].header.timestamp)?;
+
+        // A new block at or below the finalized height must fork off below the finalized
+        // block, so it can never become part of the longest chain
+        let block_height = parent_height + 1;
+        if self.finality_depth > 0
+            && block_height <= self.finalized_height()
+            && self.main_chain.get(block_height) != Some(&block_hash)
+        {
+            return Err(BlockError::BelowFinalized);
+        }
+
+        // Reject blocks replaying a transaction already confirmed in their ancestry
+        if self.replays_confirmed_tx(block) {
+            return Err(BlockError::ReplaysConfirmedTx);
+        }
+
+        // Every transaction but the coinbase must be signed by the key its sender address is derived from
+        if self.verify_signatures
+            && !block
+                .content
+                .transactions
+                .iter()
+                .all(|tx| tx.is_coinbase() || verify(&tx.transaction, &tx.public_key, &tx.signature))
+        {
+            return Err(BlockError::InvalidSignature);
+        }
+
+        // Validate transactions and update state
+        let mut new_state = self.state_at(&parent_hash).unwrap();
+
+        let mut economics = BlockEconomics::default();
+
+        // Transactions apply in block order, so each is checked against the state left by the previous ones
+        for (index, tx) in block.content.transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                if index != 0 || tx.transaction.nonce != block_height as u64 {
+                    return Err(BlockError::InvalidCoinbase);
+                }
+                economics.reward = tx.transaction.value;
+                economics.coinbase_recipient = Some(tx.transaction.receiver);
+            } else if !new_state.validate(tx) {
+                return Err(BlockError::InvalidTransaction);
+            } else {
+                economics.fees_collected = economics.fees_collected.saturating_add(tx.transaction.fee);
+            }
+            new_state.apply(tx);
+        }
+
+        // The coinbase can claim the block reward plus the fees of the transactions after it
+        if economics.reward > self.block_reward.saturating_add(economics.fees_collected) {
+            return Err(BlockError::InvalidCoinbase);
+        }
+
+        // Insert the block together with its state; both happen under `&mut self`,
+        // so no reader can see the block without its state
+        self.blocks.insert(block_hash, block.clone());
+        self.states.lock().unwrap().insert(block_hash, new_state);
+        self.economics.insert(block_hash, economics);
+
+        self.heights.insert(block_hash, block_height);
+        self.pending_headers.remove(&block_hash);
+
+        // Accumulate the work of the chain ending at this block
+        let parent_work = self.total_work.get(&parent_hash).copied().unwrap_or(0);
+        let block_total_work = parent_work.saturating_add(block_work(&block.get_difficulty()));
+        self.total_work.insert(block_hash, block_total_work);
+
+        // Update the tip if the new block ends a chain with more work than the current one;
+        // on equal work the smaller hash wins, so nodes agree whatever order blocks arrived in
+        let tip_work = self.total_work[&self.tip];
+        if block_total_work > tip_work || (block_total_work == tip_work && block_hash < self.tip) {
+            return Ok(self.set_tip(block_hash));
+        }
+        Ok(None)
+    }
+
+    /// UNSAFE: accept blocks without checking transaction signatures, for benchmarks only
+    pub fn set_verify_signatures(&mut self, verify_signatures: bool) {
+        self.verify_signatures = verify_signatures;
+    }
+
+    /// Make `block_hash` the tip regardless of fork choice, for demonstrating reorgs.
+    /// Returns false if the block is unknown.
+    pub fn force_tip(&mut self, block_hash: &H256) -> bool {
+        if !self.blocks.contains_key(block_hash) {
+            return false;
+        }
+        self.set_tip(*block_hash);
+        true
+    }
+
+    /// Move the tip to `new_tip`, updating the longest chain and its confirmed transactions.
+    /// Returns the reorg if any blocks left the longest chain.
+    fn set_tip(&mut self, new_tip: H256) -> Option<Reorg> {
+        // Walk back from the new tip until we rejoin the current longest chain
+        let mut branch = Vec::new();
+        let mut current = new_tip;
+        while !self.is_in_longest_chain(&current) {
+            branch.push(current);
+            current = self.blocks[&current].get_parent();
+        }
+        let fork_height = self.heights[&current];
+
+        // Forget the blocks that are no longer on the longest chain
+        // (a forced tip may sit below the current one, so this also drops blocks above it)
+        let removed = self.main_chain.split_off(fork_height + 1);
+        for hash in &removed {
+            for tx in &self.blocks[hash].content.transactions {
+                self.confirmed_txs.remove(&tx.hash());
+            }
+        }
+
+        // Adopt the new branch, oldest first
+        branch.reverse();
+        for &added in &branch {
+            for tx in &self.blocks[&added].content.transactions {
+                self.confirmed_txs.insert(tx.hash(), added);
+            }
+            self.main_chain.push(added);
+        }
+        self.tip = new_tip;
+
+        // Tell subscribers, forgetting those that have gone away
+        self.tip_subscribers.retain(|subscriber| subscriber.send(new_tip).is_ok());
+
+        if removed.is_empty() {
+            return None;
+        }
+        info!("Reorg at {}: {} blocks removed, {} added", current, removed.len(), branch.len());
+        Some(Reorg { common_ancestor: current, removed, added: branch })
+    }
 
-            // Validate transactions and update state
-            let parent_state_arc = self.states.get(&parent_hash).cloned().unwrap();
+    /// Transactions of the given blocks, in the order the blocks are listed
+    pub fn block_transactions(&self, block_hashes: &[H256]) -> Vec<SignedTransaction> {
+        block_hashes
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash))
+            .flat_map(|block| block.content.transactions.iter().cloned())
+            .collect()
+    }
 
-            let parent_state = parent_state_arc.lock().unwrap();
+    /// Receive the hash of every new tip from now on, whether from an insert or a reorg
+    pub fn subscribe_tip(&mut self) -> Receiver<H256> {
+        let (sender, receiver) = unbounded();
+        self.tip_subscribers.push(sender);
+        receiver
+    }
 
-            let mut new_state = parent_state.clone();
+    /// Remember a header whose body has not arrived yet, after the checks that need no body:
+    /// a known parent, enough work for its target, and a timestamp after the parent's. The
+    /// expected difficulty depends on the parent's ancestry, so it is checked once the block
+    /// itself is inserted. Returns whether the header was new.
+    pub fn accept_header(&mut self, header: &Header) -> Result<bool, BlockError> {
+        let header_hash = header.hash();
+        if self.blocks.contains_key(&header_hash) || self.pending_headers.contains_key(&header_hash) {
+            return Ok(false);
+        }
+        let parent_timestamp = match self.blocks.get(&header.parent) {
+            Some(parent) => parent.header.timestamp,
+            None => self.pending_headers.get(&header.parent).ok_or(BlockError::UnknownParent)?.timestamp,
+        };
+        if header_hash > header.target() {
+            return Err(BlockError::InsufficientWork);
+        }
+        check_timestamp(header.timestamp, parent_timestamp)?;
+        self.pending_headers.insert(header_hash, header.clone());
+        Ok(true)
+    }
 
-            drop(parent_state);
+    /// Whether a header for `block_hash` was accepted and its body is still awaited
+    pub fn has_pending_header(&self, block_hash: &H256) -> bool {
+        self.pending_headers.contains_key(block_hash)
+    }
 
-            let current_state = parent_state_arc.lock().unwrap();
+    /// Hashes describing the longest chain to a peer: the most recent blocks one by one,
+    /// then exponentially further apart, ending with genesis
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut height = self.main_chain.len() - 1;
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.main_chain[height]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator.push(self.main_chain[0]);
+        locator
+    }
 
-            let parent_state = current_state.clone();
+    /// Up to `limit` headers of the longest chain following the first `locator` hash on it,
+    /// or following genesis if none of them is
+    pub fn headers_after(&self, locator: &[H256], limit: usize) -> Vec<Header> {
+        let start = locator
+            .iter()
+            .find(|hash| self.is_in_longest_chain(hash))
+            .map_or(0, |hash| self.heights[hash])
+            + 1;
+        self.main_chain
+            .iter()
+            .skip(start)
+            .take(limit)
+            .map(|hash| self.blocks[hash].header.clone())
+            .collect()
+    }
 
+    /// Whether the block is stored, whether or not it is on the longest chain
+    pub fn contains(&self, block_hash: &H256) -> bool {
+        self.blocks.contains_key(block_hash)
+    }
+
+    /// The stored block with this hash, or None if the block is unknown
+    pub fn get_block(&self, block_hash: &H256) -> Option<&Block> {
+        self.blocks.get(block_hash)
+    }
+
+    /// Number of blocks between `block_hash` and genesis, or None if the block is unknown
+    pub fn height_of(&self, block_hash: &H256) -> Option<usize> {
+        self.heights.get(block_hash).copied()
+    }
+
+    /// Number of blocks stored across every branch, genesis included
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn is_in_longest_chain(&self, block_hash: &H256) -> bool {
+        match self.heights.get(block_hash) {
+            Some(height) => self.main_chain.get(*height) == Some(block_hash),
+            None => false,
+        }
+    }
+
+    /// Check whether any of the block's transactions is already confirmed by one of its ancestors
+    fn replays_confirmed_tx(&self, block: &Block) -> bool {
+        // Collect transactions on the block's own branch, back to where it forks off the longest chain
+        let mut branch_txs = std::collections::HashSet::new();
+        let mut current = block.get_parent();
+        while !self.is_in_longest_chain(&current) {
+            let ancestor = &self.blocks[&current];
+            branch_txs.extend(ancestor.content.transactions.iter().map(|tx| tx.hash()));
+            current = ancestor.get_parent();
+        }
+        let fork_height = self.heights[&current];
 
-            
-            for tx in &block.content.transactions {
-                if !parent_state.is_valid_transaction(tx) {
-                    info!("Returning false in blockchain/mod.rs");
-                    return false; // Invalid transaction, reject block
+        block.content.transactions.iter().any(|tx| {
+            let tx_hash = tx.hash();
+            if branch_txs.contains(&tx_hash) {
+                return true;
+            }
+            // Only transactions confirmed at or below the fork point are ancestors of this block
+            match self.confirmed_txs.get(&tx_hash) {
+                Some(confirmed_in) => self.heights[confirmed_in] <= fork_height,
+                None => false,
+            }
+        })
+    }
+
+    /// Check whether a transaction is confirmed on the longest chain
+    pub fn contains_confirmed_tx(&self, tx_hash: &H256) -> bool {
+        self.confirmed_txs.contains_key(tx_hash)
+    }
+
+    /// The genesis block, the first block of every chain this node accepts
+    pub fn genesis(&self) -> &Block {
+        &self.blocks[&self.main_chain[0]]
+    }
+
+    /// Most a block's coinbase may mint
+    pub fn block_reward(&self) -> u64 {
+        self.block_reward
+    }
+
+    /// Difficulty every block must carry, as set in the genesis header
+    pub fn consensus_difficulty(&self) -> H256 {
+        self.genesis().header.difficulty
+    }
+
+    /// Target a child of `parent_hash` must carry. Every `DIFFICULTY_WINDOW` blocks the
+    /// target is scaled by how long the last window took compared to the configured block
+    /// interval, by at most `MAX_DIFFICULTY_ADJUSTMENT`x; in between it is the parent's.
+    pub fn compute_next_difficulty(&self, parent_hash: &H256) -> Option<H256> {
+        let parent = self.blocks.get(parent_hash)?;
+        let parent_height = self.heights[parent_hash];
+        // The genesis timestamp is not a real time, so the first window must not reach it
+        if self.target_block_interval_ms == 0
+            || !(parent_height + 1).is_multiple_of(DIFFICULTY_WINDOW)
+            || parent_height <= DIFFICULTY_WINDOW
+        {
+            return Some(parent.get_difficulty());
+        }
+
+        let mut window_start = *parent_hash;
+        for _ in 0..DIFFICULTY_WINDOW {
+            window_start = self.blocks[&window_start].get_parent();
+        }
+        let expected = self.target_block_interval_ms.saturating_mul(DIFFICULTY_WINDOW as u64);
+        let actual = parent.header.timestamp.saturating_sub(self.blocks[&window_start].header.timestamp);
+        let actual = (actual.min(u64::MAX as u128) as u64).clamp(
+            (expected / MAX_DIFFICULTY_ADJUSTMENT).max(1),
+            expected.saturating_mul(MAX_DIFFICULTY_ADJUSTMENT),
+        );
+        Some(scale_target(&parent.get_difficulty(), actual, expected))
+    }
+
+    /// State at the genesis block, i.e. the initial ICO distribution
+    pub fn genesis_state(&self) -> L {
+        self.state_at(&self.main_chain[0]).expect("genesis state is created with the chain")
+    }
+
+    /// State after applying every block up to and including `block_hash`. States evicted
+    /// from the cache are rebuilt by replaying blocks from the nearest cached ancestor.
+    pub fn state_at(&self, block_hash: &H256) -> Option<L> {
+        if !self.blocks.contains_key(block_hash) {
+            return None;
+        }
+        let mut states = self.states.lock().unwrap();
+        let mut replay = vec![];
+        let mut current = *block_hash;
+        // Genesis is never evicted, so this walk always ends
+        let mut state = loop {
+            match states.get(&current) {
+                Some(state) => break state,
+                None => {
+                    replay.push(current);
+                    current = self.blocks[&current].get_parent();
                 }
-                new_state.apply_transaction(tx);
-                info!("APPLIED TRANS");
-            } 
+            }
+        };
+        if replay.is_empty() {
+            return Some(state);
+        }
+        // Stored blocks were validated on insert, so their transactions apply cleanly
+        for hash in replay.iter().rev() {
+            for tx in &self.blocks[hash].content.transactions {
+                state.apply(tx);
+            }
+        }
+        states.insert(*block_hash, state.clone());
+        Some(state)
+    }
+
+    /// Keep at most `capacity` block states in memory; the rest are recomputed when needed
+    pub fn set_state_cache_capacity(&mut self, capacity: usize) {
+        self.states.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Fees collected and reward paid by `block_hash`
+    pub fn economics(&self, block_hash: &H256) -> Option<&BlockEconomics> {
+        self.economics.get(block_hash)
+    }
+
+    /// Height of the most recent longest-chain block with a transaction sent by `address`
+    pub fn last_active_height(&self, address: &Address) -> Option<usize> {
+        self.main_chain.iter().enumerate().rev().find_map(|(height, block_hash)| {
+            self.blocks[block_hash]
+                .content
+                .transactions
+                .iter()
+                .any(|tx| tx.sender_address() == *address)
+                .then_some(height)
+        })
+    }
+
+    /// Timing and throughput of the longest chain, with the block rate measured over its last
+    /// `window` blocks
+    pub fn chain_stats(&self, window: usize) -> ChainStats {
+        let timestamps: Vec<u128> = self.main_chain[1..].iter().map(|hash| self.blocks[hash].header.timestamp).collect();
+        let transaction_count = self.main_chain[1..]
+            .iter()
+            .map(|hash| self.blocks[hash].content.transactions.iter().filter(|tx| !tx.is_coinbase()).count())
+            .sum();
+
+        // Mean gap between consecutive blocks over the span `timestamps` covers
+        let rate = |timestamps: &[u128]| -> Option<(f64, u128)> {
+            let span = timestamps.last()? - timestamps.first()?;
+            let gaps = timestamps.len().checked_sub(1).filter(|gaps| *gaps > 0)?;
+            Some((span as f64 / gaps as f64, span))
+        };
+        let recent = &timestamps[timestamps.len().saturating_sub(window)..];
+        ChainStats {
+            height: self.main_chain.len() - 1,
+            transaction_count,
+            avg_block_interval_ms: rate(&timestamps).map(|(interval, _)| interval),
+            window_blocks: recent.len(),
+            blocks_per_minute: rate(recent)
+                .filter(|(_, span)| *span > 0)
+                .map(|(interval, _)| 60_000.0 / interval),
+        }
+    }
 
-            //info!("Number of Transactions: {}", &block.content.transactions.len());
-            // Insert the block into the blockchain
-            self.blocks.insert(block_hash, block.clone());
+    /// Fee of every transaction in the last `blocks` longest-chain blocks, leaving out coinbases
+    pub fn recent_fees(&self, blocks: usize) -> Vec<u64> {
+        // Skip genesis, which never carries transactions
+        self.main_chain
+            .iter()
+            .skip(1)
+            .rev()
+            .take(blocks)
+            .flat_map(|block_hash| self.blocks[block_hash].content.transactions.iter())
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.transaction.fee)
+            .collect()
+    }
 
-            // Compute the height of the new block (parent height + 1)
-            let block_height = parent_height + 1;
-            self.heights.insert(block_hash, block_height);
+    pub fn get_state(&self, block_hash: &H256) -> Option<L> {
+        self.state_at(block_hash)
+    }
 
-            //states_lock.insert(block_hash, new_state);
-            self.states.insert(block_hash, Arc::new(Mutex::new(new_state)));
+    /// Write every known block, their heights, and the tip to `path`
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let saved = SavedChain {
+            blocks: self.blocks.values().cloned().collect(),
+            heights: self.heights.clone(),
+            tip: self.tip,
+        };
+        let bytes = bincode::serialize(&saved)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if self.compress_on_save {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes)?;
+            std::fs::write(path, encoder.finish()?)
+        } else {
+            std::fs::write(path, bytes)
+        }
+    }
 
-            //info!("State Map After Insert: {:?}", self.states);
+    /// Gzip the file written by `save_to_file`; loading detects compression either way
+    pub fn set_compress_on_save(&mut self, compress: bool) {
+        self.compress_on_save = compress;
+    }
+
+    /// Load blocks saved by `save_to_file` on top of this (genesis-only) blockchain.
+    /// Blocks are replayed parents-first so heights, work and states are rebuilt;
+    /// PoW and merkle roots are not checked here, see `verify_integrity`.
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let mut bytes = std::fs::read(path)?;
+        if bytes.starts_with(&GZIP_MAGIC) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+            bytes = decompressed;
+        }
+        let SavedChain { mut blocks, heights, tip } = bincode::deserialize(&bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let genesis_hash = self.main_chain[0];
+        if heights.get(&genesis_hash) != Some(&0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "saved chain has a different genesis block",
+            ));
+        }
 
+        blocks.sort_by_key(|block| heights.get(&block.hash()).copied().unwrap_or(usize::MAX));
+        for block in &blocks {
+            let block_hash = block.hash();
+            if !self.blocks.contains_key(&block_hash) && !self.insert(block) {
+                info!("Skipping saved block {:?} that could not be inserted", block_hash);
+            }
+        }
+        if self.tip != tip {
+            info!("Saved tip {:?} differs from the replayed tip {:?}", tip, self.tip);
+        }
+        Ok(())
+    }
 
-            // Update the tip if the new block extends the longest chain
-            if block_height > *self.heights.get(&self.tip).unwrap() {
-                self.tip = block_hash;
+    /// Check every stored block: PoW, parent link, height, and merkle root
+    pub fn verify_integrity(&self) -> Result<(), String> {
+        let genesis_hash = self.main_chain[0];
+        for (hash, block) in &self.blocks {
+            if *hash == genesis_hash {
+                continue;
+            }
+            if block.hash() > block.get_difficulty() {
+                return Err(format!("block {} fails the PoW check", hash));
             }
-            return true;
+            let parent_height = match self.heights.get(&block.get_parent()) {
+                Some(height) => *height,
+                None => return Err(format!("block {} has an unknown parent", hash)),
+            };
+            if self.heights.get(hash) != Some(&(parent_height + 1)) {
+                return Err(format!("block {} has an inconsistent height", hash));
+            }
+            if !block.is_valid_content() {
+                return Err(format!("block {} has a mismatched merkle root", hash));
+            }
+        }
+        if !self.is_in_longest_chain(&self.tip) {
+            return Err(format!("tip {} is not on the longest chain", self.tip));
         }
-        false // Parent block not found, reject block
+        Ok(())
+    }
+
+    /// Set how many blocks below the tip a block becomes final; 0 disables finality
+    pub fn set_finality_depth(&mut self, depth: usize) {
+        self.finality_depth = depth;
     }
 
-    pub fn get_state(&self, block_hash: &H256) -> Option<State> {
-        self.states.get(block_hash).map(|state_arc| state_arc.lock().unwrap().clone())
+    /// Height of the deepest block that no fork may replace
+    pub fn finalized_height(&self) -> usize {
+        (self.main_chain.len() - 1).saturating_sub(self.finality_depth)
     }
 
-    pub fn get_states(&self) -> Arc<Mutex<HashMap<H256, Arc<Mutex<State>>>>> {
-        Arc::new(Mutex::new(self.states.clone()))
+    /// Get the cumulative work of the chain ending at the given block
+    pub fn chainwork(&self, block_hash: &H256) -> Option<u128> {
+        self.total_work.get(block_hash).copied()
     }
 
     /// Get the last block's hash of the longest chain
@@ -127,29 +823,75 @@ impl Blockchain {
         self.tip
     }
 
+    /// Hashes of the longest chain at heights `start` up to but not including `end`;
+    /// bounds past the tip are clamped to it, and `start >= end` gives nothing
+    pub fn blocks_in_longest_chain_range(&self, start: usize, end: usize) -> &[H256] {
+        let end = end.min(self.main_chain.len());
+        &self.main_chain[start.min(end)..end]
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        // unimplemented!()
-        //vec![]
-        let mut chain = Vec::new();
-        let mut current_hash = self.tip;
-
-        // Traverse backward from the tip to the genesis block 
-        while let Some(block) = self.blocks.get(&current_hash) {
-            chain.push(current_hash);
-            current_hash = block.get_parent();
-            if current_hash == H256::from([0x00; 32]) {
-                break;
-            }
-        }
+        self.main_chain.clone()
+    }
+}
 
-        // Reverse to order from genesis to tip
-        chain.reverse();
-        chain
+/// Timestamps strictly increase along a chain and may not run far ahead of the local clock
+fn check_timestamp(timestamp: u128, parent_timestamp: u128) -> Result<(), BlockError> {
+    if timestamp <= parent_timestamp {
+        return Err(BlockError::TimestampNotAfterParent);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+    if timestamp > now + MAX_FUTURE_BLOCK_TIME_MS {
+        return Err(BlockError::TimestampTooFarAhead);
+    }
+    Ok(())
+}
 
+/// Expected number of hashes needed to find a block under `target`, i.e. roughly 2^256 / (target + 1).
+/// Only the upper 128 bits of the target are used, which is plenty of precision for our difficulties.
+fn block_work(target: &H256) -> u128 {
+    let bytes: [u8; 32] = target.into();
+    let mut upper = [0u8; 16];
+    upper.copy_from_slice(&bytes[..16]);
+    let upper = u128::from_be_bytes(upper);
+    match upper.checked_add(1) {
+        Some(divisor) => u128::MAX / divisor,
+        None => 1, // The easiest possible target
     }
 }
 
+/// `target * numerator / denominator`, saturating at the easiest possible target
+fn scale_target(target: &H256, numerator: u64, denominator: u64) -> H256 {
+    let bytes: [u8; 32] = target.into();
+    // Work in 32-bit limbs, most significant first, with two spare limbs for the product
+    let mut wide = [0u32; 10];
+    let mut carry: u128 = 0;
+    for i in (0..8).rev() {
+        let limb = u32::from_be_bytes([bytes[4 * i], bytes[4 * i + 1], bytes[4 * i + 2], bytes[4 * i + 3]]);
+        let product = limb as u128 * numerator as u128 + carry;
+        wide[i + 2] = product as u32;
+        carry = product >> 32;
+    }
+    wide[1] = carry as u32;
+    wide[0] = (carry >> 32) as u32;
+
+    let mut remainder: u128 = 0;
+    for limb in wide.iter_mut() {
+        let current = (remainder << 32) | *limb as u128;
+        *limb = (current / denominator as u128) as u32;
+        remainder = current % denominator as u128;
+    }
+    if wide[0] != 0 || wide[1] != 0 {
+        return H256::from([0xff; 32]);
+    }
+    let mut scaled = [0u8; 32];
+    for (i, limb) in wide[2..].iter().enumerate() {
+        scaled[4 * i..4 * i + 4].copy_from_slice(&limb.to_be_bytes());
+    }
+    H256::from(scaled)
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 /* 
 #[cfg(test)]
@@ -331,4 +1073,704 @@ mod tests {
 
 }*/
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::block::{generate_mined_block, generate_random_block};
+    use crate::types::hash::generate_random_hash;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    // The ICO account is derived from the all-zero seed
+    fn ico_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap()
+    }
+
+    fn signed_tx(key: &Ed25519KeyPair, receiver: Address, value: u64, nonce: u64) -> SignedTransaction {
+        SignedTransaction::create(receiver, value, nonce, key)
+    }
+
+    /// A child of `parent` whose hash is above `rival`'s, so it loses an equal-work tie-break
+    fn block_losing_tie_to(parent: &H256, rival: &H256) -> Block {
+        loop {
+            let block = generate_random_block(parent);
+            if block.hash() > *rival {
+                return block;
+            }
+        }
+    }
+
+    fn block_with_txs(parent: &H256, transactions: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.merkle_root = MerkleTree::new(&transactions).root();
+        block.content.transactions = transactions;
+        block
+    }
+
+    #[test]
+    fn coinbase_is_checked_and_credited() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let miner = Address::from([7u8; 20]);
+        let reward = blockchain.block_reward();
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let payment = SignedTransaction::create_with_fee(Address::from([1u8; 20]), 10, 1, 4, &ico_key);
+
+        // Over the reward and fees, at the wrong height, or after another transaction
+        for transactions in [
+            vec![SignedTransaction::coinbase(miner, reward + 5, 1), payment.clone()],
+            vec![SignedTransaction::coinbase(miner, reward, 2)],
+            vec![payment.clone(), SignedTransaction::coinbase(miner, reward, 1)],
+        ] {
+            let block = block_with_txs(&genesis_hash, transactions);
+            assert_eq!(blockchain.try_insert(&block), Err(BlockError::InvalidCoinbase));
+        }
+
+        let block = block_with_txs(&genesis_hash, vec![SignedTransaction::coinbase(miner, reward + 4, 1), payment]);
+        assert_eq!(blockchain.try_insert(&block), Ok(None));
+        assert_eq!(blockchain.get_state(&block.hash()).unwrap().accounts.get(&miner), Some(&(0, reward + 4)));
+        let economics = blockchain.economics(&block.hash()).unwrap();
+        assert_eq!(economics.fees_collected, 4);
+        assert_eq!(economics.reward, reward + 4);
+        assert_eq!(economics.coinbase_recipient, Some(miner));
+    }
+
+    #[test]
+    fn chainwork_of_competing_tips() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let genesis_work = blockchain.chainwork(&genesis_hash).unwrap();
+
+        // Fork A: two blocks on top of genesis
+        let block_a1 = generate_random_block(&genesis_hash);
+        let block_a2 = generate_random_block(&block_a1.hash());
+        assert!(blockchain.insert(&block_a1));
+        assert!(blockchain.insert(&block_a2));
+
+        // Fork B: one block on top of genesis
+        let block_b1 = generate_random_block(&genesis_hash);
+        assert!(blockchain.insert(&block_b1));
+
+        let work_a = blockchain.chainwork(&block_a2.hash()).unwrap();
+        let work_b = blockchain.chainwork(&block_b1.hash()).unwrap();
+        assert!(work_a > work_b);
+        assert!(work_b > genesis_work);
+        assert_eq!(work_a - work_b, block_work(&block_a2.header.difficulty));
+        assert_eq!(blockchain.chainwork(&blockchain.tip()), Some(work_a));
+    }
+
+    #[test]
+    fn heavier_shorter_fork_wins() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let easy = H256::from([0xff; 32]);
+        let mut hard_bytes = [0xff; 32];
+        hard_bytes[..2].copy_from_slice(&[0x00, 0x0f]);
+        let hard = H256::from(hard_bytes);
+
+        // Three easy blocks make the longer chain
+        let mut parent = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_mined_block(&parent, &easy);
+            assert!(blockchain.insert(&block));
+            parent = block.hash();
+        }
+        let easy_tip = parent;
+        assert_eq!(blockchain.tip(), easy_tip);
+
+        // A single block at a much harder target outweighs them
+        let hard_block = generate_mined_block(&genesis_hash, &hard);
+        assert!(blockchain.insert(&hard_block));
+        assert!(blockchain.chainwork(&hard_block.hash()) > blockchain.chainwork(&easy_tip));
+        assert_eq!(blockchain.tip(), hard_block.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, hard_block.hash()]);
+
+        // Extending the easy chain further does not win the tip back until it has more work
+        let block = generate_mined_block(&easy_tip, &easy);
+        assert!(blockchain.insert(&block));
+        assert_eq!(blockchain.tip(), hard_block.hash());
+    }
+
+    #[test]
+    fn overtaking_fork_reports_reorg() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_random_block(&a1.hash());
+        let b1 = generate_random_block(&genesis_hash);
+        // b2 ties a2 on work, so it must lose the tie-break for a2 to keep the tip
+        let b2 = block_losing_tie_to(&b1.hash(), &a2.hash());
+        let b3 = generate_random_block(&b2.hash());
+        assert_eq!(blockchain.try_insert(&a1), Ok(None));
+        assert_eq!(blockchain.try_insert(&a2), Ok(None));
+        assert_eq!(blockchain.try_insert(&b1), Ok(None));
+        assert_eq!(blockchain.try_insert(&b2), Ok(None));
+
+        let reorg = Reorg {
+            common_ancestor: genesis_hash,
+            removed: vec![a1.hash(), a2.hash()],
+            added: vec![b1.hash(), b2.hash(), b3.hash()],
+        };
+        assert_eq!(blockchain.try_insert(&b3), Ok(Some(reorg)));
+        assert_eq!(blockchain.tip(), b3.hash());
+    }
+
+    #[test]
+    fn block_not_after_its_parent_is_rejected() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let parent = generate_random_block(&blockchain.tip());
+        assert!(blockchain.insert(&parent));
+
+        for timestamp in [parent.header.timestamp, parent.header.timestamp - 1] {
+            let mut block = generate_random_block(&parent.hash());
+            block.header.timestamp = timestamp;
+            assert_eq!(blockchain.try_insert(&block), Err(BlockError::TimestampNotAfterParent));
+        }
+        assert_eq!(blockchain.tip(), parent.hash());
+    }
+
+    #[test]
+    fn block_far_in_the_future_is_rejected() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp += MAX_FUTURE_BLOCK_TIME_MS + 60_000;
+        assert_eq!(blockchain.try_insert(&block), Err(BlockError::TimestampTooFarAhead));
+        assert!(!blockchain.blocks.contains_key(&block.hash()));
+    }
+
+    #[test]
+    fn block_within_the_timestamp_bounds_is_accepted() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let parent = generate_random_block(&blockchain.tip());
+        assert!(blockchain.insert(&parent));
+
+        // Slightly ahead of the local clock is tolerated
+        let mut block = generate_random_block(&parent.hash());
+        block.header.timestamp += MAX_FUTURE_BLOCK_TIME_MS - 60_000;
+        assert_eq!(blockchain.try_insert(&block), Ok(None));
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    #[test]
+    fn headers_follow_the_locator_and_wait_for_bodies() {
+        let mut source = Blockchain::new(&[0u8; 32]);
+        for _ in 0..30 {
+            assert!(source.insert(&generate_random_block(&source.tip())));
+        }
+        let chain = source.all_blocks_in_longest_chain();
+        let mut syncing = Blockchain::new(&[0u8; 32]);
+        for hash in &chain[1..11] {
+            assert!(syncing.insert(&source.blocks[hash]));
+        }
+
+        // The locator lists recent blocks first and always reaches genesis
+        let locator = syncing.locator();
+        assert_eq!(locator[0], chain[10]);
+        assert_eq!(*locator.last().unwrap(), chain[0]);
+        let headers = source.headers_after(&locator, 5);
+        let hashes: Vec<H256> = headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(hashes, chain[11..16].to_vec());
+        // Nothing recognised starts from genesis
+        assert_eq!(source.headers_after(&[H256::from([0xab; 32])], 1)[0].hash(), chain[1]);
+
+        // A header may build on another accepted header, and is forgotten once its block arrives
+        assert_eq!(syncing.accept_header(&headers[0]), Ok(true));
+        assert_eq!(syncing.accept_header(&headers[1]), Ok(true));
+        assert_eq!(syncing.accept_header(&headers[1]), Ok(false));
+        assert_eq!(syncing.accept_header(&headers[3]), Err(BlockError::UnknownParent));
+        assert!(syncing.has_pending_header(&hashes[1]));
+        assert!(syncing.insert(&source.blocks[&hashes[0]]));
+        assert!(!syncing.has_pending_header(&hashes[0]));
+        assert_eq!(syncing.tip(), hashes[0]);
+
+        let mut unworked = headers[2].clone();
+        unworked.difficulty = H256::from([0u8; 32]);
+        assert_eq!(syncing.accept_header(&unworked), Err(BlockError::InsufficientWork));
+    }
+
+    #[test]
+    fn block_over_the_transaction_limit_is_rejected() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let block = block_with_txs(&blockchain.tip(), vec![SignedTransaction::default(); MAX_TX_PER_BLOCK + 1]);
+        assert_eq!(blockchain.try_insert(&block), Err(BlockError::TooManyTransactions));
+    }
+
+    #[test]
+    fn equal_work_tips_break_ties_by_hash() {
+        let genesis = Blockchain::new(&[0u8; 32]).tip();
+        let a = generate_random_block(&genesis);
+        let b = generate_random_block(&genesis);
+        let smaller = std::cmp::min(a.hash(), b.hash());
+
+        for (first, second) in [(&a, &b), (&b, &a)] {
+            let mut blockchain = Blockchain::new(&[0u8; 32]);
+            assert!(blockchain.insert(first));
+            assert!(blockchain.insert(second));
+            assert_eq!(blockchain.chainwork(&first.hash()), blockchain.chainwork(&second.hash()));
+            assert_eq!(blockchain.tip(), smaller);
+            assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis, smaller]);
+        }
+    }
+
+    #[test]
+    fn block_accessors_cover_forks_and_unknown_hashes() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        let child = generate_random_block(&block.hash());
+        let fork = generate_random_block(&genesis_hash);
+        for b in [&block, &child, &fork] {
+            assert!(blockchain.insert(b));
+        }
+
+        for (b, height) in [(&block, 1), (&child, 2), (&fork, 1)] {
+            assert!(blockchain.contains(&b.hash()));
+            assert_eq!(blockchain.get_block(&b.hash()).unwrap().hash(), b.hash());
+            assert_eq!(blockchain.height_of(&b.hash()), Some(height));
+        }
+        assert_eq!(blockchain.height_of(&genesis_hash), Some(0));
+        assert_eq!(blockchain.block_count(), 4);
+
+        let unknown = generate_random_hash();
+        assert!(!blockchain.contains(&unknown));
+        assert!(blockchain.get_block(&unknown).is_none());
+        assert_eq!(blockchain.height_of(&unknown), None);
+    }
+
+    #[test]
+    fn chain_stats_average_block_intervals() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let empty = blockchain.chain_stats(10);
+        assert_eq!((empty.height, empty.transaction_count, empty.window_blocks), (0, 0, 0));
+        assert_eq!((empty.avg_block_interval_ms, empty.blocks_per_minute), (None, None));
+
+        // Gaps of 1s, 3s, 2s and 6s after the first block
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() - 60_000;
+        for offset in [0, 1_000, 4_000, 6_000, 12_000] {
+            let mut block = generate_random_block(&blockchain.tip());
+            block.header.timestamp = start + offset;
+            assert!(blockchain.insert(&block));
+            if offset == 0 {
+                assert_eq!(blockchain.chain_stats(10).avg_block_interval_ms, None);
+            }
+        }
+
+        let stats = blockchain.chain_stats(10);
+        assert_eq!(stats.height, 5);
+        assert_eq!(stats.transaction_count, 0);
+        assert_eq!(stats.avg_block_interval_ms, Some(3_000.0));
+        assert_eq!((stats.window_blocks, stats.blocks_per_minute), (5, Some(20.0)));
+
+        // The last three blocks are 8s apart in all, 4s per gap
+        let recent = blockchain.chain_stats(3);
+        assert_eq!(recent.avg_block_interval_ms, Some(3_000.0));
+        assert_eq!((recent.window_blocks, recent.blocks_per_minute), (3, Some(15.0)));
+        assert_eq!(blockchain.chain_stats(1).blocks_per_minute, None);
+    }
+
+    #[test]
+    fn longest_chain_range_is_clamped() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        for _ in 0..5 {
+            assert!(blockchain.insert(&generate_random_block(&blockchain.tip())));
+        }
+        let chain = blockchain.all_blocks_in_longest_chain();
+
+        assert_eq!(blockchain.blocks_in_longest_chain_range(1, 4), &chain[1..4]);
+        assert_eq!(blockchain.blocks_in_longest_chain_range(0, usize::MAX), &chain[..]);
+        assert_eq!(blockchain.blocks_in_longest_chain_range(4, 100), &chain[4..]);
+        assert!(blockchain.blocks_in_longest_chain_range(3, 3).is_empty());
+        assert!(blockchain.blocks_in_longest_chain_range(4, 2).is_empty());
+        assert!(blockchain.blocks_in_longest_chain_range(50, 60).is_empty());
+    }
+
+    #[test]
+    fn chainwork_of_unknown_block() {
+        let blockchain = Blockchain::new(&[0u8; 32]);
+        assert_eq!(blockchain.chainwork(&H256::from([0xab; 32])), None);
+    }
+
+    #[test]
+    fn confirmed_transaction_cannot_be_replayed() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let tx = signed_tx(&ico_key(), Address::from([1u8; 20]), 10, 1);
+
+        let block_1 = block_with_txs(&genesis_hash, vec![tx.clone()]);
+        assert!(blockchain.insert(&block_1));
+        assert!(blockchain.contains_confirmed_tx(&tx.hash()));
+
+        // Re-including the same transaction on top of its own block is rejected
+        let replay = block_with_txs(&block_1.hash(), vec![tx.clone()]);
+        assert!(!blockchain.insert(&replay));
+        assert!(!blockchain.blocks.contains_key(&replay.hash()));
+        assert_eq!(blockchain.tip(), block_1.hash());
+    }
+
+    #[test]
+    fn state_is_available_right_after_insert() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let receiver = Address::from([1u8; 20]);
+        let block = block_with_txs(&genesis_hash, vec![signed_tx(&ico_key(), receiver, 10, 1)]);
+        assert!(blockchain.state_at(&block.hash()).is_none());
+
+        assert!(blockchain.insert(&block));
+        let state = blockchain.state_at(&block.hash()).unwrap();
+        assert_eq!(state.accounts.get(&receiver), Some(&(0, 10)));
+    }
+
+    #[test]
+    fn block_with_tampered_signature_is_rejected() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let good = signed_tx(&ico_key(), Address::from([1u8; 20]), 10, 1);
+        let mut tampered = signed_tx(&ico_key(), Address::from([2u8; 20]), 10, 2);
+        tampered.signature[0] ^= 0xff;
+
+        let block = block_with_txs(&genesis_hash, vec![good, tampered]);
+        assert_eq!(blockchain.try_insert(&block), Err(BlockError::InvalidSignature));
+        assert!(!blockchain.blocks.contains_key(&block.hash()));
+        assert_eq!(blockchain.tip(), genesis_hash);
+
+        // Benchmarks may turn the check off
+        blockchain.set_verify_signatures(false);
+        assert_eq!(blockchain.try_insert(&block), Ok(None));
+    }
+
+    #[test]
+    fn state_at_each_block_reflects_its_transactions() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let ico_address = Address::from_public_key_bytes(ico_key().public_key().as_ref());
+        let alice = Address::from([1u8; 20]);
+        let bob = Address::from([2u8; 20]);
+
+        let block_1 = block_with_txs(&blockchain.tip(), vec![signed_tx(&ico_key(), alice, 100, 1)]);
+        assert!(blockchain.insert(&block_1));
+        let block_2 = block_with_txs(&block_1.hash(), vec![
+            signed_tx(&ico_key(), bob, 30, 2),
+            signed_tx(&ico_key(), alice, 5, 3),
+        ]);
+        assert!(blockchain.insert(&block_2));
+
+        let genesis = blockchain.genesis_state();
+        assert_eq!(genesis.accounts.get(&ico_address), Some(&(0, DEFAULT_ICO_BALANCE)));
+        assert_eq!(genesis.accounts.get(&alice), None);
+
+        let after_1 = blockchain.state_at(&block_1.hash()).unwrap();
+        assert_eq!(after_1.accounts.get(&ico_address), Some(&(1, DEFAULT_ICO_BALANCE - 100)));
+        assert_eq!(after_1.accounts.get(&alice), Some(&(0, 100)));
+        assert_eq!(after_1.accounts.get(&bob), None);
+
+        let after_2 = blockchain.state_at(&block_2.hash()).unwrap();
+        assert_eq!(after_2.accounts.get(&ico_address), Some(&(3, DEFAULT_ICO_BALANCE - 135)));
+        assert_eq!(after_2.accounts.get(&alice), Some(&(0, 105)));
+        assert_eq!(after_2.accounts.get(&bob), Some(&(0, 30)));
+    }
+
+    #[test]
+    fn evicted_state_is_recomputed() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        blockchain.set_state_cache_capacity(2);
+        let mut computed = vec![];
+        for nonce in 1..=5u64 {
+            let receiver = Address::from([nonce as u8; 20]);
+            let block = block_with_txs(&blockchain.tip(), vec![signed_tx(&ico_key(), receiver, nonce * 10, nonce)]);
+            assert!(blockchain.insert(&block));
+            computed.push((block.hash(), blockchain.state_at(&block.hash()).unwrap()));
+        }
+        assert!(blockchain.states.lock().unwrap().cached_count() <= 2);
+
+        for (hash, state) in &computed {
+            assert_eq!(blockchain.state_at(hash).unwrap().accounts, state.accounts);
+        }
+        // Blocks can still be built on a parent whose state was evicted
+        let first = computed[0].0;
+        let fork = block_with_txs(&first, vec![signed_tx(&ico_key(), Address::from([9u8; 20]), 1, 2)]);
+        assert!(blockchain.insert(&fork));
+    }
+
+    #[test]
+    fn competing_fork_may_include_same_transaction() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let tx = signed_tx(&ico_key(), Address::from([1u8; 20]), 10, 1);
+
+        let block_a = block_with_txs(&genesis_hash, vec![tx.clone()]);
+        assert!(blockchain.insert(&block_a));
+
+        // A sibling is not a descendant of block_a, so it may confirm the transaction too
+        let block_b = block_with_txs(&genesis_hash, vec![tx.clone()]);
+        assert!(blockchain.insert(&block_b));
+        let block_b2 = generate_random_block(&block_b.hash());
+        assert!(blockchain.insert(&block_b2));
+        assert_eq!(blockchain.tip(), block_b2.hash());
+
+        // After the reorg the transaction is confirmed by block_b
+        assert!(blockchain.contains_confirmed_tx(&tx.hash()));
+        let replay = block_with_txs(&block_b2.hash(), vec![tx]);
+        assert!(!blockchain.insert(&replay));
+    }
+
+    #[test]
+    fn fork_below_finalized_height_is_not_stored() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        blockchain.set_finality_depth(2);
+        let genesis_hash = blockchain.tip();
+        for _ in 0..4 {
+            let block = generate_random_block(&blockchain.tip());
+            assert!(blockchain.insert(&block));
+        }
+        assert_eq!(blockchain.finalized_height(), 2);
+
+        // Height 2 is final, so a competing block there is pointless
+        let first = blockchain.all_blocks_in_longest_chain()[1];
+        let deep_fork = generate_random_block(&first);
+        assert!(!blockchain.insert(&deep_fork));
+        assert!(!blockchain.blocks.contains_key(&deep_fork.hash()));
+        let deeper_fork = generate_random_block(&genesis_hash);
+        assert!(!blockchain.insert(&deeper_fork));
+
+        // Forks above the finalized height and new tips are still accepted
+        let shallow_parent = blockchain.all_blocks_in_longest_chain()[2];
+        assert!(blockchain.insert(&generate_random_block(&shallow_parent)));
+        let new_tip = generate_random_block(&blockchain.tip());
+        assert!(blockchain.insert(&new_tip));
+        assert_eq!(blockchain.tip(), new_tip.hash());
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}-{}", name, std::process::id(), rand::random::<u32>()))
+    }
+
+    #[test]
+    fn verify_on_load_accepts_valid_chain() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let difficulty = blockchain.blocks[&blockchain.tip()].get_difficulty();
+        for _ in 0..3 {
+            let block = generate_mined_block(&blockchain.tip(), &difficulty);
+            assert!(blockchain.insert(&block));
+        }
+        let path = temp_path("valid-chain");
+        blockchain.save_to_file(&path).unwrap();
+
+        let mut loaded = Blockchain::new(&[0u8; 32]);
+        loaded.load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.verify_integrity(), Ok(()));
+        assert_eq!(loaded.tip(), blockchain.tip());
+    }
+
+    #[test]
+    fn compressed_chain_round_trips() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let difficulty = blockchain.blocks[&blockchain.tip()].get_difficulty();
+        for _ in 0..3 {
+            let block = generate_mined_block(&blockchain.tip(), &difficulty);
+            assert!(blockchain.insert(&block));
+        }
+        let fork = generate_mined_block(&blockchain.all_blocks_in_longest_chain()[1], &difficulty);
+        assert!(blockchain.insert(&fork));
+
+        let path = temp_path("compressed-chain");
+        blockchain.set_compress_on_save(true);
+        blockchain.save_to_file(&path).unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(&GZIP_MAGIC));
+
+        let mut loaded = Blockchain::new(&[0u8; 32]);
+        loaded.load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.verify_integrity(), Ok(()));
+        assert_eq!(loaded.all_blocks_in_longest_chain(), blockchain.all_blocks_in_longest_chain());
+        let mut loaded_hashes: Vec<H256> = loaded.blocks.keys().copied().collect();
+        let mut saved_hashes: Vec<H256> = blockchain.blocks.keys().copied().collect();
+        loaded_hashes.sort();
+        saved_hashes.sort();
+        assert_eq!(loaded_hashes, saved_hashes);
+    }
+
+    /// A ledger that only counts transactions, standing in for another ledger model
+    #[derive(Clone)]
+    struct TxCount(usize);
+
+    impl Ledger for TxCount {
+        type Snapshot = usize;
+
+        fn validate(&self, _tx: &SignedTransaction) -> bool {
+            true
+        }
+
+        fn apply(&mut self, _tx: &SignedTransaction) {
+            self.0 += 1;
+        }
+
+        fn snapshot(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn blockchain_runs_over_another_ledger() {
+        let mut blockchain = Blockchain::with_genesis_ledger(&NodeConfig::default(), TxCount(0));
+        let genesis_hash = blockchain.tip();
+        // Unfunded senders are fine, since this ledger accepts anything
+        let key = Ed25519KeyPair::from_seed_unchecked(&[9u8; 32]).unwrap();
+        let block_1 = block_with_txs(&genesis_hash, vec![
+            signed_tx(&key, Address::from([1u8; 20]), 5, 7),
+            signed_tx(&key, Address::from([2u8; 20]), 5, 3),
+        ]);
+        assert!(blockchain.insert(&block_1));
+
+        assert_eq!(blockchain.genesis_state().snapshot(), 0);
+        assert_eq!(blockchain.state_at(&block_1.hash()).unwrap().snapshot(), 2);
+    }
+
+    /// Extend the longest chain by `count` blocks, each `interval_ms` after its parent and
+    /// carrying the difficulty the chain expects
+    fn extend_at_interval(blockchain: &mut Blockchain, count: usize, interval_ms: u128) {
+        for _ in 0..count {
+            let parent_hash = blockchain.tip();
+            let parent_time = blockchain.blocks[&parent_hash].header.timestamp;
+            let mut block = generate_random_block(&parent_hash);
+            block.header.timestamp = parent_time.max(1_000_000) + interval_ms;
+            block.header.difficulty = blockchain.compute_next_difficulty(&parent_hash).unwrap();
+            assert!(blockchain.insert(&block));
+        }
+    }
+
+    fn retargeting_chain() -> Blockchain {
+        let config = NodeConfig { target_block_interval_ms: 1000, ..Default::default() };
+        let mut blockchain = Blockchain::with_config(&[0u8; 32], &config);
+        // Reach the first retarget at the target pace, so it leaves the difficulty alone
+        extend_at_interval(&mut blockchain, 2 * DIFFICULTY_WINDOW - 1, 1000);
+        blockchain
+    }
+
+    #[test]
+    fn difficulty_rises_when_blocks_come_fast() {
+        let mut blockchain = retargeting_chain();
+        let start = blockchain.consensus_difficulty();
+        assert_eq!(blockchain.compute_next_difficulty(&blockchain.tip()), Some(start));
+
+        extend_at_interval(&mut blockchain, DIFFICULTY_WINDOW, 500);
+        let next = blockchain.compute_next_difficulty(&blockchain.tip()).unwrap();
+        assert!(next < start);
+        assert_eq!(next, scale_target(&start, 1, 2));
+
+        // Far too fast is clamped to a 4x change
+        let mut blockchain = retargeting_chain();
+        extend_at_interval(&mut blockchain, DIFFICULTY_WINDOW, 1);
+        assert_eq!(blockchain.compute_next_difficulty(&blockchain.tip()), Some(scale_target(&start, 1, 4)));
+    }
+
+    #[test]
+    fn difficulty_falls_when_blocks_come_slowly() {
+        let mut blockchain = retargeting_chain();
+        let start = blockchain.consensus_difficulty();
+        extend_at_interval(&mut blockchain, DIFFICULTY_WINDOW, 2000);
+        let next = blockchain.compute_next_difficulty(&blockchain.tip()).unwrap();
+        assert!(next > start);
+        assert_eq!(next, scale_target(&start, 2, 1));
+
+        // Blocks between retargets keep their parent's difficulty
+        extend_at_interval(&mut blockchain, 1, 2000);
+        assert_eq!(blockchain.compute_next_difficulty(&blockchain.tip()), Some(next));
+    }
+
+    #[test]
+    fn scaled_target_saturates() {
+        let easy = H256::from([0xff; 32]);
+        assert_eq!(scale_target(&easy, 4, 1), easy);
+        let mut half = [0xff; 32];
+        half[0] = 0x7f;
+        assert_eq!(scale_target(&easy, 1, 2), H256::from(half));
+    }
+
+    #[test]
+    fn genesis_uses_configured_difficulty() {
+        let difficulty: H256 = "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".parse().unwrap();
+        let blockchain = Blockchain::with_config(&[0u8; 32], &NodeConfig { consensus_difficulty: difficulty, ..Default::default() });
+        assert_eq!(blockchain.genesis().header.difficulty, difficulty);
+        assert_eq!(blockchain.compute_next_difficulty(&blockchain.tip()), Some(difficulty));
+    }
+
+    #[test]
+    fn genesis_configs_give_distinct_genesis_hashes() {
+        let default = Blockchain::new(&[0u8; 32]);
+        assert_eq!(Blockchain::with_genesis(GenesisConfig::default()).genesis().hash(), default.genesis().hash());
+        assert_eq!(default.genesis().header.timestamp, 0);
+        assert_eq!(default.genesis().header.merkle_root, H256::from([0x00; 32]));
+
+        let funded = vec![(Address::from([1u8; 20]), 500), (Address::from([2u8; 20]), 700)];
+        let configs = vec![
+            GenesisConfig { timestamp: 1_700_000_000_000, ..Default::default() },
+            GenesisConfig { difficulty: H256::from([0x0f; 32]), ..Default::default() },
+            GenesisConfig { ico_allocations: Some(funded.clone()), ..Default::default() },
+            GenesisConfig { ico_allocations: Some(funded[..1].to_vec()), ..Default::default() },
+        ];
+        let mut hashes: Vec<H256> = configs.into_iter().map(|config| Blockchain::with_genesis(config).genesis().hash()).collect();
+        hashes.push(default.genesis().hash());
+        let distinct: std::collections::HashSet<H256> = hashes.iter().copied().collect();
+        assert_eq!(distinct.len(), hashes.len());
+
+        // The allocations fund the genesis state, and blocks from another genesis find no parent
+        let mut blockchain = Blockchain::with_genesis(GenesisConfig { ico_allocations: Some(funded.clone()), ..Default::default() });
+        assert_eq!(blockchain.genesis_state().accounts.len(), 2);
+        assert_eq!(blockchain.genesis_state().accounts[&funded[1].0], (0, 700));
+        let foreign = generate_random_block(&default.tip());
+        assert_eq!(blockchain.try_insert(&foreign), Err(BlockError::UnknownParent));
+    }
+
+    #[test]
+    fn network_id_changes_genesis() {
+        let default = Blockchain::new(&[0u8; 32]);
+        let experiment_a = Blockchain::with_config(&[0u8; 32], &NodeConfig { network_id: 1, ..Default::default() });
+        let experiment_b = Blockchain::with_config(&[0u8; 32], &NodeConfig { network_id: 2, ..Default::default() });
+        assert_ne!(experiment_a.genesis().hash(), experiment_b.genesis().hash());
+        assert_ne!(default.genesis().hash(), experiment_a.genesis().hash());
+    }
+
+    #[test]
+    fn tip_subscribers_hear_about_new_tips() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis_hash = blockchain.tip();
+        let subscriber = blockchain.subscribe_tip();
+        let dropped = blockchain.subscribe_tip();
+        drop(dropped);
+
+        let block_1 = generate_random_block(&genesis_hash);
+        assert!(blockchain.insert(&block_1));
+        assert_eq!(subscriber.try_recv(), Ok(block_1.hash()));
+
+        // A block that does not move the tip is not announced
+        let sibling = block_losing_tie_to(&genesis_hash, &block_1.hash());
+        assert!(blockchain.insert(&sibling));
+        assert!(subscriber.try_recv().is_err());
+        assert_eq!(blockchain.tip_subscribers.len(), 1);
+    }
+
+    #[test]
+    fn verify_on_load_rejects_invalid_block() {
+        let mut blockchain = Blockchain::new(&[0u8; 32]);
+        let difficulty = blockchain.blocks[&blockchain.tip()].get_difficulty();
+        let good = generate_mined_block(&blockchain.tip(), &difficulty);
+        assert!(blockchain.insert(&good));
+
+        // A block whose nonce was tampered with no longer meets the PoW target
+        let mut tampered = generate_mined_block(&good.hash(), &difficulty);
+        while tampered.hash() <= difficulty {
+            tampered.header.nonce = tampered.header.nonce.wrapping_add(1);
+        }
+        assert!(blockchain.insert(&tampered));
+
+        let path = temp_path("invalid-chain");
+        blockchain.save_to_file(&path).unwrap();
+        let mut loaded = Blockchain::new(&[0u8; 32]);
+        loaded.load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let err = loaded.verify_integrity().unwrap_err();
+        assert!(err.contains(&tampered.hash().to_string()));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::types::hash::H256;
+use crate::types::ledger::Ledger;
+use std::collections::HashMap;
+
+/// Default number of block states kept in memory
+pub const DEFAULT_STATE_CACHE_CAPACITY: usize = 1024;
+
+/// Least-recently-used cache of block states. The pinned (genesis) state is never
+/// evicted, so any other state can be rebuilt by replaying blocks on top of it.
+pub struct StateCache<L> {
+    states: HashMap<H256, (L, u64)>, // State and the tick it was last used at
+    tick: u64,
+    capacity: usize,
+    pinned: H256,
+}
+
+impl<L: Ledger> StateCache<L> {
+    pub fn new(pinned: H256, pinned_state: L) -> Self {
+        let mut states = HashMap::new();
+        states.insert(pinned, (pinned_state, 0));
+        Self {
+            states,
+            tick: 0,
+            capacity: DEFAULT_STATE_CACHE_CAPACITY,
+            pinned,
+        }
+    }
+
+    /// Change how many states are kept, evicting the least recently used if over
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    #[cfg(test)]
+    pub fn cached_count(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Copy of a cached state, marking it as recently used
+    pub fn get(&mut self, block_hash: &H256) -> Option<L> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.states.get_mut(block_hash).map(|(state, used)| {
+            *used = tick;
+            state.clone()
+        })
+    }
+
+    #[cfg(test)]
+    pub fn get_mut(&mut self, block_hash: &H256) -> Option<&mut L> {
+        self.states.get_mut(block_hash).map(|(state, _)| state)
+    }
+
+    pub fn insert(&mut self, block_hash: H256, state: L) {
+        self.tick += 1;
+        self.states.insert(block_hash, (state, self.tick));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.states.len() > self.capacity.max(1) {
+            let pinned = self.pinned;
+            let oldest = self
+                .states
+                .iter()
+                .filter(|(hash, _)| **hash != pinned)
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(hash, _)| *hash);
+            match oldest {
+                Some(hash) => {
+                    self.states.remove(&hash);
+                }
+                None => return,
+            }
+        }
+    }
+}
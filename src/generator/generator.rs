@@ -1,65 +1,125 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use log::info;
-use rand::Rng;
+use rand::seq::IteratorRandom;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use ring::signature;
 use std::ops::Add;
 use std::time;
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use crate::blockchain::Blockchain;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::key_pair;
+use crate::types::key_pair::Signer;
 use crate::types::transaction;
 use crate::network::message::Message;
-use crate::types::transaction::{Transaction, SignedTransaction, Mempool};
+use crate::metrics::{TimedLock, TimedRwLock, BLOCKCHAIN_LOCK, MEMPOOL_LOCK};
+use crate::types::transaction::{SignedTransaction, Mempool};
 use crate::types::address::Address;
 use crate::types::hash::Hashable;
-use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::signature::Ed25519KeyPair;
 use ring::rand::SystemRandom;
 
 
 
 
+pub enum ControlSignal {
+    Stop,
+}
+
 #[derive(Clone)]
 pub struct TransactionGenerator {
     mempool: Arc<Mutex<Mempool>>, 
     server: ServerHandle,
-    key_pair: Arc<Ed25519KeyPair>,
+    key_pair: Arc<dyn Signer + Send + Sync>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    known_receivers: bool,
+    rng: Option<Arc<Mutex<StdRng>>>, // Seeded source of receivers and amounts; thread_rng if None
+    control_chan: Sender<ControlSignal>,
+    control_receiver: Receiver<ControlSignal>,
+    running: Arc<AtomicBool>,
+    next_nonce: Arc<AtomicU64>, // Nonce of the next transaction, kept across restarts
 }
 
 impl TransactionGenerator {
-    pub fn new(mempool: Arc<Mutex<Mempool>>, server: ServerHandle, key_pair: Arc<Ed25519KeyPair>,) -> Self {
-        Self {mempool, server, key_pair,}
+    pub fn new(mempool: Arc<Mutex<Mempool>>, server: ServerHandle, key_pair: Arc<dyn Signer + Send + Sync>, blockchain: Arc<RwLock<Blockchain>>,) -> Self {
+        let (control_chan, control_receiver) = unbounded();
+        Self {mempool, server, key_pair, blockchain, known_receivers: false, rng: None, control_chan, control_receiver, running: Arc::new(AtomicBool::new(false)), next_nonce: Arc::new(AtomicU64::new(0)),}
+    }
+
+    /// A generator whose receivers and amounts come from an RNG seeded with `seed`, so the
+    /// same seed and key give the same transactions every run
+    pub fn new_with_seed(mempool: Arc<Mutex<Mempool>>, server: ServerHandle, key_pair: Arc<dyn Signer + Send + Sync>, blockchain: Arc<RwLock<Blockchain>>, seed: u64) -> Self {
+        let mut generator = Self::new(mempool, server, key_pair, blockchain);
+        generator.rng = Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed))));
+        generator
+    }
+
+    /// Send to accounts already in the tip state instead of fresh random addresses
+    pub fn use_known_receivers(&mut self, enabled: bool) {
+        self.known_receivers = enabled;
     }
 
-    pub fn start(self, theta: u64) {
+    /// Spawn the generation loop; returns false if it is already running
+    pub fn start(&self, theta: u64) -> bool {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        // A stop sent while nothing was running must not end the new loop
+        while self.control_receiver.try_recv().is_ok() {}
+        let generator = self.clone();
         thread::Builder::new()
             .name("transaction-generator".to_string())
             .spawn(move || {
-                self.generate_transactions(theta);
+                generator.generate_transactions(theta);
+                generator.running.store(false, Ordering::SeqCst);
+                info!("Transaction generator stopped");
             })
             .unwrap();
         info!("Transaction generator started");
+        true
+    }
+
+    pub fn stop(&self) {
+        if self.running.load(Ordering::SeqCst) {
+            self.control_chan.send(ControlSignal::Stop).unwrap();
+        }
     }
 
+    /// Nonce to continue from: past everything this generator has sent, and past what the
+    /// tip has already applied for its sender
+    fn first_nonce(&self) -> u64 {
+        let sender = Address::from_public_key_bytes(&self.key_pair.public_key_bytes());
+        let blockchain = self.blockchain.read_timed(&BLOCKCHAIN_LOCK);
+        let applied = blockchain
+            .state_at(&blockchain.tip())
+            .and_then(|state| state.accounts.get(&sender).map(|(nonce, _)| nonce + 1))
+            .unwrap_or(0);
+        applied.max(self.next_nonce.load(Ordering::SeqCst))
+    }
 
     fn generate_transactions(&self, theta: u64) {
-        let mut nonce = 0;
+        let mut nonce = self.first_nonce();
         loop {
+            match self.control_receiver.try_recv() {
+                Ok(ControlSignal::Stop) | Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => {}
+            }
             //unimplemented!();
             if let Some(transaction) = self.create_valid_transaction(nonce) {
                 nonce += 1;
+                self.next_nonce.store(nonce, Ordering::SeqCst);
                 let tx_hash = transaction.hash();
 
-                {
-                    let mut mempool = self.mempool.lock().unwrap();
-                    if let Err(e) = mempool.add_transaction(transaction.clone()) {
-                        info!("Failed to add transaction to mempool: {}", e);
-                        drop(mempool);
-                        continue;
-                    }
-
-                    self.server.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
-                    drop(mempool);
+                // A rejected transaction still waits out the interval before the next one
+                let mut mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
+                match mempool.add_transaction(transaction.clone()) {
+                    Ok(()) => self.server.broadcast(Message::NewTransactionHashes(vec![tx_hash])),
+                    Err(e) => info!("Failed to add transaction to mempool: {}", e),
                 }
+                drop(mempool);
 
             } else {
                 info!("Failed to generate a valid transaction.");
@@ -68,7 +128,11 @@ impl TransactionGenerator {
             if theta != 0 {
                 //let interval = time::Duration::from_millis(10 * theta);
                 let interval = time::Duration::from_millis(2 * theta);
-                thread::sleep(interval);
+                // Wait on the control channel so a stop does not sit out the whole interval
+                match self.control_receiver.recv_timeout(interval) {
+                    Ok(ControlSignal::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
             }
 
         }
@@ -76,39 +140,46 @@ impl TransactionGenerator {
     }
 
     fn create_valid_transaction(&self, nonce: u64) -> Option<SignedTransaction> {
-        let sender_address = Address::from_public_key_bytes(self.key_pair.public_key().as_ref());
-
-        let mut rng = rand::thread_rng();
-        
+        let sender_address = Address::from_public_key_bytes(&self.key_pair.public_key_bytes());
 
         // Generate random receiver and transfer amount
 
-        let receiver = self.generate_random_address();
-        let value = rng.gen_range(1..10); // Small amount between 1 and 10
+        let receiver = self.pick_receiver(&sender_address);
+        let (value, fee) = self.with_rng(|rng| (rng.gen_range(1..10), rng.gen_range(0..3))); // Small amount between 1 and 10
 
 
 
-        // Create transaction
-        let transaction = Transaction {
-            receiver,
-            value,
-            nonce, 
-        };
+        // Create and sign transaction
+        Some(SignedTransaction::create_with_fee(receiver, value, nonce, fee, self.key_pair.as_ref()))
 
-        // Sign transaction
-        let signature = self.key_pair.sign(&bincode::serialize(&transaction).unwrap());
+    }
 
-        Some(SignedTransaction {
-            transaction,
-            signature: signature.as_ref().to_vec(),
-            public_key: self.key_pair.public_key().as_ref().to_vec(),
-        })
+    /// A random account from the tip state other than `sender`, or a fresh address if there is none
+    fn pick_receiver(&self, sender: &Address) -> Address {
+        if self.known_receivers {
+            let blockchain = self.blockchain.read_timed(&BLOCKCHAIN_LOCK);
+            if let Some(state) = blockchain.state_at(&blockchain.tip()) {
+                drop(blockchain);
+                let known = state.accounts.keys().filter(|address| *address != sender);
+                if let Some(receiver) = self.with_rng(|rng| known.choose(rng)) {
+                    return *receiver;
+                }
+            }
+        }
+        self.generate_random_address()
+    }
 
+    /// Run `f` with the seeded RNG if there is one, otherwise with the thread's
+    fn with_rng<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match &self.rng {
+            Some(rng) => f(&mut *rng.lock().unwrap()),
+            None => f(&mut rand::thread_rng()),
+        }
     }
 
     fn generate_random_address(&self) -> Address {
         // Generate 32 random bytes to simulate a public key
-        let random_bytes: Vec<u8> = (0..32).map(|_| rand::thread_rng().gen()).collect();
+        let random_bytes: Vec<u8> = self.with_rng(|rng| (0..32).map(|_| rng.gen()).collect());
         Address::from_public_key_bytes(&random_bytes)
     }
 
@@ -116,29 +187,132 @@ impl TransactionGenerator {
     fn create_random_transactions(&self) -> SignedTransaction {
         let receiver = self.generate_random_address();
         let value = rand::thread_rng().gen_range(1..1000);
+        let fee = rand::thread_rng().gen_range(0..10);
         let nonce = rand::thread_rng().gen_range(1..1000);
 
-        let transaction = Transaction {
-            receiver,
-            value,
-            nonce,
-        };
-
         // Generate a key pair and sign the transaction
         let rng = SystemRandom::new();
         let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).expect("Failed to generate Ed25519 key");
         let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).expect("Failed to parse Ed25519 key");
-        let signature = key_pair.sign(&bincode::serialize(&transaction).unwrap());
 
-        SignedTransaction {
-            transaction,
-            signature: signature.as_ref().to_vec(),
-            public_key: key_pair.public_key().as_ref().to_vec(),
-        }
+        SignedTransaction::create_with_fee(receiver, value, nonce, fee, &key_pair)
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::Duration;
+    use ntest::timeout;
+
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::TransactionGenerator;
+    use crate::blockchain::Blockchain;
+    use crate::network::server::{Handle as ServerHandle, TestReceiver as ServerTestReceiver};
+    use crate::types::address::Address;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::Mempool;
+
+    fn generator_with_key(key: Ed25519KeyPair) -> (TransactionGenerator, ServerTestReceiver) {
+        let mempool = Arc::new(Mutex::new(Mempool::new(100_000)));
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        (TransactionGenerator::new(mempool, server, Arc::new(key), blockchain), server_receiver)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn stopped_generator_leaves_the_mempool_alone() {
+        let (generator, _server_receiver) = generator_with_key(key_pair::random());
+        let mempool = generator.mempool.clone();
+
+        assert!(generator.start(1));
+        assert!(!generator.start(1));
+        while mempool.lock().unwrap().stats().count == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        generator.stop();
+        while generator.running.load(std::sync::atomic::Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let count = mempool.lock().unwrap().stats().count;
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(mempool.lock().unwrap().stats().count, count);
 
+        // A stopped generator can be started again
+        assert!(generator.start(1));
+        generator.stop();
+    }
 
+    #[test]
+    #[timeout(60000)]
+    fn restarted_generator_continues_its_nonces() {
+        let (generator, _server_receiver) = generator_with_key(key_pair::random());
+        let mempool = generator.mempool.clone();
+        let run_until = |count: usize| {
+            assert!(generator.start(1));
+            while mempool.lock().unwrap().stats().count < count {
+                thread::sleep(Duration::from_millis(10));
+            }
+            generator.stop();
+            while generator.running.load(std::sync::atomic::Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
 
+        run_until(3);
+        let before = mempool.lock().unwrap().stats().count;
+        run_until(before + 3);
+
+        // Every transaction took a fresh nonce, so none was turned away as a repeat
+        assert!(mempool.lock().unwrap().recent_rejections().is_empty());
+        let mut nonces: Vec<u64> = mempool.lock().unwrap().get_all_transactions().iter().map(|tx| tx.transaction.nonce).collect();
+        nonces.sort_unstable();
+        let expected: Vec<u64> = (0..nonces.len() as u64).collect();
+        assert_eq!(nonces, expected);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_transactions() {
+        let seeded = |seed| {
+            let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+            let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+            let (server, _server_receiver) = ServerHandle::new_for_test();
+            let key = Ed25519KeyPair::from_seed_unchecked(&[9u8; 32]).unwrap();
+            let generator = TransactionGenerator::new_with_seed(mempool, server, Arc::new(key), blockchain, seed);
+            (0..20).map(|nonce| generator.create_valid_transaction(nonce).unwrap().hash()).collect::<Vec<_>>()
+        };
+        assert_eq!(seeded(42), seeded(42));
+        assert_ne!(seeded(42), seeded(43));
+    }
+
+    #[test]
+    fn receivers_are_drawn_from_known_accounts() {
+        let ico_address = Address::from_public_key_bytes(
+            Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap().public_key().as_ref(),
+        );
+        let (mut generator, _server_receiver) = generator_with_key(key_pair::random());
+        generator.use_known_receivers(true);
+        for nonce in 0..10 {
+            let tx = generator.create_valid_transaction(nonce).unwrap();
+            assert_eq!(tx.transaction.receiver, ico_address);
+        }
+    }
+
+    #[test]
+    fn receivers_fall_back_to_random_addresses() {
+        // The ICO account is the only one in state and never sends to itself
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let ico_address = Address::from_public_key_bytes(ico_key.public_key().as_ref());
+        let (mut generator, _server_receiver) = generator_with_key(ico_key);
+        generator.use_known_receivers(true);
+        let first = generator.create_valid_transaction(0).unwrap().transaction.receiver;
+        let second = generator.create_valid_transaction(1).unwrap().transaction.receiver;
+        assert_ne!(first, ico_address);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,122 @@
+use log::{debug, info};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::metrics::{TimedLock, MEMPOOL_LOCK};
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::types::transaction::Mempool;
+
+/// Tunables for re-announcing unconfirmed transactions
+#[derive(Clone, Copy, Debug)]
+pub struct RebroadcastConfig {
+    /// Time between rounds; zero disables rebroadcasting
+    pub interval: Duration,
+    /// Most transaction hashes announced per round
+    pub max_per_round: usize,
+}
+
+impl Default for RebroadcastConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            max_per_round: 100,
+        }
+    }
+}
+
+/// Periodically re-announces a sample of mempool transactions, giving ones whose
+/// gossip was lost another chance to reach a miner
+pub struct Rebroadcaster {
+    mempool: Arc<Mutex<Mempool>>,
+    server: ServerHandle,
+    config: RebroadcastConfig,
+}
+
+impl Rebroadcaster {
+    pub fn new(mempool: &Arc<Mutex<Mempool>>, server: &ServerHandle, config: RebroadcastConfig) -> Self {
+        Self {
+            mempool: Arc::clone(mempool),
+            server: server.clone(),
+            config,
+        }
+    }
+
+    pub fn start(self) {
+        if self.config.interval.is_zero() {
+            info!("Transaction rebroadcast disabled");
+            return;
+        }
+        thread::Builder::new()
+            .name("tx-rebroadcast".to_string())
+            .spawn(move || loop {
+                thread::sleep(self.config.interval);
+                self.rebroadcast();
+            })
+            .unwrap();
+        info!("Transaction rebroadcast started");
+    }
+
+    fn rebroadcast(&self) {
+        let hashes = self.mempool.lock_timed(&MEMPOOL_LOCK).sample_hashes(self.config.max_per_round);
+        if !hashes.is_empty() {
+            debug!("Rebroadcasting {} unconfirmed transaction(s)", hashes.len());
+            self.server.broadcast(Message::NewTransactionHashes(hashes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use ntest::timeout;
+
+    use super::{RebroadcastConfig, Rebroadcaster};
+    use crate::network::message::Message;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, Mempool, SignedTransaction};
+
+    fn random_signed_tx() -> SignedTransaction {
+        let key = key_pair::random();
+        let transaction = generate_random_transaction();
+        SignedTransaction::create_with_fee(transaction.receiver, transaction.value, transaction.nonce, transaction.fee, &key)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn unmined_transaction_is_rebroadcast() {
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let tx = random_signed_tx();
+        mempool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let config = RebroadcastConfig { interval: Duration::from_millis(100), max_per_round: 10 };
+
+        let started = Instant::now();
+        Rebroadcaster::new(&mempool, &server, config).start();
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(v)) => assert_eq!(v, vec![tx.hash()]),
+            _ => panic!(),
+        }
+        assert!(started.elapsed() >= config.interval);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn rebroadcast_is_capped_per_round() {
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        for _ in 0..5 {
+            mempool.lock().unwrap().add_transaction(random_signed_tx()).unwrap();
+        }
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let config = RebroadcastConfig { interval: Duration::from_millis(10), max_per_round: 2 };
+
+        Rebroadcaster::new(&mempool, &server, config).start();
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(v)) => assert_eq!(v.len(), 2),
+            _ => panic!(),
+        }
+    }
+}
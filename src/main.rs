@@ -8,23 +8,24 @@ pub mod types;
 pub mod miner;
 pub mod network;
 pub mod generator;
+pub mod metrics;
 
 use blockchain::Blockchain;
 use clap::clap_app;
 use ring::signature::Ed25519KeyPair;
 use ring::signature::KeyPair;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use types::key_pair;
 use types::state::State;
 use std::collections::HashMap;
 use std::net;
 use std::process;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
-use types::transaction::Mempool;
+use types::transaction::{EvictionPolicy, Mempool};
 use ring::digest;
 
 fn main() {
@@ -34,9 +35,42 @@ fn main() {
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg api_addr: --api ... [ADDR] default_value("127.0.0.1:7000") "Sets the IP addresses and the ports of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg network_id: --("network-id") [INT] default_value("0") "Sets the network id; nodes only peer with others on the same id, and each id has its own genesis")
+     (@arg target_block_ms: --("target-block-ms") [INT] default_value("0") "Sets the average block interval difficulty retargeting aims for (0 keeps the genesis difficulty)")
+     (@arg block_reward: --("block-reward") [INT] default_value("50") "Sets the most a mined block's coinbase may pay its miner")
+     (@arg ico_balance: --("ico-balance") [INT] "Sets the balance of the ICO account in the genesis state")
+     (@arg genesis_ico: --("genesis-ico") ... [ALLOCATION] number_of_values(1) "Funds another account at genesis, as ADDR:BALANCE with a 40-hex-digit address; every node of a network must pass the same list")
+     (@arg difficulty: --difficulty [HEX] "Sets the genesis difficulty target as 64 hex digits; blocks must hash at or below it")
+     (@arg debug_api: --("debug-api") "Serves debug endpoints that override consensus, such as /debug/set-tip")
+     (@arg datadir: --datadir [DIR] "Sets the directory the blockchain is loaded from and saved to after each mined block")
+     (@arg compress_datadir: --("compress-datadir") "Compresses the blockchain when it is saved to the data directory")
+     (@arg verify_on_load: --("verify-on-load") "Verifies the loaded blockchain and refuses to start if it is invalid")
+     (@arg finality_depth: --("finality-depth") [INT] default_value("100") "Sets how many blocks below the tip a block becomes final (0 disables)")
+     (@arg state_cache: --("state-cache") [INT] default_value("1024") "Sets how many block states are kept in memory; older ones are recomputed on demand")
+     (@arg max_per_sender: --("max-per-sender") [INT] default_value("0") "Sets how many transactions one sender may have in the mempool (0 means no limit)")
+     (@arg mempool_ttl_ms: --("mempool-ttl-ms") [INT] default_value("3600000") "Sets how long a transaction may wait in the mempool before it is dropped (0 keeps it until mined)")
+     (@arg mempool_evict: --("mempool-evict-lowest-fee") "Makes a full mempool drop its lowest-fee transaction for a new one paying more, instead of refusing the new one")
+     (@arg max_tx_data: --("max-tx-data") [BYTES] default_value("256") "Sets the largest transaction data field the mempool accepts")
+     (@arg skip_sig_verify: --("skip-sig-verify") "UNSAFE, for benchmarks only: accepts transactions and blocks without checking signatures; requires a loopback P2P address and no --connect peers")
+     (@arg seen_tx_cache: --("seen-tx-cache") [INT] default_value("10000") "Sets how many confirmed transactions are kept so peers can still fetch them (0 disables)")
+     (@arg refresh_ms: --("refresh-ms") [INT] default_value("0") "Sets how often the miner re-polls the mempool while searching for a nonce (0 never refreshes mid-block)")
+     (@arg finished_block_capacity: --("finished-block-capacity") [INT] default_value("16") "Sets how many mined blocks may wait for insertion before the oldest is dropped")
+     (@arg max_block_txs: --("max-block-txs") [INT] default_value("1000") "Sets the most mempool transactions the miner puts into one block")
+     (@arg suppress_empty_blocks: --("suppress-empty-blocks") "Pauses the miner while the mempool has nothing to mine")
+     (@arg empty_block_delay_ms: --("empty-block-delay-ms") [INT] default_value("1000") "Sets how long a paused miner waits before checking the mempool again")
+     (@arg min_peers_to_mine: --("min-peers-to-mine") [INT] default_value("0") "Sets how many peers must be connected before the miner produces blocks")
+     (@arg inventory_interval_ms: --("inventory-interval-ms") [INT] default_value("100") "Sets the shortest time between transaction announcements to one peer (0 sends immediately)")
+     (@arg inventory_max_hashes: --("inventory-max-hashes") [INT] default_value("500") "Sets the most transaction hashes announced to one peer at a time")
+     (@arg tx_known_receivers: --("tx-known-receivers") "Makes the transaction generator send to accounts already in the tip state when there are any")
+     (@arg rebroadcast_ms: --("rebroadcast-ms") [INT] default_value("30000") "Sets how often unconfirmed transactions are announced again (0 disables)")
+     (@arg rebroadcast_max: --("rebroadcast-max") [INT] default_value("100") "Sets the most transactions announced again per round")
+     (@arg orphan_timeout: --("orphan-timeout-ms") [INT] default_value("5000") "Sets how long to wait for an orphan's parent before requesting it again")
+     (@arg orphan_max_age: --("orphan-max-age-ms") [INT] default_value("120000") "Sets how long an orphan may wait for its parent before it is dropped")
+     (@arg max_orphans: --("max-orphans") [INT] default_value("1024") "Sets how many orphan blocks may be buffered while their ancestors are fetched")
+     (@arg max_peer_messages: --("max-peer-msgs-per-sec") [INT] default_value("1000") "Sets how many messages per second are handled from one peer before the rest are dropped")
     )
     .get_matches();
 
@@ -69,28 +103,210 @@ fn main() {
     //info!("Key pair for node {}: {:?}", p2p_addr, key_pair.public_key().as_ref());
 
     // Initialize the blockchain and state
-    let blockchain = Blockchain::new(&seed);
-    let blockchain = Arc::new(Mutex::new(blockchain));
+    let ico_balance = match matches.value_of("ico_balance") {
+        Some(v) => v.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing ICO balance: {}", e);
+            process::exit(1);
+        }),
+        None => types::state::DEFAULT_ICO_BALANCE,
+    };
+    let network_id = matches
+        .value_of("network_id")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing network id: {}", e);
+            process::exit(1);
+        });
+    let target_block_interval_ms = matches
+        .value_of("target_block_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing target block interval: {}", e);
+            process::exit(1);
+        });
+    let block_reward = matches
+        .value_of("block_reward")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block reward: {}", e);
+            process::exit(1);
+        });
+    let consensus_difficulty = match matches.value_of("difficulty") {
+        Some(v) => v.parse::<types::hash::H256>().unwrap_or_else(|e| {
+            error!("Error parsing difficulty: {}", e);
+            process::exit(1);
+        }),
+        None => blockchain::NodeConfig::default().consensus_difficulty,
+    };
+    let node_config = blockchain::NodeConfig {
+        ico_balance,
+        consensus_difficulty,
+        network_id,
+        target_block_interval_ms,
+        block_reward,
+    };
+    let mut blockchain = match matches.values_of("genesis_ico") {
+        Some(entries) => {
+            // The node's own ICO account stays funded, so the transaction generator can still spend
+            let ico_address = types::address::Address::from_public_key_bytes(key_pair.public_key().as_ref());
+            let mut allocations = vec![(ico_address, ico_balance)];
+            for entry in entries {
+                let allocation = entry
+                    .split_once(':')
+                    .ok_or_else(|| "expected ADDR:BALANCE".to_string())
+                    .and_then(|(address, balance)| {
+                        Ok((address.parse()?, balance.parse::<u64>().map_err(|e| e.to_string())?))
+                    });
+                allocations.push(allocation.unwrap_or_else(|e| {
+                    error!("Error parsing genesis ICO allocation {}: {}", entry, e);
+                    process::exit(1);
+                }));
+            }
+            let genesis = blockchain::GenesisConfig {
+                difficulty: consensus_difficulty,
+                ico_allocations: Some(allocations),
+                ..Default::default()
+            };
+            Blockchain::with_config_and_genesis(&node_config, genesis)
+        }
+        None => Blockchain::with_config(&seed, &node_config),
+    };
+    let finality_depth = matches
+        .value_of("finality_depth")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing finality depth: {}", e);
+            process::exit(1);
+        });
+    blockchain.set_finality_depth(finality_depth);
+    let state_cache = matches
+        .value_of("state_cache")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing state cache size: {}", e);
+            process::exit(1);
+        });
+    blockchain.set_state_cache_capacity(state_cache);
+    blockchain.set_compress_on_save(matches.is_present("compress_datadir"));
+    let chain_file = matches.value_of("datadir").map(|datadir| std::path::Path::new(datadir).join("blockchain.bin"));
+    if let Some(chain_file) = &chain_file {
+        if chain_file.exists() {
+            if let Err(e) = blockchain.load_from_file(chain_file) {
+                error!("Error loading blockchain from {}: {}", chain_file.display(), e);
+                process::exit(1);
+            }
+            info!("Loaded blockchain with tip {} from {}", blockchain.tip(), chain_file.display());
+            if matches.is_present("verify_on_load") {
+                if let Err(e) = blockchain.verify_integrity() {
+                    error!("Loaded blockchain failed verification: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    let blockchain = Arc::new(RwLock::new(blockchain));
 
     // Initialize the mempool
-    let mempool = Mempool::new(1000); // Set max transactions
-    let mempool = Arc::new(Mutex::new(mempool));
-
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
+    let mut mempool = Mempool::new(1000); // Set max transactions
+    let seen_tx_cache = matches
+        .value_of("seen_tx_cache")
         .unwrap()
-        .parse::<net::SocketAddr>()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing seen transaction cache size: {}", e);
+            process::exit(1);
+        });
+    mempool.set_seen_capacity(seen_tx_cache);
+    let max_per_sender = matches
+        .value_of("max_per_sender")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing per-sender mempool limit: {}", e);
+            process::exit(1);
+        });
+    mempool.set_max_per_sender(max_per_sender);
+    let max_tx_data = matches
+        .value_of("max_tx_data")
+        .unwrap()
+        .parse::<usize>()
         .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+            error!("Error parsing transaction data limit: {}", e);
             process::exit(1);
         });
+    mempool.set_max_data_len(max_tx_data);
+    if matches.is_present("mempool_evict") {
+        mempool.set_eviction_policy(EvictionPolicy::EvictLowestFee);
+    }
+    if matches.is_present("skip_sig_verify") {
+        // Forged transactions would be relayed to and rejected by honest peers
+        if matches.is_present("known_peer") || !p2p_addr.ip().is_loopback() {
+            error!("--skip-sig-verify is for local benchmarks and cannot be used with a public P2P address or --connect peers");
+            process::exit(1);
+        }
+        warn!("Signature verification is disabled; this node must not be used on a real network");
+        mempool.set_verify_signatures(false);
+        blockchain.write().unwrap().set_verify_signatures(false);
+    }
+    // Check admissions against the tip the node starts from
+    {
+        let blockchain = blockchain.read().unwrap();
+        if let Some(tip_state) = blockchain.get_state(&blockchain.tip()) {
+            mempool.update_with_state(&tip_state);
+        }
+    }
+    let mempool = Arc::new(Mutex::new(mempool));
+
+    // parse api server addresses
+    let api_addrs: Vec<net::SocketAddr> = matches
+        .values_of("api_addr")
+        .unwrap()
+        .map(|addr| {
+            addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                error!("Error parsing API server address: {}", e);
+                process::exit(1);
+            })
+        })
+        .collect();
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let inventory_interval_ms = matches
+        .value_of("inventory_interval_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing inventory interval: {}", e);
+            process::exit(1);
+        });
+    let inventory_max_hashes = matches
+        .value_of("inventory_max_hashes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing inventory max hashes: {}", e);
+            process::exit(1);
+        });
+    let inventory_config = network::inventory::InventoryConfig {
+        interval: time::Duration::from_millis(inventory_interval_ms),
+        max_hashes: inventory_max_hashes,
+    };
+    let (mut server_ctx, server) = network::server::new(p2p_addr, msg_tx, inventory_config, network_id).unwrap();
+    // Peers we connect out to are remembered across restarts and redialed when they drop
+    if let Some(datadir) = matches.value_of("datadir") {
+        if let Err(e) = std::fs::create_dir_all(datadir) {
+            error!("Error creating data directory {}: {}", datadir, e);
+            process::exit(1);
+        }
+        server_ctx.persist_peers_to(std::path::Path::new(datadir).join("peers.txt"));
+    }
     server_ctx.start().unwrap();
 
     // start the worker
@@ -102,28 +318,171 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let orphan_timeout = matches
+        .value_of("orphan_timeout")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing orphan timeout: {}", e);
+            process::exit(1);
+        });
+    // The orphan sweeper wakes every half timeout, so 0 would have it spin
+    if orphan_timeout == 0 {
+        error!("--orphan-timeout-ms must be above 0");
+        process::exit(1);
+    }
+    let orphan_max_age = matches
+        .value_of("orphan_max_age")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing orphan max age: {}", e);
+            process::exit(1);
+        });
+    let max_orphans = matches
+        .value_of("max_orphans")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max orphans: {}", e);
+            process::exit(1);
+        });
+    let max_peer_messages_per_sec = matches
+        .value_of("max_peer_messages")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max peer messages per second: {}", e);
+            process::exit(1);
+        });
+    let worker_config = network::worker::WorkerConfig {
+        orphan_retry_timeout: time::Duration::from_millis(orphan_timeout),
+        orphan_max_age: time::Duration::from_millis(orphan_max_age),
+        max_orphans,
+        max_peer_messages_per_sec,
+    };
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain, // Pass the shared blockchain to the network worker
         &mempool, // Pass the shared mempool to the network server
+        worker_config,
     );
+    let orphan_counter = worker_ctx.orphan_counter();
     worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool, 10); // Assuming 10 as max transactions per block
+    let refresh_ms = matches
+        .value_of("refresh_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner refresh interval: {}", e);
+            process::exit(1);
+        });
+    let finished_block_capacity = matches
+        .value_of("finished_block_capacity")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing finished block capacity: {}", e);
+            process::exit(1);
+        });
+    let min_peers = matches
+        .value_of("min_peers_to_mine")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing minimum peers to mine: {}", e);
+            process::exit(1);
+        });
+    let empty_block_delay_ms = matches
+        .value_of("empty_block_delay_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing empty block delay: {}", e);
+            process::exit(1);
+        });
+    let max_block_transactions = matches
+        .value_of("max_block_txs")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block transaction limit: {}", e);
+            process::exit(1);
+        });
+    // One more transaction, the coinbase, goes into every mined block
+    if max_block_transactions >= blockchain::MAX_TX_PER_BLOCK {
+        error!("--max-block-txs must be below {}, the most transactions a block may carry", blockchain::MAX_TX_PER_BLOCK);
+        process::exit(1);
+    }
+    let miner_config = miner::MinerConfig {
+        refresh_interval: time::Duration::from_millis(refresh_ms),
+        finished_block_capacity,
+        min_peers,
+        suppress_empty_blocks: matches.is_present("suppress_empty_blocks"),
+        empty_block_delay: time::Duration::from_millis(empty_block_delay_ms),
+        max_block_transactions,
+    };
+    // Block rewards go to the node's own key
+    let miner_address = types::address::Address::from_public_key_bytes(key_pair.public_key().as_ref());
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &server, miner_config, miner_address);
+    let mut miner_worker_ctx = miner::worker::Worker::new(&server, &miner, finished_block_chan, &blockchain, &mempool, 10); // Assuming 10 as max transactions per block
+    if let Some(chain_file) = chain_file {
+        if let Err(e) = std::fs::create_dir_all(chain_file.parent().unwrap()) {
+            error!("Error creating data directory {}: {}", chain_file.parent().unwrap().display(), e);
+            process::exit(1);
+        }
+        miner_worker_ctx.save_chain_to(chain_file);
+    }
+    let mempool_ttl_ms = matches
+        .value_of("mempool_ttl_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool TTL: {}", e);
+            process::exit(1);
+        });
+    if mempool_ttl_ms > 0 {
+        miner_worker_ctx.expire_mempool_after(time::Duration::from_millis(mempool_ttl_ms));
+    }
     miner_ctx.start();
     miner_worker_ctx.start();
 
     // Initialize the transaction generator with mempool and start it
-    let transaction_generator = generator::generator::TransactionGenerator::new(mempool.clone(), server.clone(), key_pair.clone(),);
+    let mut transaction_generator = generator::generator::TransactionGenerator::new(mempool.clone(), server.clone(), key_pair.clone(), blockchain.clone(),);
+    transaction_generator.use_known_receivers(matches.is_present("tx_known_receivers"));
+
+    // Periodically re-announce transactions that have not been mined yet
+    let rebroadcast_ms = matches
+        .value_of("rebroadcast_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing rebroadcast interval: {}", e);
+            process::exit(1);
+        });
+    let rebroadcast_max = matches
+        .value_of("rebroadcast_max")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing rebroadcast limit: {}", e);
+            process::exit(1);
+        });
+    let rebroadcast_config = generator::rebroadcast::RebroadcastConfig {
+        interval: time::Duration::from_millis(rebroadcast_ms),
+        max_per_round: rebroadcast_max,
+    };
+    generator::rebroadcast::Rebroadcaster::new(&mempool, &server, rebroadcast_config).start();
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let blockchain = Arc::clone(&blockchain);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -135,8 +494,10 @@ fn main() {
                         }
                     };
                     match server.connect(addr) {
-                        Ok(_) => {
+                        Ok(mut peer) => {
                             info!("Connected to outgoing peer {}", &addr);
+                            // Catch up on the peer's chain headers first
+                            network::worker::request_headers(&blockchain, &mut peer);
                             break;
                         }
                         Err(e) => {
@@ -156,11 +517,14 @@ fn main() {
 
     // start the API server
     ApiServer::start(
-        api_addr,
+        &api_addrs,
         &miner,
         &server,
         &blockchain,
         &transaction_generator, // Pass the transaction generator
+        &mempool,
+        &orphan_counter,
+        api::ApiConfig { debug_api: matches.is_present("debug_api") },
     );
 
     loop {
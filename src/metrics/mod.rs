@@ -0,0 +1,217 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in microseconds) of the wait-time histogram buckets; the last bucket is unbounded
+const BUCKET_BOUNDS_US: [u64; 7] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+const NUM_BUCKETS: usize = BUCKET_BOUNDS_US.len() + 1;
+
+/// Wait-time statistics for one shared lock. Everything is atomic so recording never blocks.
+pub struct LockStats {
+    name: &'static str,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+    buckets: [AtomicU64; NUM_BUCKETS],
+}
+
+pub static BLOCKCHAIN_LOCK: LockStats = LockStats::new("blockchain");
+pub static MEMPOOL_LOCK: LockStats = LockStats::new("mempool");
+
+/// All instrumented locks, in the order they are reported
+pub fn all_lock_stats() -> [&'static LockStats; 2] {
+    [&BLOCKCHAIN_LOCK, &MEMPOOL_LOCK]
+}
+
+#[derive(Serialize, Debug)]
+pub struct LockReport {
+    pub name: String,
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub avg_wait_us: u64,
+    pub p50_wait_us: u64,
+    pub p99_wait_us: u64,
+    pub max_wait_us: u64,
+}
+
+impl LockStats {
+    pub const fn new(name: &'static str) -> Self {
+        // AtomicU64 is not Copy, so the array has to be spelled out
+        Self {
+            name,
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            total_wait_us: AtomicU64::new(0),
+            max_wait_us: AtomicU64::new(0),
+            buckets: [
+                AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+            ],
+        }
+    }
+
+    fn record_uncontended(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.buckets[0].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_wait(&self, wait: Duration) {
+        let wait_us = wait.as_micros() as u64;
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_us.fetch_add(wait_us, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(wait_us, Ordering::Relaxed);
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|bound| wait_us < *bound)
+            .unwrap_or(NUM_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Estimate a percentile of the wait time as the upper bound of the bucket it falls in
+    fn percentile_us(&self, counts: &[u64; NUM_BUCKETS], total: u64, percentile: u64) -> u64 {
+        if total == 0 {
+            return 0;
+        }
+        let rank = (total * percentile).div_ceil(100);
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match BUCKET_BOUNDS_US.get(i) {
+                    Some(bound) => *bound,
+                    None => self.max_wait_us.load(Ordering::Relaxed),
+                };
+            }
+        }
+        self.max_wait_us.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> LockReport {
+        let acquisitions = self.acquisitions.load(Ordering::Relaxed);
+        let mut counts = [0u64; NUM_BUCKETS];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        let total_wait_us = self.total_wait_us.load(Ordering::Relaxed);
+        LockReport {
+            name: self.name.to_string(),
+            acquisitions,
+            contended: self.contended.load(Ordering::Relaxed),
+            avg_wait_us: total_wait_us.checked_div(acquisitions).unwrap_or(0),
+            p50_wait_us: self.percentile_us(&counts, acquisitions, 50),
+            p99_wait_us: self.percentile_us(&counts, acquisitions, 99),
+            max_wait_us: self.max_wait_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Lock a mutex while recording how long we waited for it
+pub trait TimedLock<T> {
+    fn lock_timed(&self, stats: &LockStats) -> MutexGuard<'_, T>;
+}
+
+impl<T> TimedLock<T> for Mutex<T> {
+    fn lock_timed(&self, stats: &LockStats) -> MutexGuard<'_, T> {
+        // Fast path: an uncontended lock costs one try_lock and a couple of atomic adds
+        match self.try_lock() {
+            Ok(guard) => {
+                stats.record_uncontended();
+                guard
+            }
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let guard = self.lock().unwrap();
+                stats.record_wait(start.elapsed());
+                guard
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{} lock poisoned: {}", stats.name, e),
+        }
+    }
+}
+
+/// Read- or write-lock an `RwLock` while recording how long we waited for it
+pub trait TimedRwLock<T> {
+    fn read_timed(&self, stats: &LockStats) -> RwLockReadGuard<'_, T>;
+    fn write_timed(&self, stats: &LockStats) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> TimedRwLock<T> for RwLock<T> {
+    fn read_timed(&self, stats: &LockStats) -> RwLockReadGuard<'_, T> {
+        match self.try_read() {
+            Ok(guard) => {
+                stats.record_uncontended();
+                guard
+            }
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let guard = self.read().unwrap();
+                stats.record_wait(start.elapsed());
+                guard
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{} lock poisoned: {}", stats.name, e),
+        }
+    }
+
+    fn write_timed(&self, stats: &LockStats) -> RwLockWriteGuard<'_, T> {
+        match self.try_write() {
+            Ok(guard) => {
+                stats.record_uncontended();
+                guard
+            }
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let guard = self.write().unwrap();
+                stats.record_wait(start.elapsed());
+                guard
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{} lock poisoned: {}", stats.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn uncontended_lock_records_no_wait() {
+        let stats = LockStats::new("test");
+        let mutex = Mutex::new(0);
+        for _ in 0..10 {
+            *mutex.lock_timed(&stats) += 1;
+        }
+        let report = stats.report();
+        assert_eq!(report.acquisitions, 10);
+        assert_eq!(report.contended, 0);
+        assert_eq!(report.max_wait_us, 0);
+        assert_eq!(report.p99_wait_us, 1);
+    }
+
+    #[test]
+    fn contended_lock_records_wait() {
+        let stats = LockStats::new("test");
+        let mutex = Arc::new(Mutex::new(0));
+        let held = Arc::clone(&mutex);
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let holder = thread::spawn(move || {
+            let guard = held.lock().unwrap();
+            ready_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        ready_rx.recv().unwrap();
+        *mutex.lock_timed(&stats) += 1;
+        holder.join().unwrap();
+
+        let report = stats.report();
+        assert_eq!(report.acquisitions, 1);
+        assert_eq!(report.contended, 1);
+        assert!(report.max_wait_us >= 10_000);
+        assert!(report.p99_wait_us >= 10_000);
+    }
+}
@@ -1,43 +1,95 @@
 pub mod worker;
 
-use log::info;
+use log::{debug, info, warn};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError, TrySendError};
 use rand::Rng;
-use std::time;
+use std::time::{self, Duration, Instant};
 
 use std::thread;
 
 use crate::blockchain;
 use crate::types::block::{Block, Header, Content};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, MAX_TX_PER_BLOCK};
+use crate::types::address::Address;
 use crate::types::hash::{Hashable, H256};
+use std::collections::HashMap;
 use crate::types::merkle;
 use crate::types::merkle::MerkleTree;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use crate::types::transaction::Mempool;
 use crate::types::transaction::SignedTransaction;
 use crate::types::state;
+use crate::metrics::{TimedLock, TimedRwLock, BLOCKCHAIN_LOCK, MEMPOOL_LOCK};
+use crate::network::server::Handle as ServerHandle;
 
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
+    Start(u64, Option<u64>), // the lambda of interval between block generation, and optionally how many blocks to mine before pausing
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
+    Resume,
     Exit,
 }
 
 enum OperatingState {
     Paused,
-    Run(u64),
+    Run(u64, Option<u64>), // lambda, and blocks left to publish before pausing (None mines until told otherwise)
     ShutDown,
 }
 
+/// Tunables for the miner
+#[derive(Clone, Copy, Debug)]
+pub struct MinerConfig {
+    /// How often to re-poll the mempool while searching for a nonce; zero never refreshes mid-block
+    pub refresh_interval: Duration,
+    /// How many mined blocks may wait for the miner worker before the oldest is dropped
+    pub finished_block_capacity: usize,
+    /// Connected peers required before blocks are produced, so a lone node does not fork off
+    pub min_peers: usize,
+    /// Stop polling the mempool while it has nothing to mine, until an Update or the delay
+    pub suppress_empty_blocks: bool,
+    /// How long an idle miner waits before looking at the mempool again
+    pub empty_block_delay: Duration,
+    /// Most mempool transactions put into one block
+    pub max_block_transactions: usize,
+}
+
+/// Transactions per block when no limit is configured, also used for API-built blocks
+pub const DEFAULT_MAX_BLOCK_TRANSACTIONS: usize = 1000;
+
+impl Default for MinerConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_millis(0),
+            finished_block_capacity: 16,
+            min_peers: 0,
+            suppress_empty_blocks: false,
+            empty_block_delay: Duration::from_millis(1000),
+            max_block_transactions: DEFAULT_MAX_BLOCK_TRANSACTIONS,
+        }
+    }
+}
+
+/// Block currently being mined and when its content was last refreshed
+struct Template {
+    block: Block,
+    refreshed: Instant,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
-    blockchain: Arc<Mutex<Blockchain>>, // thread-safe blockchain access 
+    finished_block_drain: Receiver<Block>, // Lets the miner discard its oldest unconsumed block
+    blockchain: Arc<RwLock<Blockchain>>, // thread-safe blockchain access 
     mempool: Arc<Mutex<Mempool>>, // Thread-safe Mempool
+    config: MinerConfig,
+    template: Option<Template>,
+    server: ServerHandle,
+    waiting_for_peers: bool,
+    address: Address, // Credited by the coinbase of every block this miner produces
+    resume_lambda: Option<u64>, // Pace to pick up again on a resume, set once the miner has run
 }
 
 #[derive(Clone)]
@@ -46,9 +98,15 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    server: &ServerHandle,
+    config: MinerConfig,
+    address: Address,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
-    let (finished_block_sender, finished_block_receiver) = unbounded();
+    let (finished_block_sender, finished_block_receiver) = bounded(config.finished_block_capacity.max(1));
 
 
 
@@ -56,8 +114,15 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,)
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
+        finished_block_drain: finished_block_receiver.clone(),
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool) // Clone the mempool reference for shared access
+        mempool: Arc::clone(mempool), // Clone the mempool reference for shared access
+        config,
+        template: None,
+        server: server.clone(),
+        waiting_for_peers: false,
+        address,
+        resume_lambda: None,
     };
 
     let handle = Handle {
@@ -69,7 +134,7 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,)
 /* 
 #[cfg(any(test,test_utilities))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let blockchain = Arc::new(RwLock::new(Blockchain::new()));
     new(&blockchain)
 }*/
 
@@ -80,10 +145,26 @@ impl Handle {
 
     pub fn start(&self, lambda: u64) {
         self.control_chan
-            .send(ControlSignal::Start(lambda))
+            .send(ControlSignal::Start(lambda, None))
             .unwrap();
     }
 
+    /// Mine `count` blocks at the given lambda, then pause
+    pub fn start_count(&self, lambda: u64, count: u64) {
+        self.control_chan
+            .send(ControlSignal::Start(lambda, Some(count)))
+            .unwrap();
+    }
+
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// Continue mining at the last lambda, with no block limit
+    pub fn resume(&self) {
+        self.control_chan.send(ControlSignal::Resume).unwrap();
+    }
+
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
@@ -108,43 +189,13 @@ impl Context {
             match self.operating_state {
                 OperatingState::Paused => {
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
+                    self.apply_signal(signal);
                     continue;
                 }
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
-                },
+                _ => self.drain_signals(),
             }
             if let OperatingState::ShutDown = self.operating_state {
                 return;
@@ -153,24 +204,21 @@ impl Context {
             // TODO for student: actual mining, create a block
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 
-            if let OperatingState::Run(lambda) = self.operating_state {
-                // Create a block with transactions from the mempool
-                if let Some(block) = self.create_block() {
-
-                    // Proof-of-Work check
-                    if block.hash() <= block.header.difficulty {
-                        // Send mined block to channel 
-                        self.finished_block_chan
-                            .send(block.clone())
-                            .expect("Send finished block error");
-                        info!("Block succesfully mined with nonce: {}", block.header.nonce);
-
+            if let OperatingState::Run(lambda, _) = self.operating_state {
+                if !self.peers_ready() {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                if let Some(block) = self.mine_step() {
+                    if self.submit(block) {
+                        self.count_published();
                     }
-                    
+                } else if self.config.suppress_empty_blocks && self.template.is_none() {
+                    self.wait_for_content();
+                    continue;
                 }
 
-                if lambda != 0 {
-                    let interval = time::Duration::from_micros(lambda as u64);
+                if let Some(interval) = sleep_interval(lambda) {
                     thread::sleep(interval);
                 }
 
@@ -179,72 +227,321 @@ impl Context {
         }
     }
 
-    // Helper function to create a block, adding transactions from the mempool
-    fn create_block(&self) -> Option<Block> {
-        let parent_hash = {
-            let blockchain = self.blockchain.lock().unwrap();
-            blockchain.tip()
-        };
+    /// Apply every pending control signal, so the newest start wins over older ones
+    fn drain_signals(&mut self) {
+        loop {
+            match self.control_chan.try_recv() {
+                Ok(signal) => self.apply_signal(signal),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
+            }
+        }
+    }
 
-        let blockchain = self.blockchain.lock().unwrap();
+    fn apply_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+            }
+            ControlSignal::Start(i, count) => {
+                // A start while running only changes the pace; the current template is kept
+                match self.operating_state {
+                    OperatingState::Run(old, _) if old == i => {}
+                    OperatingState::Run(old, _) => info!("Miner lambda changed from {} to {}", old, i),
+                    _ => info!("Miner starting in continuous mode with lambda {}", i),
+                }
+                if let Some(count) = count {
+                    info!("Miner will pause after {} more blocks", count);
+                }
+                self.resume_lambda = Some(i);
+                self.operating_state = OperatingState::Run(i, count);
+                if count == Some(0) {
+                    self.pause();
+                }
+            }
+            ControlSignal::Update => match self.operating_state {
+                // in paused state, don't need to update
+                OperatingState::Paused => {}
+                // drop the block being searched so the next step rebuilds it on the
+                // latest tip with fresh mempool content
+                OperatingState::Run(..) => {
+                    debug!("Miner restarting its block after an update");
+                    self.template = None;
+                }
+                OperatingState::ShutDown => {}
+            },
+            ControlSignal::Pause => {
+                if let OperatingState::Run(..) = self.operating_state {
+                    self.pause();
+                }
+            }
+            ControlSignal::Resume => match (&self.operating_state, self.resume_lambda) {
+                (OperatingState::Paused, Some(lambda)) => {
+                    info!("Miner resuming with lambda {}", lambda);
+                    self.operating_state = OperatingState::Run(lambda, None);
+                }
+                (OperatingState::Paused, None) => info!("Miner has not been started; ignoring resume"),
+                _ => {}
+            },
+        }
+    }
 
-        let state = blockchain.get_state(&parent_hash).unwrap();
+    /// Stop mining until a start or resume; the block being searched is dropped
+    /// since the tip and mempool will likely have moved on by then
+    fn pause(&mut self) {
+        info!("Miner paused");
+        self.operating_state = OperatingState::Paused;
+        self.template = None;
+    }
 
-        drop(blockchain);
+    /// Count a published block against the limit set by the last start, pausing once it is reached
+    fn count_published(&mut self) {
+        if let OperatingState::Run(lambda, Some(left)) = self.operating_state {
+            let left = left.saturating_sub(1);
+            if left == 0 {
+                self.pause();
+            } else {
+                self.operating_state = OperatingState::Run(lambda, Some(left));
+            }
+        }
+    }
 
-        
-        //let difficulty = hex_literal::hex!("00001fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(); doing 2 before for one below
-        let difficulty = hex_literal::hex!("0005ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
-        let mut nonce = rand::thread_rng().gen::<u32>();
-        let timestamp = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
+    /// Nothing in the mempool can be mined, so sleep until a control signal arrives
+    /// (an Update announces new transactions) or the empty-block delay passes
+    fn wait_for_content(&mut self) {
+        match self.control_chan.recv_timeout(self.config.empty_block_delay) {
+            Ok(ControlSignal::Update) => {
+                info!("Miner resuming after new transactions");
+            }
+            Ok(signal) => self.apply_signal(signal),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("Miner control channel detached"),
+        }
+    }
 
-        let transactions = self
-            .mempool
-            .lock()
-            .unwrap()
-            .get_transactions_for_block(1000); // Assuming 200 as block transaction limit 
+    /// Whether enough peers are connected to produce blocks, logging when mining is
+    /// deferred and when it begins
+    fn peers_ready(&mut self) -> bool {
+        let peers = self.server.peer_count();
+        let ready = peers >= self.config.min_peers;
+        if !ready && !self.waiting_for_peers {
+            info!("Deferring mining until {} peers are connected ({} now)", self.config.min_peers, peers);
+        } else if ready && self.waiting_for_peers {
+            info!("{} peers connected; mining begins", peers);
+        }
+        self.waiting_for_peers = !ready;
+        ready
+    }
 
-        //info!("SIZE OF TRANS: {}", transactions.len());
-        let mut finalized_transactions: Vec<SignedTransaction> = vec![];
+    /// Publish a mined block unless the tip moved away from its parent while the nonce
+    /// was being searched for, in which case it would only become an orphan or a stale fork
+    fn submit(&mut self, block: Block) -> bool {
+        let tip = self.blockchain.read_timed(&BLOCKCHAIN_LOCK).tip();
+        if block.header.parent != tip {
+            info!("Discarding block {} mined on {}, which is no longer the tip", block.hash(), block.header.parent);
+            return false;
+        }
+        // Peers would drop it, so never announce a block that applies a transaction twice
+        if block.has_duplicate_transactions() {
+            warn!("Discarding block {}, which carries the same transaction twice", block.hash());
+            return false;
+        }
+        info!("Block succesfully mined with nonce: {}", block.header.nonce);
+        self.publish(block);
+        true
+    }
+
+    /// Hand a mined block to the miner worker. If the worker has fallen behind and the
+    /// channel is full, drop the oldest pending block rather than buffering without bound.
+    fn publish(&self, mut block: Block) {
+        loop {
+            match self.finished_block_chan.try_send(block) {
+                Ok(()) => return,
+                Err(TrySendError::Full(returned)) => {
+                    if let Ok(stale) = self.finished_block_drain.try_recv() {
+                        warn!("Miner worker is behind; dropping pending block {}", stale.hash());
+                    }
+                    block = returned;
+                }
+                Err(TrySendError::Disconnected(_)) => panic!("Send finished block error"),
+            }
+        }
+    }
 
-        for tx in &transactions {
-            if state.is_valid_transaction(&tx) {
-                finalized_transactions.push(tx.clone());
+    /// Try one nonce on the current template, rebuilding it first if the tip moved
+    /// or the refresh interval elapsed. Returns the block if it meets the difficulty.
+    fn mine_step(&mut self) -> Option<Block> {
+        let tip = self.blockchain.read_timed(&BLOCKCHAIN_LOCK).tip();
+        let refresh_due = match &self.template {
+            None => true,
+            Some(template) => {
+                template.block.header.parent != tip
+                    || (!self.config.refresh_interval.is_zero()
+                        && template.refreshed.elapsed() >= self.config.refresh_interval)
             }
+        };
+        if refresh_due {
+            self.refresh_template();
         }
 
-        //info!("SIZE OF TRANS_VALID: {}", finalized_transactions.len());
+        let template = self.template.as_mut()?;
+        if template.block.hash() <= template.block.get_difficulty() {
+            return self.template.take().map(|template| template.block);
+        }
+        template.block.header.nonce = template.block.header.nonce.wrapping_add(1);
+        None
+    }
 
-         // Check if there are transactions; return None if empty
-        if finalized_transactions.is_empty() {
-            for tx in &transactions {
-                info!("{}", tx.transaction.nonce);
-                //info!("HERE");
+    /// Rebuild the block being mined from the tip and mempool, keeping the
+    /// nonce search going if neither the parent nor the content changed
+    fn refresh_template(&mut self) {
+        let block = match self.create_block() {
+            Some(block) => block,
+            None => {
+                self.template = None;
+                return;
             }
-            info!("ERROR");
-            //info!("Current mempool size: {}", self.mempool.lock().unwrap().len());
+        };
+        match &mut self.template {
+            Some(template)
+                if template.block.header.parent == block.header.parent
+                    && template.block.header.merkle_root == block.header.merkle_root =>
+            {
+                template.refreshed = Instant::now();
+            }
+            _ => {
+                self.template = Some(Template { block, refreshed: Instant::now() });
+            }
+        }
+    }
+
+    // Helper function to create a block, adding transactions from the mempool
+    fn create_block(&self) -> Option<Block> {
+        let tip = self.blockchain.read_timed(&BLOCKCHAIN_LOCK).tip();
+        assemble_block_on(&self.blockchain, &self.mempool, tip, self.config.max_block_transactions, Some(self.address))
+    }
+}
+
+/// Pause between mining steps for a given lambda, in microseconds; zero never pauses
+fn sleep_interval(lambda: u64) -> Option<Duration> {
+    if lambda == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(lambda))
+    }
+}
+
+/// Build an unmined block on the current tip from valid mempool transactions.
+/// Returns None when there is nothing valid to include.
+pub fn assemble_block(blockchain: &RwLock<Blockchain>, mempool: &Mutex<Mempool>) -> Option<Block> {
+    let parent_hash = {
+        let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+        blockchain.tip()
+    };
+    assemble_block_on(blockchain, mempool, parent_hash, DEFAULT_MAX_BLOCK_TRANSACTIONS, None)
+}
+
+/// Assemble a block on `parent_hash` and search nonces until it meets the difficulty.
+/// Returns None when the parent is unknown or there is nothing valid to include.
+pub fn mine_on(blockchain: &RwLock<Blockchain>, mempool: &Mutex<Mempool>, parent_hash: H256) -> Option<Block> {
+    let mut block = assemble_block_on(blockchain, mempool, parent_hash, DEFAULT_MAX_BLOCK_TRANSACTIONS, None)?;
+    while block.hash() > block.get_difficulty() {
+        block.header.nonce = block.header.nonce.wrapping_add(1);
+    }
+    Some(block)
+}
+
+/// Like `assemble_block`, but on any known block rather than the tip, taking at most
+/// `max_transactions` from the mempool and, given a recipient, paying it the block reward
+fn assemble_block_on(
+    blockchain: &RwLock<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    parent_hash: H256,
+    max_transactions: usize,
+    coinbase_recipient: Option<Address>,
+) -> Option<Block> {
+    let blockchain = blockchain.read_timed(&BLOCKCHAIN_LOCK);
+
+    let mut state = blockchain.get_state(&parent_hash)?;
+    let difficulty = blockchain.compute_next_difficulty(&parent_hash)?;
+    let height = blockchain.height_of(&parent_hash)? + 1;
+    let block_reward = blockchain.block_reward();
+    let parent_timestamp = blockchain.get_block(&parent_hash).unwrap().header.timestamp;
 
-            return None;
+    drop(blockchain);
+
+    let nonce = rand::thread_rng().gen::<u32>();
+    // A block must be stamped after its parent, even if the parent's clock ran a little ahead
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+        .max(parent_timestamp + 1);
+
+    // Leave room for the coinbase under the consensus limit
+    let transactions = mempool
+        .lock_timed(&MEMPOOL_LOCK)
+        .get_transactions_for_block(max_transactions.min(MAX_TX_PER_BLOCK - 1));
+
+    //info!("SIZE OF TRANS: {}", transactions.len());
+    let mut finalized_transactions: Vec<SignedTransaction> = vec![];
+
+    // Group candidates by sender, keeping senders in the order they were first seen
+    let mut senders: Vec<Address> = vec![];
+    let mut by_sender: HashMap<Address, Vec<SignedTransaction>> = HashMap::new();
+    for tx in &transactions {
+        let sender = tx.sender_address();
+        by_sender.entry(sender).or_insert_with(|| {
+            senders.push(sender);
+            vec![]
+        }).push(tx.clone());
+    }
+
+    // Apply each sender's transactions in nonce order against the tip state and keep
+    // the longest valid prefix; anything after a gap or overdraft waits for a later block
+    for sender in senders {
+        let mut candidates = by_sender.remove(&sender).unwrap();
+        candidates.sort_by_key(|tx| tx.transaction.nonce);
+        for tx in candidates {
+            if !state.is_valid_transaction(&tx) {
+                break;
+            }
+            state.apply_transaction(&tx);
+            finalized_transactions.push(tx);
         }
-        
-        
-        //let merkle_root = H256::from([0u8; 32]); // Placeholder for merkle root
-        let merkle_root = MerkleTree::new(&finalized_transactions).root();
-
-        Some(Block {
-            header: Header {
-                parent: parent_hash,
-                nonce,
-                difficulty,
-                timestamp,
-                merkle_root,
-            },
-            content: Content { transactions: finalized_transactions },
-        })
     }
+
+    //info!("SIZE OF TRANS_VALID: {}", finalized_transactions.len());
+
+     // Check if there are transactions; return None if empty
+    if finalized_transactions.is_empty() {
+        debug!("None of {} pooled transaction(s) can be mined on {}", transactions.len(), parent_hash);
+        return None;
+    }
+    
+    
+    // The coinbase claims the block reward plus every included transaction's fee
+    if let Some(recipient) = coinbase_recipient {
+        let fees = finalized_transactions.iter().fold(0u64, |fees, tx| fees.saturating_add(tx.transaction.fee));
+        let coinbase = SignedTransaction::coinbase(recipient, block_reward.saturating_add(fees), height as u64);
+        finalized_transactions.insert(0, coinbase);
+    }
+
+    //let merkle_root = H256::from([0u8; 32]); // Placeholder for merkle root
+    let merkle_root = MerkleTree::new(&finalized_transactions).root();
+
+    Some(Block {
+        header: Header {
+            parent: parent_hash,
+            nonce,
+            difficulty,
+            timestamp,
+            merkle_root,
+            bits: None,
+        },
+        content: Content { transactions: finalized_transactions },
+    })
 }
 /* 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -334,3 +631,409 @@ fn custom_block_validity_check_test() {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use ntest::timeout;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::MinerConfig;
+    use crate::blockchain::{Blockchain, NodeConfig};
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::address::Address;
+    use crate::types::block::{generate_mined_block, generate_mined_block_with_transactions, generate_random_block};
+    use crate::types::hash::{Hashable, H256};
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::{Mempool, SignedTransaction};
+
+    fn signed_tx(key: &Ed25519KeyPair, value: u64) -> SignedTransaction {
+        signed_tx_with_nonce(key, value, 1)
+    }
+
+    fn signed_tx_with_nonce(key: &Ed25519KeyPair, value: u64, nonce: u64) -> SignedTransaction {
+        SignedTransaction::create(Address::from([9u8; 20]), value, nonce, key)
+    }
+
+    #[test]
+    fn transaction_arriving_mid_search_is_included() {
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let other_key = Ed25519KeyPair::from_seed_unchecked(&[1u8; 32]).unwrap();
+        let blockchain = Blockchain::new(&[0u8; 32]);
+        // Fund a second account so both transactions are valid against the tip
+        let genesis_hash = blockchain.tip();
+        blockchain.states
+            .lock()
+            .unwrap()
+            .get_mut(&genesis_hash)
+            .unwrap()
+            .accounts
+            .insert(Address::from_public_key_bytes(other_key.public_key().as_ref()), (0, 100));
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let config = MinerConfig { refresh_interval: Duration::from_millis(10), ..Default::default() };
+        let (mut ctx, _handle, _finished_block_chan) = super::new(&blockchain, &mempool, &server, config, Address::default());
+
+        let first = signed_tx(&ico_key, 10);
+        mempool.lock().unwrap().add_transaction(first.clone()).unwrap();
+        ctx.refresh_template();
+        let before = ctx.template.as_ref().unwrap().block.clone();
+        // The coinbase plus the one pending transaction
+        assert_eq!(before.content.transactions.len(), 2);
+
+        // A transaction arrives while the nonce search is underway
+        let late = signed_tx(&other_key, 20);
+        mempool.lock().unwrap().add_transaction(late.clone()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let block = loop {
+            if let Some(block) = ctx.mine_step() {
+                break block;
+            }
+        };
+        let included: Vec<_> = block.content.transactions.iter().map(|tx| tx.hash()).collect();
+        assert!(included.contains(&first.hash()));
+        assert!(included.contains(&late.hash()));
+        assert_eq!(block.header.merkle_root, MerkleTree::new(&block.content.transactions).root());
+        assert!(block.hash() <= block.header.difficulty);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn updates_keep_mining_on_the_newest_tip() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (ctx, handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        ctx.start();
+        handle.start(0);
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+
+        for nonce in 1..=3 {
+            let tip = blockchain.read().unwrap().tip();
+            mempool.lock().unwrap().add_transaction(signed_tx_with_nonce(&ico_key, 1, nonce)).unwrap();
+            for _ in 0..3 {
+                handle.update();
+            }
+            // A block already in flight may still sit on an older tip; wait for one on this tip
+            let block = loop {
+                let block = finished_block_chan.recv_timeout(Duration::from_secs(10)).unwrap();
+                if block.get_parent() == tip {
+                    break block;
+                }
+            };
+            assert!(blockchain.write().unwrap().insert(&block));
+            mempool.lock().unwrap().confirm_transactions(&block.content.transactions);
+        }
+        assert_eq!(blockchain.read().unwrap().all_blocks_in_longest_chain().len(), 4);
+        handle.exit();
+    }
+
+    #[test]
+    fn block_carries_at_most_the_configured_transactions() {
+        let blockchain = Blockchain::new(&[0u8; 32]);
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        // Fund five senders, each with one pending transaction, so any three make a valid block
+        let genesis_hash = blockchain.tip();
+        for seed in 1..=5u8 {
+            let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+            blockchain.states
+                .lock()
+                .unwrap()
+                .get_mut(&genesis_hash)
+                .unwrap()
+                .accounts
+                .insert(Address::from_public_key_bytes(key.public_key().as_ref()), (0, 100));
+            mempool.lock().unwrap().add_transaction(signed_tx(&key, 1)).unwrap();
+        }
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let config = MinerConfig { max_block_transactions: 3, ..Default::default() };
+        let (mut ctx, _handle, _finished_block_chan) = super::new(&blockchain, &mempool, &server, config, Address::default());
+
+        let block = loop {
+            if let Some(block) = ctx.mine_step() {
+                break block;
+            }
+        };
+        // The limit counts mempool transactions, not the coinbase in front of them
+        assert_eq!(block.content.transactions.len(), 4);
+        assert!(block.content.transactions[0].is_coinbase());
+        assert_eq!(block.header.merkle_root, MerkleTree::new(&block.content.transactions).root());
+        assert!(blockchain.write().unwrap().insert(&block));
+    }
+
+    #[test]
+    fn block_mined_on_abandoned_tip_is_not_published() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (mut ctx, _handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        mempool.lock().unwrap().add_transaction(signed_tx(&ico_key, 10)).unwrap();
+
+        let stale = loop {
+            if let Some(block) = ctx.mine_step() {
+                break block;
+            }
+        };
+        assert_eq!(stale.get_parent(), genesis_hash);
+
+        // A peer's block moves the tip before the found block is submitted
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let competing = generate_mined_block(&genesis_hash, &difficulty);
+        assert!(blockchain.write().unwrap().insert(&competing));
+        assert!(!ctx.submit(stale));
+        assert!(finished_block_chan.try_recv().is_err());
+
+        // The next round builds on the new tip and is published
+        let fresh = loop {
+            if let Some(block) = ctx.mine_step() {
+                break block;
+            }
+        };
+        assert!(ctx.submit(fresh.clone()));
+        assert_eq!(finished_block_chan.try_recv().unwrap().hash(), fresh.hash());
+        assert_eq!(fresh.get_parent(), competing.hash());
+    }
+
+    #[test]
+    fn block_repeating_a_transaction_is_not_published() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (mut ctx, _handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = signed_tx(&ico_key, 10);
+
+        let block = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![tx.clone(), tx]);
+        assert!(!ctx.submit(block));
+        assert!(finished_block_chan.try_recv().is_err());
+    }
+
+    #[test]
+    fn mined_block_uses_consensus_difficulty() {
+        let config = NodeConfig {
+            consensus_difficulty: H256::from([0x7f; 32]),
+            ..Default::default()
+        };
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_config(&[0u8; 32], &config)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        mempool.lock().unwrap().add_transaction(signed_tx(&ico_key, 10)).unwrap();
+
+        let block = super::assemble_block(&blockchain, &mempool).unwrap();
+        let genesis_difficulty = blockchain.read().unwrap().genesis().header.difficulty;
+        assert_eq!(genesis_difficulty, config.consensus_difficulty);
+        assert_eq!(block.header.difficulty, genesis_difficulty);
+    }
+
+    #[test]
+    fn mining_waits_for_peer_threshold() {
+        // Every hash meets this target, so a block is found on the first nonce once allowed
+        let config = NodeConfig { consensus_difficulty: H256::from([0xff; 32]), ..Default::default() };
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_config(&[0u8; 32], &config)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        mempool.lock().unwrap().add_transaction(signed_tx(&ico_key, 10)).unwrap();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let config = MinerConfig { min_peers: 2, ..Default::default() };
+        let (mut ctx, _handle, _finished_block_chan) = super::new(&blockchain, &mempool, &server, config, Address::default());
+
+        server.set_peer_count_for_test(1);
+        for _ in 0..10 {
+            assert!(!ctx.peers_ready());
+        }
+
+        server.set_peer_count_for_test(2);
+        assert!(ctx.peers_ready());
+        assert!(ctx.mine_step().is_some());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn idle_miner_resumes_on_update() {
+        let node_config = NodeConfig { consensus_difficulty: H256::from([0xff; 32]), ..Default::default() };
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_config(&[0u8; 32], &node_config)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        // A long delay, so only the Update can explain mining resuming promptly
+        let config = MinerConfig {
+            suppress_empty_blocks: true,
+            empty_block_delay: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let (ctx, handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, config, Address::default());
+        ctx.start();
+        handle.start(0);
+
+        assert!(finished_block_chan.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = signed_tx(&ico_key, 10);
+        mempool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        handle.update();
+        let block = finished_block_chan.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(block.content.transactions[1].hash(), tx.hash());
+        handle.exit();
+    }
+
+    #[test]
+    fn latest_start_sets_the_pace() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (mut ctx, handle, _finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+
+        handle.start(10);
+        ctx.drain_signals();
+        assert!(matches!(ctx.operating_state, super::OperatingState::Run(10, None)));
+
+        // Two starts in quick succession: the later lambda takes effect
+        handle.start(250);
+        handle.start(500);
+        ctx.drain_signals();
+        match ctx.operating_state {
+            super::OperatingState::Run(lambda, _) => {
+                assert_eq!(super::sleep_interval(lambda), Some(Duration::from_micros(500)));
+            }
+            _ => panic!("miner should still be running"),
+        }
+    }
+
+    #[test]
+    fn stalled_worker_keeps_newest_blocks() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let config = MinerConfig { finished_block_capacity: 3, ..Default::default() };
+        // Nobody reads the receiver, as if the miner worker were stuck
+        let (ctx, _handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, config, Address::default());
+
+        let blocks: Vec<_> = (0..10).map(|_| generate_random_block(&H256::default())).collect();
+        for block in &blocks {
+            ctx.publish(block.clone());
+            assert!(finished_block_chan.len() <= 3);
+        }
+
+        let pending: Vec<H256> = finished_block_chan.try_iter().map(|block| block.hash()).collect();
+        let newest: Vec<H256> = blocks[7..].iter().map(|block| block.hash()).collect();
+        assert_eq!(pending, newest);
+    }
+
+    #[test]
+    fn miner_collects_the_reward_for_each_block() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let miner_address = Address::from([5u8; 20]);
+        let (mut ctx, _handle, _finished_block_chan) =
+            super::new(&blockchain, &mempool, &server, MinerConfig::default(), miner_address);
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+
+        let num_blocks = 3;
+        for nonce in 1..=num_blocks {
+            mempool.lock().unwrap().add_transaction(signed_tx_with_nonce(&ico_key, 1, nonce)).unwrap();
+            let block = loop {
+                if let Some(block) = ctx.mine_step() {
+                    break block;
+                }
+            };
+            assert!(blockchain.write().unwrap().insert(&block));
+            mempool.lock().unwrap().confirm_transactions(&block.content.transactions);
+        }
+
+        let blockchain = blockchain.read().unwrap();
+        let rewards = blockchain.block_reward();
+        let state = blockchain.get_state(&blockchain.tip()).unwrap();
+        assert_eq!(state.accounts.get(&miner_address), Some(&(0, rewards * num_blocks)));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_pauses_after_count_and_resumes() {
+        // Every hash meets this target, so each mining step yields a block
+        let node_config = NodeConfig { consensus_difficulty: H256::from([0xff; 32]), ..Default::default() };
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_config(&[0u8; 32], &node_config)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        // Nothing is inserted, so every block re-mines this transaction on genesis
+        mempool.lock().unwrap().add_transaction(signed_tx(&ico_key, 10)).unwrap();
+        let (ctx, handle, finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        ctx.start();
+
+        handle.start_count(0, 3);
+        for _ in 0..3 {
+            finished_block_chan.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(finished_block_chan.recv_timeout(Duration::from_millis(300)).is_err());
+
+        handle.resume();
+        finished_block_chan.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.pause();
+        // Drain whatever was published before the pause landed, then expect silence
+        while finished_block_chan.recv_timeout(Duration::from_millis(300)).is_ok() {}
+        handle.exit();
+    }
+
+    #[test]
+    fn resume_before_start_stays_paused() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (mut ctx, handle, _finished_block_chan) = super::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+
+        handle.resume();
+        ctx.drain_signals();
+        assert!(matches!(ctx.operating_state, super::OperatingState::Paused));
+
+        handle.start_count(7, 2);
+        ctx.drain_signals();
+        ctx.count_published();
+        assert!(matches!(ctx.operating_state, super::OperatingState::Run(7, Some(1))));
+        ctx.count_published();
+        assert!(matches!(ctx.operating_state, super::OperatingState::Paused));
+
+        // Resuming keeps the last pace but drops the limit
+        handle.resume();
+        ctx.drain_signals();
+        assert!(matches!(ctx.operating_state, super::OperatingState::Run(7, None)));
+    }
+
+    #[test]
+    fn same_sender_transactions_stop_at_nonce_gap() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        for nonce in &[4, 2, 1] {
+            mempool.lock().unwrap().add_transaction(signed_tx_with_nonce(&key, 10, *nonce)).unwrap();
+        }
+
+        let block = super::assemble_block(&blockchain, &mempool).unwrap();
+        let nonces: Vec<u64> = block.content.transactions.iter().map(|tx| tx.transaction.nonce).collect();
+        assert_eq!(nonces, vec![1, 2]);
+        // The chain applies them in the same order, so it accepts the block
+        assert!(blockchain.write().unwrap().insert(&block));
+    }
+
+    #[test]
+    fn same_sender_transactions_stop_at_overdraft() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_ico_balance(&[0u8; 32], 25)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        for nonce in 1..=3 {
+            mempool.lock().unwrap().add_transaction(signed_tx_with_nonce(&key, 10, nonce)).unwrap();
+        }
+
+        let block = super::assemble_block(&blockchain, &mempool).unwrap();
+        let nonces: Vec<u64> = block.content.transactions.iter().map(|tx| tx.transaction.nonce).collect();
+        assert_eq!(nonces, vec![1, 2]);
+    }
+}
@@ -1,42 +1,74 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, error, info};
 use crate::types::block::{Block, Content, Header};
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use crate::blockchain::Blockchain;
 use crate::types::hash::{Hashable, H256};
 use crate::network::message::Message;
+use crate::metrics::{TimedLock, TimedRwLock, BLOCKCHAIN_LOCK, MEMPOOL_LOCK};
 use crate::types::transaction::{Mempool, SignedTransaction};
 use::std::time;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
+    miner: super::Handle, // Told to restart on the new tip after each insert
     finished_block_chan: Receiver<Block>,
-    blockchain: Arc<Mutex<Blockchain>>, // Thread-safe blockchain reference 
+    blockchain: Arc<RwLock<Blockchain>>, // Thread-safe blockchain reference 
     mempool: Arc<Mutex<Mempool>>, // Thread-safe Mempool reference
     max_transactions_per_block: usize, // Transaction limit per block
+    chain_file: Option<PathBuf>, // Where the chain is saved after each mined block, if anywhere
+    mempool_ttl: Option<time::Duration>, // How long a transaction may stay pooled, if limited
 }
 
+/// Longest wait between two sweeps for expired mempool transactions
+const MAX_EXPIRY_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
 impl Worker {
     pub fn new(
         server: &ServerHandle,
+        miner: &super::Handle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
         max_transactions_per_block: usize,
     ) -> Self {
         Self {
             server: server.clone(),
+            miner: miner.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
             max_transactions_per_block,
+            chain_file: None,
+            mempool_ttl: None,
         }
     }
 
+    /// Save the whole chain to `path` every time a mined block is inserted
+    pub fn save_chain_to(&mut self, path: PathBuf) {
+        self.chain_file = Some(path);
+    }
+
+    /// Drop mempool transactions once they have waited longer than `ttl` to be mined
+    pub fn expire_mempool_after(&mut self, ttl: time::Duration) {
+        self.mempool_ttl = Some(ttl);
+    }
+
     pub fn start(self) {
+        if let Some(ttl) = self.mempool_ttl {
+            let mempool = Arc::clone(&self.mempool);
+            thread::Builder::new()
+                .name("mempool-expiry".to_string())
+                .spawn(move || loop {
+                    thread::sleep(ttl.min(MAX_EXPIRY_INTERVAL));
+                    mempool.lock_timed(&MEMPOOL_LOCK).expire(ttl);
+                })
+                .unwrap();
+        }
         thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
@@ -50,10 +82,28 @@ impl Worker {
         loop {
             let block = self.finished_block_chan.recv().expect("Receive finished block error");
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            {
-                let mut blockchain = self.blockchain.lock().unwrap();
-                blockchain.insert(&block);
-                drop(blockchain);
+            // Blocks leaving the longest chain give their transactions back to the mempool
+            let (abandoned, adopted, tip_state) = {
+                let mut blockchain = self.blockchain.write_timed(&BLOCKCHAIN_LOCK);
+                let (abandoned, adopted) = match blockchain.try_insert(&block) {
+                    Ok(Some(reorg)) => (blockchain.block_transactions(&reorg.removed), blockchain.block_transactions(&reorg.added)),
+                    // A block that lands on a side branch leaves its transactions pooled
+                    Ok(None) if blockchain.tip() == block.hash() => (Vec::new(), block.content.transactions.clone()),
+                    Ok(None) => (Vec::new(), Vec::new()),
+                    Err(reason) => {
+                        info!("Mined block {:?} rejected: {}", block.hash(), reason);
+                        continue;
+                    }
+                };
+                (abandoned, adopted, blockchain.get_state(&blockchain.tip()))
+            };
+            self.miner.update();
+
+            if let Some(path) = &self.chain_file {
+                let blockchain = self.blockchain.read_timed(&BLOCKCHAIN_LOCK);
+                if let Err(e) = blockchain.save_to_file(path) {
+                    error!("Error saving blockchain to {}: {}", path.display(), e);
+                }
             }
 
             info!("Block inserted into blockchain with hash: {:?}", block.hash());
@@ -64,17 +114,19 @@ impl Worker {
 
             info!("Broadcasted new block hash: {:?}", new_block_hash);
 
-            // Remove transactions included in this block from the mempool
-            let mut mempool = self.mempool.lock().unwrap();
-            let tx_hashes: Vec<_> = block.content.transactions.iter().map(|tx| tx.hash()).collect();
-            mempool.remove_transactions(tx_hashes);
+            // Move transactions included in the new chain out of the mempool
+            let mut mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
+            match tip_state {
+                Some(tip_state) => mempool.reorganize(abandoned, &adopted, &tip_state),
+                None => mempool.confirm_transactions(&adopted),
+            }
             drop(mempool);
             }
     }
     /* 
     // Function to create a new block with transactions from the mempool
     fn create_blcok(&self, parent_hash: H256) -> Block {
-        let mut mempool = self.mempool.lock().unwrap();
+        let mut mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
         let transactions = mempool.get_transactions_for_block(self.max_transactions_per_block);
         drop(mempool);
 
@@ -89,6 +141,7 @@ impl Worker {
             difficulty: H256::from([0xff; 32]), // Use your actual difficulty here
             timestamp,
             merkle_root: crate::types::merkle::MerkleTree::new(&transactions).root(),
+            bits: None,
 
         };
 
@@ -97,3 +150,104 @@ impl Worker {
         Block {header, content}
     }*/
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use ntest::timeout;
+
+    use super::Worker;
+    use crate::blockchain::Blockchain;
+    use crate::miner::{self, MinerConfig};
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::address::Address;
+    use crate::types::block::{generate_mined_block, generate_mined_block_with_transactions};
+    use crate::types::hash::Hashable;
+    use crate::types::state::DEFAULT_ICO_BALANCE;
+    use crate::types::transaction::{Mempool, SignedTransaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    #[timeout(60000)]
+    fn mined_blocks_are_saved() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        let (block_sender, block_receiver) = crossbeam::channel::unbounded();
+        let path = std::env::temp_dir().join(format!("mined-chain-{}-{}", std::process::id(), rand::random::<u32>()));
+
+        let mut worker = Worker::new(&server, &miner, block_receiver, &blockchain, &mempool, 10);
+        worker.save_chain_to(path.clone());
+        worker.start();
+
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let block = generate_mined_block(&genesis_hash, &difficulty);
+        block_sender.send(block.clone()).unwrap();
+
+        // The file is written after the insert, so wait until it holds the new block
+        let mut loaded = Blockchain::new(&[0u8; 32]);
+        while loaded.tip() != block.hash() {
+            std::thread::sleep(Duration::from_millis(10));
+            loaded = Blockchain::new(&[0u8; 32]);
+            let _ = loaded.load_from_file(&path);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.all_blocks_in_longest_chain(), blockchain.read().unwrap().all_blocks_in_longest_chain());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reorg_returns_abandoned_transactions_to_the_mempool() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &server, MinerConfig::default(), Address::default());
+        let (block_sender, block_receiver) = crossbeam::channel::unbounded();
+        Worker::new(&server, &miner, block_receiver, &blockchain, &mempool, 10).start();
+
+        let ico_key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let ico_address = Address::from_public_key_bytes(ico_key.public_key().as_ref());
+        let receiver = Address::from([9u8; 20]);
+        let shared = SignedTransaction::create(receiver, 10, 1, &ico_key);
+        let abandoned = SignedTransaction::create(receiver, 20, 2, &ico_key);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+
+        // The current chain confirms both transactions; the competing fork only the first, but grows longer
+        let a1 = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![shared.clone()]);
+        let a2 = generate_mined_block_with_transactions(&a1.hash(), &difficulty, vec![abandoned.clone()]);
+        let b1 = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![shared.clone()]);
+        // b2 ties a2 on work, so it must lose the tie-break for a2 to keep the tip
+        let b2 = loop {
+            let block = generate_mined_block(&b1.hash(), &difficulty);
+            if block.hash() > a2.hash() {
+                break block;
+            }
+        };
+        let b3 = generate_mined_block(&b2.hash(), &difficulty);
+        {
+            let mut chain = blockchain.write().unwrap();
+            for block in &[&a1, &a2, &b1, &b2] {
+                assert!(chain.insert(block));
+            }
+            assert_eq!(chain.tip(), a2.hash());
+        }
+
+        // The worker inserts the block that overtakes the current chain
+        block_sender.send(b3.clone()).unwrap();
+        while !mempool.lock().unwrap().contains_transactions(&abandoned.hash()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(blockchain.read().unwrap().tip(), b3.hash());
+
+        // Only the transaction the new chain lacks is pending again, and the tip state reflects the new chain
+        let pooled: Vec<_> = mempool.lock().unwrap().get_all_transactions().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(pooled, vec![abandoned.hash()]);
+        let state = blockchain.read().unwrap().get_state(&b3.hash()).unwrap();
+        assert_eq!(state.accounts.get(&ico_address), Some(&(1, DEFAULT_ICO_BALANCE - 10)));
+        assert_eq!(state.accounts.get(&receiver), Some(&(0, 10)));
+    }
+}
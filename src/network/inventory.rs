@@ -0,0 +1,105 @@
+use crate::types::hash::H256;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Most transaction hashes queued for one peer; the oldest are dropped beyond this
+const MAX_PENDING_HASHES: usize = 10_000;
+
+/// Limits on transaction announcements sent to each peer
+#[derive(Clone, Copy, Debug)]
+pub struct InventoryConfig {
+    /// Shortest time between two announcements to the same peer; zero sends immediately
+    pub interval: Duration,
+    /// Most hashes in one announcement
+    pub max_hashes: usize,
+}
+
+impl Default for InventoryConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            max_hashes: 500,
+        }
+    }
+}
+
+/// Transaction hashes waiting to be announced to one peer
+#[derive(Default)]
+pub struct InventoryQueue {
+    pending: VecDeque<H256>, // Oldest first
+    queued: HashSet<H256>,
+    last_sent: Option<Instant>,
+}
+
+impl InventoryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue hashes for the next announcement, skipping any already waiting
+    pub fn push(&mut self, hashes: Vec<H256>) {
+        for hash in hashes {
+            if self.queued.insert(hash) {
+                self.pending.push_back(hash);
+            }
+        }
+        while self.pending.len() > MAX_PENDING_HASHES {
+            if let Some(oldest) = self.pending.pop_front() {
+                self.queued.remove(&oldest);
+            }
+        }
+    }
+
+    /// Next batch to announce, or nothing if the last one was sent less than an interval ago
+    pub fn take_due(&mut self, now: Instant, config: &InventoryConfig) -> Vec<H256> {
+        if self.pending.is_empty() {
+            return vec![];
+        }
+        if let Some(last_sent) = self.last_sent {
+            if now.saturating_duration_since(last_sent) < config.interval {
+                return vec![];
+            }
+        }
+        let count = config.max_hashes.min(self.pending.len());
+        let batch: Vec<H256> = self.pending.drain(..count).collect();
+        for hash in &batch {
+            self.queued.remove(hash);
+        }
+        self.last_sent = Some(now);
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u32) -> H256 {
+        let mut raw = [0u8; 32];
+        raw[..4].copy_from_slice(&i.to_be_bytes());
+        H256::from(raw)
+    }
+
+    #[test]
+    fn announcements_stay_under_cap() {
+        let config = InventoryConfig { interval: Duration::from_millis(100), max_hashes: 50 };
+        let mut queue = InventoryQueue::new();
+        queue.push((0..1000).map(hash).collect());
+        // Re-announcing hashes that are still queued adds nothing
+        queue.push((0..100).map(hash).collect());
+
+        // Poll far more often than the interval, as a busy flush timer might
+        let start = Instant::now();
+        let mut sent_per_interval = [0; 25];
+        let mut sent = vec![];
+        for tick in 0..250u64 {
+            let now = start + Duration::from_millis(tick * 10);
+            let batch = queue.take_due(now, &config);
+            sent_per_interval[(tick / 10) as usize] += batch.len();
+            sent.extend(batch);
+        }
+
+        assert!(sent_per_interval.iter().all(|count| *count <= config.max_hashes));
+        assert_eq!(sent, (0..1000).map(hash).collect::<Vec<_>>());
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -9,7 +9,10 @@ pub enum Message {
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    GetHeaders(Vec<H256>), // Block locator: the longest chain of the sender, newest first
+    Headers(Vec<Header>), // Longest chain after the first locator hash on it, oldest first
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Hello(u32), // First message on every connection, carrying the sender's network id
 }
@@ -1,4 +1,7 @@
+pub mod inventory;
 pub mod message;
 pub mod peer;
+pub mod peer_score;
+pub mod rate_limit;
 pub mod server;
 pub mod worker;
@@ -48,9 +48,14 @@ impl Handle {
 
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
+        Self::test_handle_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
             write_queue: s,
         },
         TestReceiver {
@@ -66,4 +71,10 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
+
+    /// The next message if one has already been written, without waiting
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let bytes = self.r.try_recv().ok()?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
 }
\ No newline at end of file
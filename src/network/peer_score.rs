@@ -0,0 +1,159 @@
+use super::peer;
+use crate::types::hash::H256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Latency assumed for peers we have never timed, so new peers still get a chance
+const DEFAULT_LATENCY: Duration = Duration::from_millis(500);
+
+/// Responsiveness of one peer to our block requests
+struct PeerRecord {
+    handle: peer::Handle,
+    pending: HashMap<H256, Instant>, // Outstanding requests and when they were sent
+    successes: u64,
+    failures: u64,
+    avg_latency: Option<Duration>, // Exponentially weighted moving average
+}
+
+impl PeerRecord {
+    fn score(&self) -> f64 {
+        // Laplace-smoothed success rate divided by latency in milliseconds
+        let success_rate = (self.successes + 1) as f64 / (self.successes + self.failures + 2) as f64;
+        let latency_ms = self.avg_latency.unwrap_or(DEFAULT_LATENCY).as_secs_f64() * 1000.0;
+        success_rate / (latency_ms + 1.0)
+    }
+}
+
+/// Per-peer request latency and success rate, used to pick who to ask for blocks
+#[derive(Default)]
+pub struct PeerScores {
+    peers: HashMap<SocketAddr, PeerRecord>,
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a peer we heard from so it can be chosen for later requests
+    pub fn observe(&mut self, handle: &peer::Handle) {
+        let record = self.peers.entry(*handle.addr()).or_insert_with(|| PeerRecord {
+            handle: handle.clone(),
+            pending: HashMap::new(),
+            successes: 0,
+            failures: 0,
+            avg_latency: None,
+        });
+        record.handle = handle.clone();
+    }
+
+    pub fn request_sent(&mut self, addr: &SocketAddr, hash: H256, now: Instant) {
+        if let Some(record) = self.peers.get_mut(addr) {
+            record.pending.entry(hash).or_insert(now);
+        }
+    }
+
+    /// Record that `addr` delivered `hash`; ignored if we never asked it for that block
+    pub fn response_received(&mut self, addr: &SocketAddr, hash: &H256, now: Instant) {
+        if let Some(record) = self.peers.get_mut(addr) {
+            if let Some(sent) = record.pending.remove(hash) {
+                let latency = now.saturating_duration_since(sent);
+                record.avg_latency = Some(match record.avg_latency {
+                    Some(avg) => (avg * 3 + latency) / 4,
+                    None => latency,
+                });
+                record.successes += 1;
+            }
+        }
+    }
+
+    /// Record that `addr` did not deliver `hash` in time
+    pub fn request_failed(&mut self, addr: &SocketAddr, hash: &H256) {
+        if let Some(record) = self.peers.get_mut(addr) {
+            if record.pending.remove(hash).is_some() {
+                record.failures += 1;
+            }
+        }
+    }
+
+    /// Record that `handle` sent something no honest peer would, such as bytes that are not a message
+    pub fn misbehaved(&mut self, handle: &peer::Handle) {
+        self.observe(handle);
+        if let Some(record) = self.peers.get_mut(handle.addr()) {
+            record.failures += 1;
+        }
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> Option<f64> {
+        self.peers.get(addr).map(|record| record.score())
+    }
+
+    /// The highest-scoring peer we know of
+    pub fn best_peer(&self) -> Option<peer::Handle> {
+        self.peers
+            .values()
+            .max_by(|a, b| a.score().partial_cmp(&b.score()).unwrap())
+            .map(|record| record.handle.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    fn test_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    #[test]
+    fn fast_peer_is_preferred() {
+        let (fast, _fast_receiver) = peer::Handle::test_handle_at(test_addr(1001));
+        let (slow, _slow_receiver) = peer::Handle::test_handle_at(test_addr(1002));
+        let mut scores = PeerScores::new();
+        scores.observe(&slow);
+        scores.observe(&fast);
+
+        let start = Instant::now();
+        for i in 0..3 {
+            let sent = start + Duration::from_millis(i * 1000);
+            let fast_hash = generate_random_hash();
+            let slow_hash = generate_random_hash();
+            scores.request_sent(fast.addr(), fast_hash, sent);
+            scores.request_sent(slow.addr(), slow_hash, sent);
+            scores.response_received(fast.addr(), &fast_hash, sent + Duration::from_millis(10));
+            scores.response_received(slow.addr(), &slow_hash, sent + Duration::from_millis(400));
+        }
+
+        assert!(scores.score(fast.addr()).unwrap() > scores.score(slow.addr()).unwrap());
+        assert_eq!(scores.best_peer().unwrap().addr(), fast.addr());
+    }
+
+    #[test]
+    fn failing_peer_loses_preference() {
+        let (flaky, _flaky_receiver) = peer::Handle::test_handle_at(test_addr(1001));
+        let (steady, _steady_receiver) = peer::Handle::test_handle_at(test_addr(1002));
+        let mut scores = PeerScores::new();
+        scores.observe(&flaky);
+        scores.observe(&steady);
+
+        let now = Instant::now();
+        for _ in 0..3 {
+            let hash = generate_random_hash();
+            scores.request_sent(flaky.addr(), hash, now);
+            scores.request_failed(flaky.addr(), &hash);
+        }
+        assert_eq!(scores.best_peer().unwrap().addr(), steady.addr());
+    }
+
+    #[test]
+    fn unsolicited_blocks_do_not_count() {
+        let (peer, _receiver) = peer::Handle::test_handle_at(test_addr(1001));
+        let mut scores = PeerScores::new();
+        scores.observe(&peer);
+        let before = scores.score(peer.addr()).unwrap();
+        scores.response_received(peer.addr(), &generate_random_hash(), Instant::now());
+        assert_eq!(scores.score(peer.addr()).unwrap(), before);
+    }
+}
@@ -0,0 +1,71 @@
+use super::peer;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Length of the window each peer's message count covers
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Peers tracked before those quiet for a whole window are forgotten
+const MAX_TRACKED_PEERS: usize = 1024;
+
+/// Messages one peer sent in its current window
+struct PeerWindow {
+    start: Instant,
+    count: u32,
+}
+
+/// Caps how many messages per second each peer may have handled, so one flooding
+/// peer cannot keep every worker thread busy
+pub struct PeerRateLimiter {
+    max_per_sec: u32,
+    peers: HashMap<SocketAddr, PeerWindow>,
+}
+
+impl PeerRateLimiter {
+    pub fn new(max_per_sec: u32) -> Self {
+        Self { max_per_sec, peers: HashMap::new() }
+    }
+
+    /// Count a message from `peer`; false once it has sent more than the limit this window
+    pub fn allow(&mut self, peer: &peer::Handle, now: Instant) -> bool {
+        if self.peers.len() >= MAX_TRACKED_PEERS && !self.peers.contains_key(peer.addr()) {
+            self.peers.retain(|_, window| now.saturating_duration_since(window.start) < WINDOW);
+        }
+        let window = self.peers.entry(*peer.addr()).or_insert(PeerWindow { start: now, count: 0 });
+        if now.saturating_duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.count = 0;
+        }
+        if window.count >= self.max_per_sec {
+            return false;
+        }
+        window.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    #[test]
+    fn each_peer_gets_its_own_allowance_per_window() {
+        let (flooder, _flooder_receiver) = peer::Handle::test_handle_at(test_addr(1001));
+        let (other, _other_receiver) = peer::Handle::test_handle_at(test_addr(1002));
+        let mut limiter = PeerRateLimiter::new(3);
+
+        let start = Instant::now();
+        let allowed: Vec<bool> = (0..5).map(|_| limiter.allow(&flooder, start)).collect();
+        assert_eq!(allowed, vec![true, true, true, false, false]);
+        assert!(limiter.allow(&other, start));
+
+        // A new window starts the count again
+        assert!(!limiter.allow(&flooder, start + Duration::from_millis(999)));
+        assert!(limiter.allow(&flooder, start + WINDOW));
+    }
+}
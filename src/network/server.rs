@@ -1,63 +1,168 @@
 use crate::types::address::Address;
 use super::peer;
 use super::message;
+use super::inventory::{InventoryConfig, InventoryQueue};
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::HashMap;
 use std::net;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// How often dropped peers are checked for a reconnection attempt
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Wait before the second attempt at a dropped peer; doubles after each failure
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Longest wait between attempts at a dropped peer
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// When to next dial a peer we connected out to, and how long to wait after that if it fails
+struct Redial {
+    next_attempt: Instant,
+    backoff: Duration,
+    dialing: bool, // An attempt is in flight, so another is not started
+}
+
+impl Redial {
+    fn now() -> Self {
+        Redial { next_attempt: Instant::now(), backoff: INITIAL_RECONNECT_BACKOFF, dialing: false }
+    }
+
+    fn failed(&mut self) {
+        self.dialing = false;
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    inventory_config: InventoryConfig,
+    network_id: u32,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let (stop_sender, stop_receiver) = smol::channel::bounded(1);
+    let peer_count = Arc::new(AtomicUsize::new(0));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        peer_count: peer_count.clone(),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
+        peer_count,
+        inventory: std::collections::HashMap::new(),
+        inventory_config,
+        network_id,
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        streams: HashMap::new(),
+        known_peers: HashMap::new(),
+        peer_file: None,
+        listener_task: None,
+        _stop: stop_sender,
+        stopped: stop_receiver,
     };
     Ok((ctx, handle))
 }
 
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peer_count: Arc<AtomicUsize>, // Mirrors `peers.len()` for handles on other threads
+    inventory: std::collections::HashMap<std::net::SocketAddr, InventoryQueue>, // Transaction hashes waiting per peer
+    inventory_config: InventoryConfig,
+    network_id: u32, // Peers must greet with the same id or they are disconnected
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    streams: HashMap<std::net::SocketAddr, AsyncArc<Async<net::TcpStream>>>, // Closed on shutdown
+    known_peers: HashMap<std::net::SocketAddr, Redial>, // Peers we connected out to, redialed when dropped
+    peer_file: Option<PathBuf>, // Where known peers are saved, if anywhere
+    listener_task: Option<smol::Task<()>>, // Cancelled on shutdown, freeing the port
+    _stop: smol::channel::Sender<()>, // Dropped with the context, which closes `stopped`
+    stopped: smol::channel::Receiver<()>,
 }
 
 impl Context {
+    /// Remember peers we connect out to in `path`, and redial the ones already listed there
+    /// once the server starts
+    pub fn persist_peers_to(&mut self, path: PathBuf) {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                    match line.parse::<std::net::SocketAddr>() {
+                        Ok(addr) => {
+                            self.known_peers.insert(addr, Redial::now());
+                        }
+                        Err(e) => warn!("Ignoring peer address {} in {}: {}", line, path.display(), e),
+                    }
+                }
+                info!("Loaded {} known peers from {}", self.known_peers.len(), path.display());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Error reading known peers from {}: {}", path.display(), e),
+        }
+        self.peer_file = Some(path);
+    }
+
     /// Start a new server context.
-    pub fn start(self) -> std::io::Result<()> {
+    pub fn start(mut self) -> std::io::Result<()> {
         // initialize the server socket
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
+        let flush_chan = self.control_sender.clone();
+        let reconnect_chan = self.control_sender.clone();
+        let flush_interval = self.inventory_config.interval;
+        let executor_stopped = self.stopped.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
+        self.listener_task = Some(ex.spawn(async move {
+            Self::listener_loop(listener, control_chan).await.unwrap();
+        }));
         ex.spawn(async move {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
         ex.spawn(async move {
-            Self::listener_loop(listener, control_chan).await.unwrap();
+            loop {
+                smol::Timer::after(RECONNECT_CHECK_INTERVAL).await;
+                if reconnect_chan.send(ControlSignal::Reconnect).await.is_err() {
+                    return;
+                }
+            }
         })
             .detach();
-        thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
+        if !flush_interval.is_zero() {
+            ex.spawn(async move {
+                loop {
+                    smol::Timer::after(flush_interval).await;
+                    if flush_chan.send(ControlSignal::FlushInventory).await.is_err() {
+                        return;
+                    }
+                }
+            })
+                .detach();
+        }
+        thread::spawn(move || {
+            smol::block_on(ex.run(async move {
+                let _ = executor_stopped.recv().await;
+            }))
+        });
         return Ok(());
     }
 
@@ -83,22 +188,101 @@ impl Context {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
                     let handle = self.connect(&addr, ex.clone()).await;
+                    if handle.is_ok() && self.known_peers.insert(addr, Redial::now()).is_none() {
+                        self.save_known_peers();
+                    }
                     result_chan.send(handle).unwrap();
                 }
+                ControlSignal::Reconnect => {
+                    let now = Instant::now();
+                    let due: Vec<std::net::SocketAddr> = self
+                        .known_peers
+                        .iter()
+                        .filter(|(addr, redial)| {
+                            !self.peers.contains_key(addr) && !redial.dialing && redial.next_attempt <= now
+                        })
+                        .map(|(addr, _)| *addr)
+                        .collect();
+                    // Dial off the control loop, so a peer that never answers does not hold
+                    // up broadcasts and accepts until its connect times out
+                    for addr in due {
+                        self.known_peers.get_mut(&addr).unwrap().dialing = true;
+                        let redialed_chan = self.control_sender.clone();
+                        ex.spawn(async move {
+                            let result = Async::<std::net::TcpStream>::connect(addr).await;
+                            let _ = redialed_chan.send(ControlSignal::Redialed(addr, result)).await;
+                        })
+                            .detach();
+                    }
+                }
+                ControlSignal::Redialed(addr, result) => {
+                    let result = match result {
+                        Ok(stream) => self.register(stream, peer::Direction::Outgoing, ex.clone()).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(_) => {
+                            info!("Reconnected to peer {}", addr);
+                            self.known_peers.insert(addr, Redial::now());
+                        }
+                        Err(e) => {
+                            if let Some(redial) = self.known_peers.get_mut(&addr) {
+                                redial.failed();
+                                debug!("Error reconnecting to peer {}, retrying in {:?}: {}", addr, redial.backoff, e);
+                            }
+                        }
+                    }
+                }
+                ControlSignal::Shutdown => {
+                    info!("P2P server at {} shutting down", self.addr);
+                    // Stop accepting, then drop connections accepted but not yet registered
+                    if let Some(listener_task) = self.listener_task.take() {
+                        listener_task.cancel().await;
+                    }
+                    while self.control_chan.try_recv().is_ok() {}
+                    for stream in self.streams.values() {
+                        let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+                    }
+                    return Ok(());
+                }
+                ControlSignal::BroadcastMessage(message::Message::NewTransactionHashes(hashes))
+                    if !self.inventory_config.interval.is_zero() =>
+                {
+                    trace!("Queueing transaction hashes for each peer");
+                    for addr in self.peers.keys() {
+                        self.inventory.entry(*addr).or_default().push(hashes.clone());
+                    }
+                }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
                     for (_, hd) in self.peers.iter_mut() {
                         hd.write(msg.clone());
                     }
                 }
+                ControlSignal::FlushInventory => {
+                    let now = std::time::Instant::now();
+                    for (addr, hd) in self.peers.iter_mut() {
+                        if let Some(queue) = self.inventory.get_mut(addr) {
+                            let batch = queue.take_due(now, &self.inventory_config);
+                            if !batch.is_empty() {
+                                hd.write(message::Message::NewTransactionHashes(batch));
+                            }
+                        }
+                    }
+                }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
                     self.accept(stream, ex.clone()).await?;
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    // Both the reader and the writer may report the same peer
+                    if self.peers.remove(&addr).is_some() {
+                        self.inventory.remove(&addr);
+                        self.streams.remove(&addr);
+                        self.peer_count.store(self.peers.len(), Ordering::Relaxed);
+                        info!("Peer {} disconnected", addr);
+                    }
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
@@ -108,6 +292,18 @@ impl Context {
         return Ok(());
     }
 
+    /// Write the known peer addresses to the peer file, one per line
+    fn save_known_peers(&self) {
+        let path = match &self.peer_file {
+            Some(path) => path,
+            None => return,
+        };
+        let contents: String = self.known_peers.keys().map(|addr| format!("{}\n", addr)).collect();
+        if let Err(e) = std::fs::write(path, contents) {
+            warn!("Error saving known peers to {}: {}", path.display(), e);
+        }
+    }
+
     /// Connect to a peer, and register this peer
     async fn connect(
         &mut self,
@@ -142,7 +338,10 @@ impl Context {
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
+        let reader_control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let network_id = self.network_id;
+        let handshake_stream = stream.clone();
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
@@ -152,6 +351,8 @@ impl Context {
             let mut size_buffer: [u8; 4] = [0; 4];
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
+            // the first message must be a Hello from the same network
+            let mut greeted = false;
             loop {
                 // first, read exactly 4 bytes to get the frame header
                 let msg_size = match reader.read_exact(&mut size_buffer).await {
@@ -170,6 +371,20 @@ impl Context {
                 {
                     Ok(_) => {
                         let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
+                        if !greeted {
+                            match bincode::deserialize(&new_payload) {
+                                Ok(message::Message::Hello(id)) if id == network_id => {
+                                    greeted = true;
+                                    continue;
+                                }
+                                Ok(message::Message::Hello(id)) => {
+                                    warn!("Peer {} is on network {}, not {}; disconnecting", addr, id, network_id);
+                                }
+                                _ => warn!("Peer {} did not start with a handshake; disconnecting", addr),
+                            }
+                            let _ = handshake_stream.get_ref().shutdown(net::Shutdown::Both);
+                            break;
+                        }
                         new_msg_chan
                             .send((new_payload, handle_copy.clone()))
                             .await
@@ -180,7 +395,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected; the writer only notices on its next write
+            let _ = reader_control_chan.send(ControlSignal::DroppedPeer(addr)).await;
         })
             .detach();
 
@@ -214,16 +430,20 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
-            control_chan
+            // the peer is disconnected; after a shutdown nobody is listening
+            let _ = control_chan
                 .send(ControlSignal::DroppedPeer(addr))
-                .await
-                .unwrap();
+                .await;
         })
             .detach();
 
+        // greet the peer; it drops us if our network id differs from its own
+        handle.clone().write(message::Message::Hello(self.network_id));
+
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        self.streams.insert(addr, stream);
+        self.peer_count.store(self.peers.len(), Ordering::Relaxed);
         Ok(handle)
     }
 }
@@ -231,6 +451,7 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    peer_count: Arc<AtomicUsize>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -259,6 +480,11 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    /// Disconnect every peer and stop listening, freeing the P2P port
+    pub fn shutdown(&self) {
+        smol::block_on(self.control_chan.send(ControlSignal::Shutdown)).unwrap();
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
@@ -267,10 +493,20 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Number of peers currently connected
+    pub fn peer_count(&self) -> usize {
+        self.peer_count.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn set_peer_count_for_test(&self, count: usize) {
+        self.peer_count.store(count, Ordering::Relaxed);
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {control_chan: s, peer_count: Arc::new(AtomicUsize::new(0))};
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -285,4 +521,86 @@ enum ControlSignal {
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
     SendToPeer((Address,message::Message)),
+    FlushInventory, // Announce queued transaction hashes to peers that are due
+    Reconnect, // Redial dropped peers whose backoff has passed
+    Redialed(std::net::SocketAddr, std::io::Result<Async<net::TcpStream>>), // Outcome of a redial
+    Shutdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use ntest::timeout;
+
+    use super::message::Message;
+    use super::InventoryConfig;
+
+    /// Start a P2P server on `addr` and return its handle and the messages it forwards to workers
+    fn start_node(addr: &str, network_id: u32) -> (super::Handle, smol::channel::Receiver<(Vec<u8>, super::peer::Handle)>) {
+        let (msg_tx, msg_rx) = smol::channel::bounded(100);
+        let (ctx, handle) = super::new(addr.parse().unwrap(), msg_tx, InventoryConfig::default(), network_id).unwrap();
+        ctx.start().unwrap();
+        (handle, msg_rx)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn peers_on_other_networks_are_refused() {
+        let (node_a, received_a) = start_node("127.0.0.1:16970", 1);
+        let (node_b, _received_b) = start_node("127.0.0.1:16971", 2);
+        let (node_c, _received_c) = start_node("127.0.0.1:16972", 1);
+
+        // A peer from another network is dropped before any of its messages are handled
+        let mut to_a = node_b.connect("127.0.0.1:16970".parse().unwrap()).unwrap();
+        to_a.write(Message::Ping("other network".to_string()));
+        while node_b.peer_count() > 0 || node_a.peer_count() > 0 {
+            to_a.write(Message::Ping("other network".to_string()));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // A peer on the same network is kept and heard
+        let mut to_a = node_c.connect("127.0.0.1:16970".parse().unwrap()).unwrap();
+        to_a.write(Message::Ping("same network".to_string()));
+        let (bytes, _) = smol::block_on(received_a.recv()).unwrap();
+        match bincode::deserialize(&bytes).unwrap() {
+            Message::Ping(nonce) => assert_eq!(nonce, "same network"),
+            _ => panic!(),
+        }
+        assert_eq!(node_a.peer_count(), 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn dropped_peer_is_redialed_and_remembered() {
+        let peer_file = std::env::temp_dir().join(format!("peers-{}-{}", std::process::id(), rand::random::<u32>()));
+        let (msg_tx, _received_a) = smol::channel::bounded(100);
+        let (mut ctx, node_a) = super::new("127.0.0.1:16973".parse().unwrap(), msg_tx, InventoryConfig::default(), 1).unwrap();
+        ctx.persist_peers_to(peer_file.clone());
+        ctx.start().unwrap();
+        let (node_b, _received_b) = start_node("127.0.0.1:16974", 1);
+
+        node_a.connect("127.0.0.1:16974".parse().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&peer_file).unwrap(), "127.0.0.1:16974\n");
+
+        // Kill B, then bring it back on the same address; A notices and redials it
+        node_b.shutdown();
+        while node_a.peer_count() > 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+        let (node_b, _received_b) = start_node("127.0.0.1:16974", 1);
+        while node_a.peer_count() == 0 || node_b.peer_count() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // A node starting from the saved file dials B without being told to
+        let (msg_tx, _received_c) = smol::channel::bounded(100);
+        let (mut ctx, node_c) = super::new("127.0.0.1:16975".parse().unwrap(), msg_tx, InventoryConfig::default(), 1).unwrap();
+        ctx.persist_peers_to(peer_file.clone());
+        ctx.start().unwrap();
+        while node_c.peer_count() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&peer_file).unwrap();
+    }
 }
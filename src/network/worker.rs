@@ -1,43 +1,185 @@
 use super::message::Message;
 use super::peer;
+use super::peer_score::PeerScores;
+use super::rate_limit::PeerRateLimiter;
 use super::server::Handle as ServerHandle;
 use crate::types::hash::H256;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, MAX_TX_PER_BLOCK};
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use log::{debug, warn, error};
 use stderrlog::new;
 
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::types::transaction::Mempool;
+use crate::metrics::{TimedLock, TimedRwLock, BLOCKCHAIN_LOCK, MEMPOOL_LOCK};
 
 #[cfg(any(test,test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test,test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
 
+/// Tunables for the network worker
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerConfig {
+    /// How long to wait for an orphan's parent before requesting it again
+    pub orphan_retry_timeout: Duration,
+    /// How long an orphan may wait for its parent before it is dropped
+    pub orphan_max_age: Duration,
+    /// Most orphan blocks buffered at once; further orphans are dropped until some connect
+    pub max_orphans: usize,
+    /// Most messages handled from one peer per second; the rest are dropped unread
+    pub max_peer_messages_per_sec: u32,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            orphan_retry_timeout: Duration::from_secs(5),
+            orphan_max_age: Duration::from_secs(120),
+            max_orphans: 1024,
+            max_peer_messages_per_sec: 1000,
+        }
+    }
+}
+
+/// Most headers sent in one `Headers` message; a full batch means there are more to ask for
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+/// Most block bodies asked for in one `GetBlocks` message during header sync
+const MAX_BODIES_PER_REQUEST: usize = 128;
+
+/// Start a header sync with `peer`: it answers with the headers of its longest chain that
+/// we lack, and the bodies are fetched once the headers check out
+pub fn request_headers(blockchain: &RwLock<Blockchain>, peer: &mut peer::Handle) {
+    let locator = blockchain.read_timed(&BLOCKCHAIN_LOCK).locator();
+    peer.write(Message::GetHeaders(locator));
+}
+
+/// Wait before restarting a panicked worker, doubled for each further panic
+const MIN_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest wait between worker restarts; a worker that lived this long starts over from the minimum
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Most recently validated block hashes remembered, so copies from other peers skip validation
+const VALIDATED_CACHE_CAPACITY: usize = 1024;
+
+/// Hashes of blocks that recently passed `validate_block`, forgetting the oldest first.
+/// A hash commits to the parent, so a block that passed once would pass again.
+#[derive(Default)]
+struct ValidatedBlocks {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+    validations: usize, // Times `validate_block` actually ran
+}
+
+impl ValidatedBlocks {
+    fn insert(&mut self, block_hash: H256) {
+        if !self.hashes.insert(block_hash) {
+            return;
+        }
+        self.order.push_back(block_hash);
+        if self.order.len() > VALIDATED_CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Blocks waiting on the same missing parent
+struct OrphanEntry {
+    blocks: Vec<Block>,
+    peer: peer::Handle, // Peer we last asked for the parent
+    first_seen: Instant,
+    last_requested: Instant,
+}
+
+/// Reads how many orphan blocks a worker is holding, from outside the worker
+#[derive(Clone, Default)]
+pub struct OrphanCounter {
+    orphan_buffer: Arc<Mutex<HashMap<H256, OrphanEntry>>>,
+}
+
+impl OrphanCounter {
+    pub fn count(&self) -> usize {
+        lock_recovering(&self.orphan_buffer).values().map(|entry| entry.blocks.len()).sum()
+    }
+}
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>, // Add blockchain for thread-safe access
-    orphan_buffer: Arc<Mutex<HashMap<H256, Vec<Block>>>>, // Orphan buffer to handle blocks with missing parents
+    blockchain: Arc<RwLock<Blockchain>>, // Add blockchain for thread-safe access
+    orphan_buffer: Arc<Mutex<HashMap<H256, OrphanEntry>>>, // Orphan buffer to handle blocks with missing parents, keyed by parent
     mempool: Arc<Mutex<Mempool>>, // Include mempool for transactions
+    peer_scores: Arc<Mutex<PeerScores>>, // Responsiveness of peers to our block requests
+    validated: Arc<Mutex<ValidatedBlocks>>, // Blocks that recently passed validation
+    rate_limiter: Arc<Mutex<PeerRateLimiter>>, // Recent message counts of each peer
+    config: WorkerConfig,
+}
+
+
+/// Check a block whose parent is known: proof of work, that the header commits to
+/// the transactions it carries, and that it carries the difficulty expected after its parent
+fn validate_block(block: &Block, expected_difficulty: &H256) -> Result<(), &'static str> {
+    if block.hash() > block.get_difficulty() {
+        return Err("failed PoW check");
+    }
+    if !block.is_valid_content() {
+        return Err("merkle root does not match content");
+    }
+    if block.has_duplicate_transactions() {
+        return Err("carries the same transaction twice");
+    }
+    if block.get_difficulty() != *expected_difficulty {
+        return Err("difficulty differs from the expected next difficulty");
+    }
+    // A coinbase has no signer, so it is the one transaction without a key or signature
+    if block.content.transactions.iter().any(|tx| !tx.is_coinbase() && tx.check_format().is_err()) {
+        return Err("transaction has a malformed public key or signature");
+    }
+    Ok(())
 }
 
+/// Describe a caught panic using the message it was raised with, if it has one
+fn panic_message(cause: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = cause.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = cause.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+/// Lock one of the worker's own bookkeeping maps. These only hold hints (orphans waiting
+/// on parents, peer scores), so a worker that panicked while holding one cannot leave
+/// anything unsafe behind and the data is kept rather than poisoning every later lock.
+fn lock_recovering<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering a lock poisoned by a panicked worker");
+        poisoned.into_inner()
+    })
+}
 
 impl Worker {
     pub fn new(
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>, // Accept mempool reference 
+        config: WorkerConfig,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -46,17 +188,62 @@ impl Worker {
             blockchain: Arc::clone(blockchain),
             orphan_buffer: Arc::new(Mutex::new(HashMap::new())), // Initialize orphan buffer
             mempool: Arc::clone(mempool), // Clone mempool reference
+            peer_scores: Arc::new(Mutex::new(PeerScores::new())),
+            validated: Arc::new(Mutex::new(ValidatedBlocks::default())),
+            rate_limiter: Arc::new(Mutex::new(PeerRateLimiter::new(config.max_peer_messages_per_sec))),
+            config,
         }
     }
 
+    pub fn orphan_counter(&self) -> OrphanCounter {
+        OrphanCounter { orphan_buffer: Arc::clone(&self.orphan_buffer) }
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+            thread::spawn(move || cloned.supervise(i, Self::worker_loop));
+        }
+
+        // Periodically re-request missing parents and expire stale orphans
+        let sweeper = self.clone();
+        thread::Builder::new()
+            .name("orphan-sweeper".to_string())
+            .spawn(move || loop {
+                thread::sleep(sweeper.config.orphan_retry_timeout / 2);
+                sweeper.sweep_orphans();
+            })
+            .unwrap();
+    }
+
+    /// Run `body` as worker thread `i`, starting it again whenever it panics
+    fn supervise(&self, i: usize, body: impl Fn(&Self)) {
+        let mut backoff = MIN_RESTART_BACKOFF;
+        loop {
+            // A panic while handling one message must not cost the node a worker,
+            // so start the loop again in place of the one that died
+            let started = Instant::now();
+            match panic::catch_unwind(AssertUnwindSafe(|| body(self))) {
+                Ok(()) => {
+                    warn!("Worker thread {} exited", i);
+                    return;
+                }
+                Err(cause) => error!("Worker thread {} panicked ({}); restarting it", i, panic_message(&*cause)),
+            }
+            // The blockchain or mempool may have been left half-updated, and every
+            // later message would panic on the poisoned lock
+            if self.blockchain.is_poisoned() || self.mempool.is_poisoned() {
+                error!("Worker thread {} panicked while holding the blockchain or mempool; exiting", i);
+                std::process::exit(1);
+            }
+            // Back off while the worker keeps dying, so a bug hit by every message
+            // does not spin the thread and flood the log
+            if started.elapsed() > MAX_RESTART_BACKOFF {
+                backoff = MIN_RESTART_BACKOFF;
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
         }
     }
 
@@ -69,7 +256,19 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if !lock_recovering(&self.rate_limiter).allow(&peer, Instant::now()) {
+                debug!("Dropping message from {}: over {} messages per second", peer.addr(), self.config.max_peer_messages_per_sec);
+                continue;
+            }
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Dropping undecodable message from {}: {}", peer.addr(), e);
+                    lock_recovering(&self.peer_scores).misbehaved(&peer);
+                    continue;
+                }
+            };
+            lock_recovering(&self.peer_scores).observe(&peer);
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -81,7 +280,7 @@ impl Worker {
 
                 // Transaction-related messages
                 Message::NewTransactionHashes(hashes) =>{
-                    let mempool = self.mempool.lock().unwrap();
+                    let mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
                     let missing_hashes: Vec<H256> = hashes
                         .into_iter()
                         .filter(|hash| !mempool.contains_transactions(hash))
@@ -94,10 +293,10 @@ impl Worker {
                 }
 
                 Message::GetTransactions(hashes) => {
-                    let mempool = self.mempool.lock().unwrap();
+                    let mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
                     let transactions_to_send: Vec<_> = hashes
                         .into_iter()
-                        .filter_map(|hash| mempool.get_transactions(&hash))
+                        .filter_map(|hash| mempool.lookup(&hash))
                         .collect();
                     drop(mempool);
 
@@ -107,37 +306,46 @@ impl Worker {
                 }
 
                 Message::Transactions(transactions) => {
-                    let mut mempool = self.mempool.lock().unwrap();
+                    let mut mempool = self.mempool.lock_timed(&MEMPOOL_LOCK);
+                    let mut new_tx_hashes = Vec::new();
                     for tx in transactions {
-                        mempool.add_transaction(tx).ok();
+                        let tx_hash = tx.hash();
+                        if mempool.add_transaction(tx).is_ok() {
+                            new_tx_hashes.push(tx_hash);
+                        }
                     }
 
                     drop(mempool);
+
+                    // Relay only what we just accepted, so known transactions are not echoed back
+                    if !new_tx_hashes.is_empty() {
+                        self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
+                    }
                 }
 
                 Message::NewBlockHashes(hashes) => {
 
-                    let blockchain = self.blockchain.lock().unwrap();
+                    let blockchain = self.blockchain.read_timed(&BLOCKCHAIN_LOCK);
 
                     // Request blocks we don't already have in blockchain
                     // Filter out hashes that are not already in the blockchain (check all blocks)
                     let missing_hashes: Vec<H256> = hashes
                         .into_iter()
-                        .filter(|hash| !blockchain.blocks.contains_key(hash))
+                        .filter(|hash| !blockchain.contains(hash))
                         .collect();
 
                     drop (blockchain);
 
                     if !missing_hashes.is_empty() {
-                        peer.write(Message::GetBlocks(missing_hashes));
+                        self.request_blocks(&mut peer, missing_hashes);
                     }
                 }
 
                 Message::GetBlocks(hashes) => {
-                    let blockchain = self.blockchain.lock().unwrap();
+                    let blockchain = self.blockchain.read_timed(&BLOCKCHAIN_LOCK);
                     let blocks_to_send: Vec<_> = hashes
                         .into_iter()
-                        .filter_map(|hash| blockchain.blocks.get(&hash).cloned())
+                        .filter_map(|hash| blockchain.get_block(&hash).cloned())
                         .collect();
                     drop(blockchain);
 
@@ -146,107 +354,297 @@ impl Worker {
                     }
                 }
 
+                Message::GetHeaders(locator) => {
+                    let headers = self
+                        .blockchain
+                        .read_timed(&BLOCKCHAIN_LOCK)
+                        .headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+
+                Message::Headers(headers) => {
+                    let batch_full = headers.len() >= MAX_HEADERS_PER_MESSAGE;
+                    let last_hash = headers.last().map(|header| header.hash());
+                    let mut blockchain = self.blockchain.write_timed(&BLOCKCHAIN_LOCK);
+                    let mut wanted = Vec::new();
+                    for header in headers {
+                        let header_hash = header.hash();
+                        match blockchain.accept_header(&header) {
+                            Ok(true) => wanted.push(header_hash),
+                            Ok(false) => {}
+                            // Later headers build on this one, so none of them can be accepted either
+                            Err(reason) => {
+                                debug!("Header {:?} rejected: {}", header_hash, reason);
+                                break;
+                            }
+                        }
+                    }
+                    // A full batch that checked out means the peer stopped at the message limit,
+                    // so continue from where it left off
+                    let continue_from = last_hash.filter(|hash| {
+                        batch_full && (blockchain.has_pending_header(hash) || blockchain.contains(hash))
+                    });
+                    drop(blockchain);
+
+                    for chunk in wanted.chunks(MAX_BODIES_PER_REQUEST) {
+                        self.request_blocks(&mut peer, chunk.to_vec());
+                    }
+                    if let Some(last_hash) = continue_from {
+                        peer.write(Message::GetHeaders(vec![last_hash]));
+                    }
+                }
+
                 Message::Blocks(blocks) => {
-                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut blockchain = self.blockchain.write_timed(&BLOCKCHAIN_LOCK);
                     let mut new_block_hashes = Vec::new();
-                    let mut mempool = self.mempool.lock().unwrap(); // Lock the mempool here for removal - ADDED
-
+                    let mut mempool = self.mempool.lock_timed(&MEMPOOL_LOCK); // Lock the mempool here for removal - ADDED
+                    let mut abandoned_txs = Vec::new();
+                    let mut adopted_txs = Vec::new();
+                    // A batch may arrive out of order, so parents later in it are not requested,
+                    // and the orphans they leave are connected in one pass once the batch is in
+                    let batch: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+                    let in_batch: HashSet<H256> = batch.iter().copied().collect();
+                    let mut buffered: usize = lock_recovering(&self.orphan_buffer).values().map(|entry| entry.blocks.len()).sum();
 
                     for block in blocks {
                         let block_hash = block.hash();
                         //debug!("Received new block with hash: {:?}", block_hash);
+                        lock_recovering(&self.peer_scores).response_received(peer.addr(), &block_hash, Instant::now());
+
+                        // A block we already have is neither inserted nor announced again
+                        if blockchain.contains(&block_hash) {
+                            continue;
+                        }
 
                         // Check PoW Validity
-                        if block_hash > block.header.difficulty {
+                        if block_hash > block.get_difficulty() {
                             debug!("Block with hash {:?} failed PoW check", block_hash);
                             continue;
                         }
 
+                        // An oversized block is not even buffered as an orphan
+                        if block.content.transactions.len() > MAX_TX_PER_BLOCK {
+                            debug!("Block with hash {:?} carries {} transactions", block_hash, block.content.transactions.len());
+                            continue;
+                        }
+
                         // Check if parent exists in blockchain 
                         let parent_hash = block.header.parent;
-                        if !blockchain.blocks.contains_key(&parent_hash) {
+                        if !blockchain.contains(&parent_hash) {
                             debug!("Parent block missing for block {:?}", block_hash);
 
-                            // Add block to orphan buffer
-                            self.orphan_buffer.lock().unwrap().entry(parent_hash)
-                                .or_insert_with(Vec::new)
-                                .push(block.clone());
+                            let mut orphan_buffer = lock_recovering(&self.orphan_buffer);
+                            let already_buffered = orphan_buffer
+                                .get(&parent_hash)
+                                .is_some_and(|entry| entry.blocks.iter().any(|b| b.hash() == block_hash));
+                            if already_buffered {
+                                continue;
+                            }
+                            // Walking a long chain back one parent at a time would buffer all of it;
+                            // stop growing the buffer and let the chain be fetched another way
+                            if buffered >= self.config.max_orphans {
+                                warn!("Orphan buffer full ({} blocks); dropping orphan {:?}", buffered, block_hash);
+                                continue;
+                            }
+
+                            // Request the missing parent from the most responsive peer, unless
+                            // header sync has already asked for its body
+                            let mut target = self.preferred_peer(&peer);
+                            if !in_batch.contains(&parent_hash) && !blockchain.has_pending_header(&parent_hash) {
+                                self.request_blocks(&mut target, vec![parent_hash]);
+                            }
 
-                            // Request the missing parent
-                            peer.write(Message::GetBlocks(vec![parent_hash]));
+                            // Add block to orphan buffer
+                            let now = Instant::now();
+                            let entry = orphan_buffer.entry(parent_hash).or_insert_with(|| OrphanEntry {
+                                blocks: Vec::new(),
+                                peer: target.clone(),
+                                first_seen: now,
+                                last_requested: now,
+                            });
+                            entry.blocks.push(block.clone());
+                            entry.peer = target;
+                            entry.last_requested = now;
+                            buffered += 1;
+                            drop(orphan_buffer);
                             continue;
                         }
 
-                        // Difficulty check with parent block
-                        let parent_block = blockchain.blocks.get(&parent_hash).unwrap();
-                        if block.header.difficulty != parent_block.header.difficulty {
-                            debug!("Block with hash {:?} has incorrect difficulty", block_hash);
+                        // Full validation against the parent block
+                        let expected_difficulty = blockchain.compute_next_difficulty(&parent_hash).unwrap();
+                        if let Err(reason) = self.validate_once(&block, &block_hash, &expected_difficulty) {
+                            debug!("Block with hash {:?} rejected: {}", block_hash, reason);
                             continue;
                         }
 
-                        // Insert block and add to broadcast if new
-                        if !blockchain.blocks.contains_key(&block_hash) {
-                            blockchain.insert(&block);
-
-                            // Remove transactions included in this block from the mempool
-                            let tx_hashes: Vec<H256> = block.content.transactions.iter().map(|tx| tx.hash()).collect();
-                            mempool.remove_transactions(tx_hashes);
-
-                            new_block_hashes.push(block_hash);
+                        // Insert block and add to broadcast
+                        match blockchain.try_insert(&block) {
+                            Err(reason) => {
+                                debug!("Block with hash {:?} rejected: {}", block_hash, reason);
+                                continue;
+                            }
+                            // Blocks leaving the longest chain give their transactions back to the pool
+                            Ok(Some(reorg)) => {
+                                abandoned_txs.extend(blockchain.block_transactions(&reorg.removed));
+                                adopted_txs.extend(blockchain.block_transactions(&reorg.added));
+                            }
+                            // A block on a side branch leaves its transactions pooled
+                            Ok(None) if blockchain.tip() == block_hash => adopted_txs.extend(block.content.transactions.iter().cloned()),
+                            Ok(None) => {}
+                        }
+                        new_block_hashes.push(block_hash);
+                    }
 
+                    // Move transactions included in the new chain out of the pool and drop
+                    // pooled ones the new tip has made unminable
+                    if !new_block_hashes.is_empty() {
+                        match blockchain.get_state(&blockchain.tip()) {
+                            Some(tip_state) => mempool.reorganize(abandoned_txs, &adopted_txs, &tip_state),
+                            None => mempool.confirm_transactions(&adopted_txs),
                         }
                     }
 
                     drop(blockchain);
                     drop(mempool);
 
+                    // Process any orphans that may now have their parent, and announce them
+                    // in the same message as the blocks that connected them
+                    new_block_hashes.extend(self.process_orphans(batch));
+                    let mut announced = HashSet::new();
+                    new_block_hashes.retain(|hash| announced.insert(*hash));
                     if !new_block_hashes.is_empty() {
                         self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
                     }
-
-                    // Process any orphans that may now have their parent
-                    self.process_orphans();
                 }
-                _=> unimplemented!(),
+                // The server consumes the handshake before messages reach workers
+                Message::Hello(_) => {}
             }
         }
     }
 
-    fn process_orphans(&self) {
-        let mut processed_any = true;
-        while processed_any {
-            processed_any = false;
-            let mut orphan_buffer = self.orphan_buffer.lock().unwrap();
-            let mut blockchain = self.blockchain.lock().unwrap();
-            let mut new_block_hashes = Vec::new();
+    /// `validate_block`, skipped for a block that recently passed it
+    fn validate_once(&self, block: &Block, block_hash: &H256, expected_difficulty: &H256) -> Result<(), &'static str> {
+        let mut validated = lock_recovering(&self.validated);
+        if validated.hashes.contains(block_hash) {
+            return Ok(());
+        }
+        validated.validations += 1;
+        validate_block(block, expected_difficulty)?;
+        validated.insert(*block_hash);
+        Ok(())
+    }
 
-            // Process any orphans whose parents now exist in the blockchain
-            for (parent_hash, orphans) in orphan_buffer.clone().iter() {
-                if blockchain.blocks.contains_key(parent_hash) {
-                    for orphan in orphans {
-                        let orphan_hash = orphan.hash();
-                        blockchain.insert(orphan);
-                        new_block_hashes.push(orphan_hash);
-                        processed_any = true;
+    /// Insert buffered orphans waiting on any of `parents` that is now known, following each
+    /// inserted block to the orphans waiting on it, and return the hashes of the blocks inserted
+    fn process_orphans(&self, parents: Vec<H256>) -> Vec<H256> {
+        // Same lock order as the Blocks handler: blockchain before orphan buffer
+        let mut blockchain = self.blockchain.write_timed(&BLOCKCHAIN_LOCK);
+        let mut orphan_buffer = lock_recovering(&self.orphan_buffer);
+        let mut new_block_hashes = Vec::new();
+        let mut abandoned_txs = Vec::new();
+        let mut adopted_txs = Vec::new();
 
+        // Start from orphans whose parents now exist in the blockchain, in the order given
+        let mut connectable: Vec<H256> = parents
+            .into_iter()
+            .rev()
+            .filter(|parent_hash| orphan_buffer.contains_key(parent_hash) && blockchain.contains(parent_hash))
+            .collect();
+        while let Some(parent_hash) = connectable.pop() {
+            // Remove processed orphans from buffer
+            let entry = match orphan_buffer.remove(&parent_hash) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for orphan in entry.blocks {
+                let orphan_hash = orphan.hash();
+                // Buffered blocks get the same validation as blocks whose parent was known
+                let expected_difficulty = blockchain.compute_next_difficulty(&parent_hash).unwrap();
+                if let Err(reason) = self.validate_once(&orphan, &orphan_hash, &expected_difficulty) {
+                    debug!("Orphan block {:?} rejected: {}", orphan_hash, reason);
+                    continue;
+                }
+                if blockchain.contains(&orphan_hash) {
+                    continue;
+                }
+                match blockchain.try_insert(&orphan) {
+                    Err(reason) => {
+                        debug!("Orphan block {:?} rejected: {}", orphan_hash, reason);
+                        continue;
                     }
-
-                    // Remove processed orphans from buffer
-                    orphan_buffer.remove(parent_hash);
-
+                    Ok(Some(reorg)) => {
+                        abandoned_txs.extend(blockchain.block_transactions(&reorg.removed));
+                        adopted_txs.extend(blockchain.block_transactions(&reorg.added));
+                    }
+                    Ok(None) if blockchain.tip() == orphan_hash => adopted_txs.extend(orphan.content.transactions),
+                    Ok(None) => {}
+                }
+                new_block_hashes.push(orphan_hash);
+                // Orphans waiting on this block can go in next
+                if orphan_buffer.contains_key(&orphan_hash) {
+                    connectable.push(orphan_hash);
                 }
             }
+        }
 
-            drop(blockchain);
-            drop(orphan_buffer);
+        drop(orphan_buffer);
+        let tip_state = if new_block_hashes.is_empty() { None } else { blockchain.get_state(&blockchain.tip()) };
+        drop(blockchain);
 
-            // Broadcast newly processed orphan blocks
-            if !new_block_hashes.is_empty() {
-                self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
+        match tip_state {
+            Some(tip_state) => self.mempool.lock_timed(&MEMPOOL_LOCK).reorganize(abandoned_txs, &adopted_txs, &tip_state),
+            None if !adopted_txs.is_empty() => self.mempool.lock_timed(&MEMPOOL_LOCK).confirm_transactions(&adopted_txs),
+            None => {}
+        }
+        new_block_hashes
+    }
+
+    /// Re-request parents of orphans that have waited longer than the retry timeout,
+    /// and drop orphans that have waited longer than the max age
+    fn sweep_orphans(&self) {
+        let now = Instant::now();
+        let mut orphan_buffer = lock_recovering(&self.orphan_buffer);
+
+        let expired: Vec<H256> = orphan_buffer
+            .iter()
+            .filter(|(_, entry)| now.duration_since(entry.first_seen) >= self.config.orphan_max_age)
+            .map(|(parent_hash, _)| *parent_hash)
+            .collect();
+        for parent_hash in expired {
+            let entry = orphan_buffer.remove(&parent_hash).unwrap();
+            warn!("Dropping {} orphan(s) whose parent {:?} never arrived", entry.blocks.len(), parent_hash);
+        }
+
+        for (parent_hash, entry) in orphan_buffer.iter_mut() {
+            if now.duration_since(entry.last_requested) >= self.config.orphan_retry_timeout {
+                debug!("Re-requesting missing parent {:?}", parent_hash);
+                lock_recovering(&self.peer_scores).request_failed(entry.peer.addr(), parent_hash);
+                let mut target = self.preferred_peer(&entry.peer);
+                self.request_blocks(&mut target, vec![*parent_hash]);
+                entry.peer = target;
+                entry.last_requested = now;
             }
+        }
+    }
+
+    /// The highest-scoring known peer, or `fallback` if we don't know any
+    fn preferred_peer(&self, fallback: &peer::Handle) -> peer::Handle {
+        lock_recovering(&self.peer_scores)
+            .best_peer()
+            .unwrap_or_else(|| fallback.clone())
+    }
 
-            
+    /// Send a GetBlocks request, remembering when it was sent to score the peer's response
+    fn request_blocks(&self, peer: &mut peer::Handle, hashes: Vec<H256>) {
+        let now = Instant::now();
+        let mut peer_scores = lock_recovering(&self.peer_scores);
+        for hash in &hashes {
+            peer_scores.request_sent(peer.addr(), *hash, now);
         }
+        drop(peer_scores);
+        peer.write(Message::GetBlocks(hashes));
     }
 }
 
@@ -262,7 +660,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_raw(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_raw(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -341,4 +742,611 @@ mod test {
 
 }*/
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use ntest::timeout;
+    use ring::signature::Ed25519KeyPair;
+
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use super::{OrphanEntry, TestMsgSender, Worker, WorkerConfig};
+    use super::super::peer;
+    use super::super::message::Message;
+    use super::super::server::{Handle as ServerHandle, TestReceiver as ServerTestReceiver};
+    use crate::blockchain::{Blockchain, MAX_TX_PER_BLOCK};
+    use crate::types::address::Address;
+    use crate::types::block::{generate_mined_block, generate_mined_block_with_transactions, generate_random_block};
+    use crate::types::hash::{Hashable, H256};
+    use crate::types::transaction::{Mempool, SignedTransaction};
+
+    fn start_test_worker(config: WorkerConfig) -> (TestMsgSender, ServerTestReceiver, Arc<RwLock<Blockchain>>) {
+        let (test_msg_sender, server_receiver, blockchain, _orphan_buffer) = start_test_worker_with_orphans(config);
+        (test_msg_sender, server_receiver, blockchain)
+    }
+
+    type OrphanBuffer = Arc<Mutex<HashMap<H256, OrphanEntry>>>;
+
+    fn start_test_worker_with_orphans(
+        config: WorkerConfig,
+    ) -> (TestMsgSender, ServerTestReceiver, Arc<RwLock<Blockchain>>, OrphanBuffer) {
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, config);
+        let orphan_buffer = Arc::clone(&worker.orphan_buffer);
+        worker.start();
+        (test_msg_sender, server_receiver, blockchain, orphan_buffer)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphan_parent_is_re_requested() {
+        let config = WorkerConfig {
+            orphan_retry_timeout: Duration::from_millis(100),
+            orphan_max_age: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let (test_msg_sender, _server_receiver, blockchain) = start_test_worker(config);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+        let parent = generate_mined_block(&genesis_hash, &difficulty);
+        let orphan = generate_mined_block(&parent.hash(), &difficulty);
+
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![orphan]));
+        // The first request is sent immediately, the second only after the timeout
+        for _ in 0..2 {
+            match peer_receiver.recv() {
+                Message::GetBlocks(v) => assert_eq!(v, vec![parent.hash()]),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn stale_orphans_are_dropped() {
+        let config = WorkerConfig {
+            orphan_retry_timeout: Duration::from_millis(50),
+            orphan_max_age: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (test_msg_sender, server_receiver, blockchain) = start_test_worker(config);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+        let parent = generate_mined_block(&genesis_hash, &difficulty);
+        let orphan = generate_mined_block(&parent.hash(), &difficulty);
+
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![orphan.clone()]));
+        std::thread::sleep(Duration::from_millis(500));
+
+        // The parent arrives too late; the expired orphan must not be connected
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![parent.hash()]),
+            _ => panic!(),
+        }
+        let tip: H256 = blockchain.read().unwrap().tip();
+        assert_eq!(tip, parent.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reply_new_transaction_hashes() {
+        let (test_msg_sender, _server_receiver, _blockchain) = start_test_worker(WorkerConfig::default());
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        let mut peer_receiver = test_msg_sender.send(Message::NewTransactionHashes(vec![tx.hash()]));
+        match peer_receiver.recv() {
+            Message::GetTransactions(v) => assert_eq!(v, vec![tx.hash()]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reply_transactions_and_get_transactions() {
+        let (test_msg_sender, server_receiver, _blockchain) = start_test_worker(WorkerConfig::default());
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        let mut forged = SignedTransaction::create(Address::from([1u8; 20]), 10, 2, &key);
+        forged.signature[0] ^= 0xff;
+
+        // Only the transaction the mempool accepted is relayed
+        let _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx.clone(), forged]));
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(v)) => assert_eq!(v, vec![tx.hash()]),
+            _ => panic!(),
+        }
+
+        let mut peer_receiver = test_msg_sender.send(Message::GetTransactions(vec![tx.hash()]));
+        match peer_receiver.recv() {
+            Message::Transactions(v) => {
+                assert_eq!(v.len(), 1);
+                assert_eq!(v[0].hash(), tx.hash());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn transaction_from_block_can_be_served() {
+        let (test_msg_sender, server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        let block = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![tx.clone()]);
+
+        // The transaction never entered our mempool, only the block did
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![block.hash()]),
+            _ => panic!(),
+        }
+
+        let mut peer_receiver = test_msg_sender.send(Message::GetTransactions(vec![tx.hash()]));
+        match peer_receiver.recv() {
+            Message::Transactions(v) => {
+                assert_eq!(v.len(), 1);
+                assert_eq!(v[0].hash(), tx.hash());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn buffered_orphan_with_bad_pow_is_not_inserted() {
+        let (test_msg_sender, server_receiver, blockchain, orphan_buffer) =
+            start_test_worker_with_orphans(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+        let parent = generate_mined_block(&genesis_hash, &difficulty);
+
+        let mut bad = generate_random_block(&parent.hash());
+        bad.header.difficulty = difficulty;
+        while bad.hash() <= difficulty {
+            bad.header.nonce = bad.header.nonce.wrapping_add(1);
+        }
+        // Slip the invalid block straight into the buffer, bypassing the receive-time checks
+        let (peer_handle, _peer_receiver) = peer::Handle::test_handle();
+        let now = Instant::now();
+        orphan_buffer.lock().unwrap().insert(parent.hash(), OrphanEntry {
+            blocks: vec![bad.clone()],
+            peer: peer_handle,
+            first_seen: now,
+            last_requested: now,
+        });
+
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![parent.hash()]),
+            _ => panic!(),
+        }
+        // The single worker thread handles messages in order, so once this reply
+        // arrives the orphans from the previous message have been processed
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![parent.hash()]));
+        match peer_receiver.recv() {
+            Message::Blocks(v) => assert_eq!(v[0].hash(), parent.hash()),
+            _ => panic!(),
+        }
+
+        assert!(!blockchain.read().unwrap().contains(&bad.hash()));
+        assert_eq!(blockchain.read().unwrap().tip(), parent.hash());
+        assert!(orphan_buffer.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphan_buffer_is_bounded() {
+        let config = WorkerConfig { max_orphans: 5, ..Default::default() };
+        let (test_msg_sender, _server_receiver, blockchain, orphan_buffer) = start_test_worker_with_orphans(config);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+
+        let mut chain = vec![generate_mined_block(&genesis_hash, &difficulty)];
+        for _ in 1..20 {
+            let next = generate_mined_block(&chain.last().unwrap().hash(), &difficulty);
+            chain.push(next);
+        }
+
+        // Deliver the chain tip first, as a peer answering parent requests one at a time would
+        let mut peer_receivers = Vec::new();
+        for block in chain[1..].iter().rev() {
+            peer_receivers.push(test_msg_sender.send(Message::Blocks(vec![block.clone()])));
+        }
+        // Messages are handled in order, so once this is answered every block above was processed
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![genesis_hash]));
+        match peer_receiver.recv() {
+            Message::Blocks(v) => assert_eq!(v[0].hash(), genesis_hash),
+            _ => panic!(),
+        }
+
+        let buffered: usize = orphan_buffer.lock().unwrap().values().map(|entry| entry.blocks.len()).sum();
+        assert_eq!(buffered, 5);
+        assert_eq!(blockchain.read().unwrap().tip(), genesis_hash);
+    }
+
+    /// Deliver `blocks` to a worker and wait until it has handled them
+    fn deliver(test_msg_sender: &TestMsgSender, genesis_hash: H256, blocks: Vec<super::Block>) {
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(blocks));
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![genesis_hash]));
+        match peer_receiver.recv() {
+            Message::Blocks(_) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn burst_beyond_the_rate_limit_is_dropped() {
+        let config = WorkerConfig { max_peer_messages_per_sec: 3, ..Default::default() };
+        let (test_msg_sender, _server_receiver, blockchain) = start_test_worker(config);
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let blocks: Vec<_> = (0..5).map(|_| generate_mined_block(&genesis_hash, &difficulty)).collect();
+        for block in &blocks {
+            test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        }
+
+        // Another peer has its own allowance, and is answered once the burst is handled
+        let (other, mut other_receiver) =
+            peer::Handle::test_handle_at("127.0.0.1:4000".parse().unwrap());
+        let request = bincode::serialize(&Message::GetBlocks(vec![genesis_hash])).unwrap();
+        smol::block_on(test_msg_sender.s.send((request, other))).unwrap();
+        match other_receiver.recv() {
+            Message::Blocks(_) => {}
+            _ => panic!(),
+        }
+
+        let blockchain = blockchain.read().unwrap();
+        let inserted: Vec<bool> = blocks.iter().map(|block| blockchain.contains(&block.hash())).collect();
+        assert_eq!(inserted, vec![true, true, true, false, false]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn known_block_is_announced_once() {
+        let (test_msg_sender, server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let block = generate_mined_block(&genesis_hash, &difficulty);
+        let child = generate_mined_block(&block.hash(), &difficulty);
+
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone(), block.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![child.clone()]);
+
+        // The one worker handles messages in order, so a repeat announcement would come before the child's
+        let announcements: Vec<Vec<H256>> = (0..2)
+            .map(|_| match server_receiver.recv() {
+                Some(Message::NewBlockHashes(hashes)) => hashes,
+                _ => panic!("expected a block announcement"),
+            })
+            .collect();
+        assert_eq!(announcements, vec![vec![block.hash()], vec![child.hash()]]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn side_branch_block_leaves_its_transactions_pooled() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        Worker::new(1, msg_chan, &server, &blockchain, &mempool, WorkerConfig::default()).start();
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        mempool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+
+        let block = generate_mined_block(&genesis_hash, &difficulty);
+        let child = generate_mined_block(&block.hash(), &difficulty);
+        deliver(&test_msg_sender, genesis_hash, vec![block, child.clone()]);
+
+        // A sibling of the first block does not move the tip, so its transaction is still unmined
+        let side = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![tx.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![side.clone()]);
+        assert!(blockchain.read().unwrap().contains(&side.hash()));
+        assert_eq!(blockchain.read().unwrap().tip(), child.hash());
+        assert!(mempool.lock().unwrap().contains_transactions(&tx.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn copies_from_other_peers_skip_validation() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, WorkerConfig::default());
+        let validated = Arc::clone(&worker.validated);
+        worker.start();
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+
+        // A held block is recognised before validation
+        let block = generate_mined_block(&genesis_hash, &difficulty);
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone()]);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+        assert_eq!(validated.lock().unwrap().validations, 1);
+
+        // A well-formed block the chain refuses is not held, but its checks are not repeated
+        let unfunded_key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        let unfunded = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &unfunded_key);
+        let refused = generate_mined_block_with_transactions(&block.hash(), &difficulty, vec![unfunded]);
+        deliver(&test_msg_sender, genesis_hash, vec![refused.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![refused.clone()]);
+        assert!(!blockchain.read().unwrap().contains(&refused.hash()));
+        assert_eq!(validated.lock().unwrap().validations, 2);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reversed_batch_connects_in_one_pass() {
+        let (test_msg_sender, _server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let mut blocks = vec![generate_mined_block(&genesis_hash, &difficulty)];
+        for _ in 1..100 {
+            let block = generate_mined_block(&blocks.last().unwrap().hash(), &difficulty);
+            blocks.push(block);
+        }
+        let tip = blocks.last().unwrap().hash();
+        blocks.reverse();
+
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(blocks));
+        deliver(&test_msg_sender, genesis_hash, vec![]);
+
+        assert_eq!(blockchain.read().unwrap().tip(), tip);
+        assert_eq!(blockchain.read().unwrap().all_blocks_in_longest_chain().len(), 101);
+        // Every parent came in the same batch, so none was requested
+        assert!(peer_receiver.try_recv().is_none());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn connected_orphans_are_announced_with_their_parent() {
+        let (test_msg_sender, server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let parent = generate_mined_block(&genesis_hash, &difficulty);
+        let child = generate_mined_block(&parent.hash(), &difficulty);
+        let grandchild = generate_mined_block(&child.hash(), &difficulty);
+
+        // Descendants arrive first and wait as orphans until the parent connects them all
+        deliver(&test_msg_sender, genesis_hash, vec![grandchild.clone(), child.clone()]);
+        deliver(&test_msg_sender, genesis_hash, vec![parent.clone()]);
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(hashes)) => {
+                assert_eq!(hashes, vec![parent.hash(), child.hash(), grandchild.hash()]);
+            }
+            _ => panic!("expected a block announcement"),
+        }
+        assert_eq!(blockchain.read().unwrap().tip(), grandchild.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn competing_nodes_converge_once_a_branch_extends() {
+        let (node_a, _server_a, chain_a) = start_test_worker(WorkerConfig::default());
+        let (node_b, _server_b, chain_b) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = chain_a.read().unwrap().tip();
+        assert_eq!(chain_b.read().unwrap().tip(), genesis_hash);
+        let difficulty = chain_a.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+
+        // Each node mines its own block at height 1, then they exchange them
+        let block_a = generate_mined_block(&genesis_hash, &difficulty);
+        let block_b = generate_mined_block(&genesis_hash, &difficulty);
+        deliver(&node_a, genesis_hash, vec![block_a.clone()]);
+        deliver(&node_b, genesis_hash, vec![block_b.clone()]);
+        deliver(&node_a, genesis_hash, vec![block_b.clone()]);
+        deliver(&node_b, genesis_hash, vec![block_a.clone()]);
+
+        // Equal work is broken by hash, so both settle on the smaller block whatever the arrival order
+        let smaller = std::cmp::min(block_a.hash(), block_b.hash());
+        assert_eq!(chain_a.read().unwrap().tip(), smaller);
+        assert_eq!(chain_b.read().unwrap().tip(), smaller);
+
+        // A block extending either branch outweighs the tie on both nodes
+        let next = generate_mined_block(&block_b.hash(), &difficulty);
+        deliver(&node_b, genesis_hash, vec![next.clone()]);
+        deliver(&node_a, genesis_hash, vec![next.clone()]);
+        assert_eq!(chain_a.read().unwrap().tip(), next.hash());
+        assert_eq!(chain_b.read().unwrap().tip(), next.hash());
+        assert_eq!(
+            chain_a.read().unwrap().all_blocks_in_longest_chain(),
+            chain_b.read().unwrap().all_blocks_in_longest_chain()
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn joining_node_syncs_headers_then_bodies() {
+        let (node_a, _server_a, chain_a) = start_test_worker(WorkerConfig::default());
+        let (node_b, _server_b, chain_b) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = chain_a.read().unwrap().tip();
+        let difficulty = chain_a.read().unwrap().consensus_difficulty();
+        for _ in 0..50 {
+            let block = generate_mined_block(&chain_a.read().unwrap().tip(), &difficulty);
+            assert!(chain_a.write().unwrap().insert(&block));
+        }
+        let chain = chain_a.read().unwrap().all_blocks_in_longest_chain();
+
+        // B asks A for the headers it lacks, relaying each message by hand
+        let (mut to_a, mut from_b) = peer::Handle::test_handle();
+        super::request_headers(&chain_b, &mut to_a);
+        let mut from_a = node_a.send(from_b.recv());
+        let headers = match from_a.recv() {
+            Message::Headers(headers) => headers,
+            _ => panic!("expected headers"),
+        };
+        assert_eq!(headers.iter().map(|header| header.hash()).collect::<Vec<_>>(), chain[1..].to_vec());
+
+        // B checks the headers and asks for every body, before holding any of the blocks
+        let mut from_b = node_b.send(Message::Headers(headers));
+        let get_blocks = from_b.recv();
+        match &get_blocks {
+            Message::GetBlocks(hashes) => assert_eq!(*hashes, chain[1..].to_vec()),
+            _ => panic!("expected a body request"),
+        }
+        assert!(chain[1..].iter().all(|hash| chain_b.read().unwrap().has_pending_header(hash)));
+        assert_eq!(chain_b.read().unwrap().tip(), genesis_hash);
+
+        let mut from_a = node_a.send(get_blocks);
+        let bodies = match from_a.recv() {
+            Message::Blocks(blocks) => blocks,
+            _ => panic!("expected blocks"),
+        };
+        deliver(&node_b, genesis_hash, bodies);
+        assert_eq!(chain_b.read().unwrap().all_blocks_in_longest_chain(), chain);
+        assert!(!chain[1..].iter().any(|hash| chain_b.read().unwrap().has_pending_header(hash)));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn blocks_over_the_transaction_limit_are_dropped() {
+        let (test_msg_sender, server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().consensus_difficulty();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let transactions: Vec<SignedTransaction> = (1..=MAX_TX_PER_BLOCK as u64 + 1)
+            .map(|nonce| SignedTransaction::create(Address::from([1u8; 20]), 1, nonce, &key))
+            .collect();
+
+        let oversized = generate_mined_block_with_transactions(&genesis_hash, &difficulty, transactions.clone());
+        deliver(&test_msg_sender, genesis_hash, vec![oversized.clone()]);
+        assert!(!blockchain.read().unwrap().contains(&oversized.hash()));
+
+        let full = generate_mined_block_with_transactions(
+            &genesis_hash,
+            &difficulty,
+            transactions[..MAX_TX_PER_BLOCK].to_vec(),
+        );
+        deliver(&test_msg_sender, genesis_hash, vec![full.clone()]);
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![full.hash()]),
+            _ => panic!(),
+        }
+        assert_eq!(blockchain.read().unwrap().tip(), full.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn worker_restarts_after_panic() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, WorkerConfig::default());
+        let orphan_buffer = Arc::clone(&worker.orphan_buffer);
+
+        // The first run panics while holding one of the worker's locks, as a bug mid-update would
+        let panicked = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        std::thread::spawn(move || {
+            worker.supervise(0, |worker| {
+                if !panicked.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    let _guard = worker.orphan_buffer.lock().unwrap();
+                    panic!("poisoning the orphan buffer");
+                }
+                worker.worker_loop();
+            })
+        });
+
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("still there".to_string()));
+        match peer_receiver.recv() {
+            Message::Pong(nonce) => assert_eq!(nonce, "still there"),
+            _ => panic!(),
+        }
+        assert!(super::lock_recovering(&orphan_buffer).is_empty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn undecodable_message_is_dropped_and_counts_against_its_peer() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(RwLock::new(Blockchain::new(&[0u8; 32])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, WorkerConfig::default());
+        let peer_scores = Arc::clone(&worker.peer_scores);
+        worker.start();
+
+        // The (only) worker skips the junk and answers the next message
+        let (junk_peer, _junk_receiver) = peer::Handle::test_handle_at("127.0.0.1:4001".parse().unwrap());
+        smol::block_on(test_msg_sender.s.send((vec![0xff; 3], junk_peer.clone()))).unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("still there".to_string()));
+        match peer_receiver.recv() {
+            Message::Pong(nonce) => assert_eq!(nonce, "still there"),
+            _ => panic!(),
+        }
+
+        let peer_scores = peer_scores.lock().unwrap();
+        let honest = peer::Handle::test_handle().0;
+        assert!(peer_scores.score(junk_peer.addr()).unwrap() < peer_scores.score(honest.addr()).unwrap());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn block_with_mismatched_merkle_root_is_dropped() {
+        let (test_msg_sender, _server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let committed = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        let swapped = SignedTransaction::create(Address::from([2u8; 20]), 10, 1, &key);
+
+        // The header commits to one transaction while the body carries another
+        let mut block = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![committed]);
+        block.content.transactions = vec![swapped];
+        assert!(!block.is_valid_content());
+        assert_eq!(super::validate_block(&block, &difficulty), Err("merkle root does not match content"));
+
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone()]);
+        assert!(!blockchain.read().unwrap().contains(&block.hash()));
+        assert_eq!(blockchain.read().unwrap().tip(), genesis_hash);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn block_with_repeated_transaction_is_dropped() {
+        let (test_msg_sender, _server_receiver, blockchain) = start_test_worker(WorkerConfig::default());
+        let genesis_hash = blockchain.read().unwrap().tip();
+        let difficulty = blockchain.read().unwrap().get_block(&genesis_hash).unwrap().get_difficulty();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+
+        let block = generate_mined_block_with_transactions(&genesis_hash, &difficulty, vec![tx.clone(), tx]);
+        assert!(block.is_valid_content());
+        assert!(block.has_duplicate_transactions());
+        assert_eq!(super::validate_block(&block, &difficulty), Err("carries the same transaction twice"));
+
+        deliver(&test_msg_sender, genesis_hash, vec![block.clone()]);
+        assert!(!blockchain.read().unwrap().contains(&block.hash()));
+        assert_eq!(blockchain.read().unwrap().tip(), genesis_hash);
+    }
+
+    #[test]
+    fn block_with_malformed_transaction_is_invalid() {
+        let blockchain = Blockchain::new(&[0u8; 32]);
+        let genesis = blockchain.genesis().clone();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let mut tx = SignedTransaction::create(Address::from([1u8; 20]), 1, 1, &key);
+        tx.public_key.truncate(5);
+
+        let block = generate_mined_block_with_transactions(&genesis.hash(), &genesis.get_difficulty(), vec![tx]);
+        assert_eq!(
+            super::validate_block(&block, &genesis.get_difficulty()),
+            Err("transaction has a malformed public key or signature")
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
 use ring::digest;
+use std::convert::TryInto;
 
 // 20-byte address
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
@@ -37,6 +38,19 @@ impl std::fmt::Display for Address {
     }
 }
 
+/// Parse 40 hex digits, as printed by `Display`
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+        let raw: [u8; 20] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| format!("expected 20 bytes, got {}", bytes.len()))?;
+        Ok(Address(raw))
+    }
+}
+
 impl std::fmt::Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -1,6 +1,6 @@
 use ring::digest::SHA256;
 use serde::{Serialize, Deserialize};
-use crate::types::hash::{H256, Hashable};
+use crate::types::hash::{compact_to_target, H256, Hashable};
 use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -20,6 +20,7 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    pub bits: Option<u32>, // Compact difficulty; takes precedence over `difficulty` when set
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,7 +51,18 @@ impl Block {
 
     pub fn get_difficulty(&self) -> H256 {
         //unimplemented!()
-        self.header.difficulty
+        self.header.target()
+    }
+
+    /// Whether the header's merkle root commits to exactly the transactions in the content
+    pub fn is_valid_content(&self) -> bool {
+        self.header.merkle_root == MerkleTree::new(&self.content.transactions).root()
+    }
+
+    /// Whether the content carries the same transaction more than once, which would apply it twice
+    pub fn has_duplicate_transactions(&self) -> bool {
+        let mut seen = std::collections::HashSet::new();
+        !self.content.transactions.iter().all(|tx| seen.insert(tx.hash()))
     }
 }
 
@@ -67,21 +79,38 @@ impl Header {
             nonce,
             difficulty,
             timestamp,
-            merkle_root
+            merkle_root,
+            bits: None,
+        }
+    }
+
+    /// Target the header's hash must not exceed
+    pub fn target(&self) -> H256 {
+        match self.bits {
+            Some(bits) => compact_to_target(bits),
+            None => self.difficulty,
         }
     }
 }
 
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
     //unimplemented!()
     let nonce: u32 = rand::random(); // Generate a random nonce
     let difficulty = H256::from([0xff; 32]); // Set a high difficulty (all bits set to 1)
     
-    let timestamp = SystemTime::now()
+    let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_millis(); // Get current UNIX timestamp in milliseconds
+        .as_millis() as u64; // Get current UNIX timestamp in milliseconds
+    // Blocks generated within the same millisecond still come after one another
+    static LAST_GENERATED_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+    let last = LAST_GENERATED_TIMESTAMP
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+        .unwrap();
+    let timestamp = now.max(last + 1) as u128;
 
     let transactions = Vec::new(); // Empty content for now
     let merkle_root = MerkleTree::new(&transactions).root(); // Generate Merkle root of empty input
@@ -90,4 +119,22 @@ pub fn generate_random_block(parent: &H256) -> Block {
     let content = Content::new(transactions);
 
     Block { header, content }
-}
\ No newline at end of file
+}
+/// Generate an empty block on top of `parent` whose hash meets `difficulty`
+#[cfg(any(test, test_utilities))]
+pub fn generate_mined_block(parent: &H256, difficulty: &H256) -> Block {
+    generate_mined_block_with_transactions(parent, difficulty, vec![])
+}
+
+/// Generate a block carrying `transactions` on top of `parent` whose hash meets `difficulty`
+#[cfg(any(test, test_utilities))]
+pub fn generate_mined_block_with_transactions(parent: &H256, difficulty: &H256, transactions: Vec<SignedTransaction>) -> Block {
+    let mut block = generate_random_block(parent);
+    block.header.difficulty = *difficulty;
+    block.header.merkle_root = MerkleTree::new(&transactions).root();
+    block.content.transactions = transactions;
+    while block.hash() > *difficulty {
+        block.header.nonce = block.header.nonce.wrapping_add(1);
+    }
+    block
+}
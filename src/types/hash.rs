@@ -37,6 +37,31 @@ impl std::fmt::Display for H256 {
     }
 }
 
+/// Parse 64 hex digits, most significant byte first, as printed by `Display`
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+        let raw: [u8; 32] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| format!("expected 32 bytes, got {}", bytes.len()))?;
+        Ok(H256(raw))
+    }
+}
+
+impl H256 {
+    /// Parse 64 hex digits, as `to_string` prints them
+    pub fn from_hex(input: &str) -> Result<H256, String> {
+        input.parse()
+    }
+
+    /// The first 8 hex digits, enough to tell hashes apart in logs
+    pub fn short(&self) -> String {
+        hex::encode(&self.0[..4])
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -109,6 +134,49 @@ impl PartialOrd for H256 {
     }
 }
 
+/// Expand a compact "bits" difficulty (as in Bitcoin's nBits) into a full target.
+/// The top byte is the target's length in bytes and the low 23 bits its leading digits;
+/// the sign bit is ignored and targets too large for 256 bits saturate.
+pub fn compact_to_target(bits: u32) -> H256 {
+    let size = (bits >> 24) as i32;
+    let mantissa = (bits & 0x007f_ffff).to_be_bytes();
+    let mut target = [0u8; 32];
+    for (offset, byte) in mantissa[1..].iter().enumerate() {
+        let index = 32 - size + offset as i32;
+        if *byte == 0 {
+            continue;
+        }
+        if index < 0 {
+            return H256([0xff; 32]);
+        }
+        if index < 32 {
+            target[index as usize] = *byte;
+        }
+    }
+    H256(target)
+}
+
+/// Encode a target in compact "bits" form, keeping its three most significant bytes.
+/// The result expands to a target no larger than the input.
+pub fn target_to_compact(target: &H256) -> u32 {
+    let first = match target.0.iter().position(|byte| *byte != 0) {
+        Some(first) => first,
+        None => return 0,
+    };
+    let mut size = (32 - first) as u32;
+    let mut mantissa = target.0[first..]
+        .iter()
+        .chain([0u8; 3].iter())
+        .take(3)
+        .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+    // The mantissa's top bit is a sign bit, so shift it into the next byte
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    (size << 24) | mantissa
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
@@ -116,4 +184,79 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compact_to_target, generate_random_hash, target_to_compact, H256};
+
+    #[test]
+    fn bitcoin_genesis_bits() {
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(compact_to_target(0x1d00ffff), H256::from(expected));
+        assert_eq!(target_to_compact(&H256::from(expected)), 0x1d00ffff);
+    }
+
+    #[test]
+    fn compact_round_trips() {
+        for size in 1..=32u32 {
+            for mantissa in &[0x01u32, 0x7f, 0x0100, 0x7fff, 0x01_0000, 0x12_3456, 0x7f_ffff] {
+                let bits = (size << 24) | mantissa;
+                let target = compact_to_target(bits);
+                // Re-encoding must reproduce the same target even when the bits were not normalized
+                assert_eq!(compact_to_target(target_to_compact(&target)), target);
+            }
+        }
+    }
+
+    #[test]
+    fn target_round_trip_keeps_leading_bytes() {
+        for _ in 0..100 {
+            let target = generate_random_hash();
+            let expanded = compact_to_target(target_to_compact(&target));
+            assert!(expanded <= target);
+            let first = target.as_ref().iter().position(|byte| *byte != 0).unwrap();
+            let kept = (32 - first).min(if target.as_ref()[first] & 0x80 != 0 { 2 } else { 3 });
+            assert_eq!(expanded.as_ref()[..first + kept], target.as_ref()[..first + kept]);
+        }
+        assert_eq!(target_to_compact(&H256::default()), 0);
+        assert_eq!(compact_to_target(0), H256::default());
+    }
+
+    #[test]
+    fn parses_its_own_display() {
+        let hash = generate_random_hash();
+        assert_eq!(hash.to_string().parse::<H256>(), Ok(hash));
+        assert!("00ff".parse::<H256>().is_err());
+        assert!("zz".repeat(32).parse::<H256>().is_err());
+    }
+
+    #[test]
+    fn from_hex_round_trips_and_rejects_bad_input() {
+        let hash = generate_random_hash();
+        assert_eq!(H256::from_hex(&hash.to_string()), Ok(hash));
+        assert_eq!(H256::from_hex(&"ab".repeat(32)), Ok(H256::from([0xab; 32])));
+        assert_eq!(H256::from_hex(&"AB".repeat(32)), Ok(H256::from([0xab; 32])));
+
+        // Too short, too long, odd length, and non-hex digits
+        for input in ["", &"00".repeat(31), &"00".repeat(33), &"0".repeat(63), &format!("{}zz", "00".repeat(31))] {
+            assert!(H256::from_hex(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn short_is_the_leading_hex_digits() {
+        let hash = generate_random_hash();
+        assert_eq!(hash.short(), hash.to_string()[..8]);
+        let mut raw = [0u8; 32];
+        raw[..4].copy_from_slice(&[0x00, 0x0a, 0xbc, 0xff]);
+        assert_eq!(H256::from(raw).short(), "000abcff");
+    }
+
+    #[test]
+    fn oversized_compact_saturates() {
+        assert_eq!(compact_to_target(0x2200_ffff), H256::from([0xff; 32]));
+    }
+}
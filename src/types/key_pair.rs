@@ -1,5 +1,5 @@
 use ring::rand;
-use ring::signature::Ed25519KeyPair;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +7,80 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Signature bytes produced by a `Signer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature(Vec<u8>);
+
+impl AsRef<[u8]> for Signature {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A secret key of some signature scheme
+pub trait Signer {
+    fn sign_message(&self, message: &[u8]) -> Signature;
+
+    /// The public key as carried in transactions, which `Verifier::verify_message` accepts back
+    fn public_key_bytes(&self) -> Vec<u8>;
+}
+
+/// Checks signatures of the scheme whose `Signer` made them
+pub trait Verifier {
+    fn verify_message(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool;
+
+    fn public_key_len(&self) -> usize;
+
+    fn signature_len(&self) -> usize;
+}
+
+impl Signer for Ed25519KeyPair {
+    fn sign_message(&self, message: &[u8]) -> Signature {
+        Signature(self.sign(message).as_ref().to_vec())
+    }
+
+    fn public_key_bytes(&self) -> Vec<u8> {
+        self.public_key().as_ref().to_vec()
+    }
+}
+
+/// The scheme transactions are signed with unless told otherwise
+pub struct Ed25519Verifier;
+
+impl Verifier for Ed25519Verifier {
+    fn verify_message(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, public_key).verify(message, signature).is_ok()
+    }
+
+    fn public_key_len(&self) -> usize {
+        32
+    }
+
+    fn signature_len(&self) -> usize {
+        64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ed25519_backend_matches_ring() {
+        let key = random();
+        let message = b"some message";
+        let signer: &dyn Signer = &key;
+        let signature = signer.sign_message(message);
+
+        assert_eq!(signature.as_ref(), key.sign(message).as_ref());
+        assert_eq!(signer.public_key_bytes(), key.public_key().as_ref());
+        assert_eq!(signature.as_ref().len(), Ed25519Verifier.signature_len());
+        assert_eq!(signer.public_key_bytes().len(), Ed25519Verifier.public_key_len());
+
+        let verifier: &dyn Verifier = &Ed25519Verifier;
+        assert!(verifier.verify_message(message, &signer.public_key_bytes(), signature.as_ref()));
+        assert!(!verifier.verify_message(b"another message", &signer.public_key_bytes(), signature.as_ref()));
+        assert!(!verifier.verify_message(message, &random().public_key_bytes(), signature.as_ref()));
+    }
+}
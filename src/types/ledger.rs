@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use crate::types::address::Address;
+use crate::types::state::State;
+use crate::types::transaction::SignedTransaction;
+
+/// How transactions change the ledger a block leaves behind. The blockchain only goes
+/// through this trait, so ledger models other than accounts (e.g. UTXO) can be swapped in.
+pub trait Ledger: Clone + Send {
+    /// What `snapshot` returns, e.g. balances for inspection or serialization
+    type Snapshot;
+
+    /// Whether `tx` can be applied on top of this ledger
+    fn validate(&self, tx: &SignedTransaction) -> bool;
+
+    /// Apply `tx`, which must have passed `validate` or be a coinbase the blockchain accepted
+    fn apply(&mut self, tx: &SignedTransaction);
+
+    fn snapshot(&self) -> Self::Snapshot;
+}
+
+/// The account model: each address has a nonce and a balance
+impl Ledger for State {
+    type Snapshot = HashMap<Address, (u64, u64)>;
+
+    fn validate(&self, tx: &SignedTransaction) -> bool {
+        self.is_valid_transaction(tx)
+    }
+
+    fn apply(&mut self, tx: &SignedTransaction) {
+        if tx.is_coinbase() {
+            self.apply_coinbase(tx)
+        } else {
+            self.apply_transaction(tx)
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.get_state_snapshot()
+    }
+}
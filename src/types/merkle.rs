@@ -1,5 +1,24 @@
 use super::hash::{Hashable, H256};
 
+/// Hash used to combine two child nodes into their parent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NodeHash {
+    #[default]
+    Sha256,
+    DoubleSha256, // SHA256 applied twice, as in Bitcoin
+}
+
+impl NodeHash {
+    fn combine(self, left: &H256, right: &H256) -> H256 {
+        let concatenated = [left.as_ref(), right.as_ref()].concat();
+        let once = ring::digest::digest(&ring::digest::SHA256, &concatenated);
+        match self {
+            NodeHash::Sha256 => H256::from(once),
+            NodeHash::DoubleSha256 => H256::from(ring::digest::digest(&ring::digest::SHA256, once.as_ref())),
+        }
+    }
+}
+
 /// A Merkle tree.
 #[derive(Debug, Default)]
 pub struct MerkleTree {
@@ -8,6 +27,11 @@ pub struct MerkleTree {
 
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
+        Self::new_with(data, NodeHash::default())
+    }
+
+    /// Build the tree combining nodes with `node_hash` instead of the default single SHA256
+    pub fn new_with<T>(data: &[T], node_hash: NodeHash) -> Self where T: Hashable, {
         //unimplemented!()
         if data.is_empty() {
             return MerkleTree {
@@ -25,8 +49,7 @@ impl MerkleTree {
 
             let mut next_level = Vec::new();
             for pair in current_level.chunks(2) {
-                let concatenated = [pair[0].as_ref(), pair[1].as_ref()].concat();
-                next_level.push(H256::from(ring::digest::digest(&ring::digest::SHA256, &concatenated)));
+                next_level.push(node_hash.combine(&pair[0], &pair[1]));
             }
 
             nodes.push(next_level.clone());
@@ -68,11 +91,40 @@ impl MerkleTree {
 
         proof
     }
+
+    /// Returns one proof covering the data at every index in `indices`. Siblings that can be
+    /// recomputed from the covered leaves are left out, so this is no longer than the single
+    /// proofs combined. Nodes are listed level by level from the leaves up, left to right.
+    pub fn proof_batch(&self, indices: &[usize]) -> Vec<H256> {
+        let mut proof = Vec::new();
+        let mut known: Vec<usize> = indices.iter().copied().filter(|&i| i < self.nodes[0].len()).collect();
+        known.sort_unstable();
+        known.dedup();
+
+        for level in &self.nodes[..self.nodes.len() - 1] {
+            for &index in &known {
+                let sibling = index ^ 1;
+                // A last node without a sibling is paired with itself, so nothing is needed
+                if sibling < level.len() && known.binary_search(&sibling).is_err() {
+                    proof.push(level[sibling]);
+                }
+            }
+            known = known.iter().map(|index| index / 2).collect();
+            known.dedup();
+        }
+
+        proof
+    }
 }
 
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    verify_with(root, datum, proof, index, leaf_size, NodeHash::default())
+}
+
+/// Like `verify`, for a tree built with `MerkleTree::new_with(_, node_hash)`
+pub fn verify_with(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize, node_hash: NodeHash) -> bool {
     //unimplemented!()
     if index >= leaf_size {
         return false;
@@ -83,23 +135,71 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
     
     for sibling_hash in proof {
         if index % 2 == 0 {
-            hash = H256::from(ring::digest::digest(
-                &ring::digest::SHA256,
-                &[hash.as_ref(), sibling_hash.as_ref()].concat(),
-            ));
-
+            hash = node_hash.combine(&hash, sibling_hash);
         } else {
-            hash = H256::from(ring::digest::digest(
-                &ring::digest::SHA256,
-                &[sibling_hash.as_ref(), hash.as_ref()].concat(),
-            ));
-            
+            hash = node_hash.combine(sibling_hash, &hash);
         }
         index /= 2;
     }
     &hash == root
     
 }
+/// Verify a proof from `MerkleTree::proof_batch`: that `leaves`, the hashes of the data at the
+/// matching `indices`, all belong to the tree with `root` and `leaf_size` leaves.
+pub fn verify_batch(root: &H256, leaves: &[H256], proof: &[H256], indices: &[usize], leaf_size: usize) -> bool {
+    verify_batch_with(root, leaves, proof, indices, leaf_size, NodeHash::default())
+}
+
+/// Like `verify_batch`, for a tree built with `MerkleTree::new_with(_, node_hash)`
+pub fn verify_batch_with(
+    root: &H256,
+    leaves: &[H256],
+    proof: &[H256],
+    indices: &[usize],
+    leaf_size: usize,
+    node_hash: NodeHash,
+) -> bool {
+    if leaves.is_empty() || leaves.len() != indices.len() || indices.iter().any(|&i| i >= leaf_size) {
+        return false;
+    }
+    let mut known: Vec<(usize, H256)> = indices.iter().copied().zip(leaves.iter().copied()).collect();
+    known.sort_unstable_by_key(|(index, _)| *index);
+    known.dedup();
+    // The same index given twice must carry the same leaf
+    if known.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return false;
+    }
+
+    let mut proof = proof.iter();
+    let mut level_size = leaf_size;
+    while level_size > 1 {
+        let mut parents = Vec::with_capacity(known.len());
+        let mut nodes = known.iter().peekable();
+        while let Some(&(index, hash)) = nodes.next() {
+            let parent = if index % 2 == 1 {
+                match proof.next() {
+                    Some(left) => node_hash.combine(left, &hash),
+                    None => return false,
+                }
+            } else if index + 1 == level_size {
+                node_hash.combine(&hash, &hash)
+            } else if let Some(&(_, right)) = nodes.next_if(|(next, _)| *next == index + 1) {
+                node_hash.combine(&hash, &right)
+            } else {
+                match proof.next() {
+                    Some(right) => node_hash.combine(&hash, right),
+                    None => return false,
+                }
+            };
+            parents.push((index / 2, parent));
+        }
+        known = parents;
+        level_size = level_size.div_ceil(2);
+    }
+
+    proof.next().is_none() && known.len() == 1 && &known[0].1 == root
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -225,4 +325,102 @@ mod tests {
 
 }
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+#[cfg(test)]
+mod proof_tests {
+    use crate::types::hash::H256;
+    use rand::Rng;
+    use super::*;
+
+    #[test]
+    fn merkle_verifying_with_each_node_hash() {
+        let input_data: Vec<H256> = vec![
+            (hex!("0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d")).into(),
+            (hex!("0101010101010101010101010101010101010101010101010101010101010202")).into(),
+            (hex!("deadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeef")).into(),
+            (hex!("0000000000000000000000000000000000000000000000000000000000000001")).into(),
+        ];
+        for node_hash in [NodeHash::Sha256, NodeHash::DoubleSha256] {
+            let merkle_tree = MerkleTree::new_with(&input_data, node_hash);
+            for (index, datum) in input_data.iter().enumerate() {
+                let proof = merkle_tree.proof(index);
+                assert!(verify_with(&merkle_tree.root(), &datum.hash(), &proof, index, input_data.len(), node_hash));
+            }
+        }
+
+        // The default matches single SHA256, and the two choices give different roots
+        let single = MerkleTree::new_with(&input_data, NodeHash::Sha256).root();
+        let double = MerkleTree::new_with(&input_data, NodeHash::DoubleSha256).root();
+        assert_eq!(MerkleTree::new(&input_data).root(), single);
+        assert_ne!(single, double);
+        let proof = MerkleTree::new_with(&input_data, NodeHash::DoubleSha256).proof(0);
+        assert!(!verify(&double, &input_data[0].hash(), &proof, 0, input_data.len()));
+    }
+
+    fn numbered_leaves(count: u32) -> Vec<H256> {
+        (0..count)
+            .map(|i| {
+                let mut raw = [0u8; 32];
+                raw[..4].copy_from_slice(&i.to_be_bytes());
+                H256::from(raw)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_proof_matches_individual_proofs() {
+        use rand::seq::index::sample;
+
+        let input_data = numbered_leaves(8);
+        let merkle_tree = MerkleTree::new(&input_data);
+        let root = merkle_tree.root();
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            let count = rng.gen_range(1..=input_data.len());
+            let indices = sample(&mut rng, input_data.len(), count).into_vec();
+            let leaves: Vec<H256> = indices.iter().map(|&i| input_data[i].hash()).collect();
+
+            // Each leaf also verifies on its own, and the batch never needs more nodes
+            let mut individual = std::collections::HashSet::new();
+            for (&index, leaf) in indices.iter().zip(&leaves) {
+                let proof = merkle_tree.proof(index);
+                assert!(verify(&root, leaf, &proof, index, input_data.len()));
+                individual.extend(proof);
+            }
+            let batch = merkle_tree.proof_batch(&indices);
+            assert!(verify_batch(&root, &leaves, &batch, &indices, input_data.len()));
+            assert!(batch.len() <= individual.len());
+            assert!(batch.iter().all(|node| individual.contains(node)));
+
+            // A wrong leaf or a dropped proof node is caught
+            let mut wrong = leaves.clone();
+            wrong[0] = H256::from([0xff; 32]);
+            assert!(!verify_batch(&root, &wrong, &batch, &indices, input_data.len()));
+            if let Some((_, shorter)) = batch.split_last() {
+                assert!(!verify_batch(&root, &leaves, shorter, &indices, input_data.len()));
+            }
+        }
+
+        // Covering every leaf needs no extra nodes at all
+        let all: Vec<usize> = (0..input_data.len()).collect();
+        assert!(merkle_tree.proof_batch(&all).is_empty());
+    }
+
+    #[test]
+    fn batch_proof_with_unpaired_nodes() {
+        for size in [1, 3, 5, 6, 7] {
+            let input_data = numbered_leaves(size);
+            let leaves: Vec<H256> = input_data.iter().map(|datum| datum.hash()).collect();
+            for node_hash in [NodeHash::Sha256, NodeHash::DoubleSha256] {
+                let merkle_tree = MerkleTree::new_with(&input_data, node_hash);
+                // Every non-empty subset of leaves
+                for mask in 1u32..(1 << size) {
+                    let indices: Vec<usize> = (0..size as usize).filter(|i| mask & (1 << i) != 0).collect();
+                    let subset: Vec<H256> = indices.iter().map(|&i| leaves[i]).collect();
+                    let proof = merkle_tree.proof_batch(&indices);
+                    assert!(verify_batch_with(&merkle_tree.root(), &subset, &proof, &indices, size as usize, node_hash));
+                }
+            }
+        }
+    }
+}
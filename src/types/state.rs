@@ -8,6 +8,9 @@ use log::info;
 
 use super::transaction;
 
+/// Balance credited to the ICO account when none is configured
+pub const DEFAULT_ICO_BALANCE: u64 = 1_000_000_000;
+
 #[derive(Debug, Clone)]
 pub struct State {
     // HashMap to store account: (nonce, balance)
@@ -17,23 +20,39 @@ pub struct State {
 impl State {
     // Initialize state with an ICO (Initial Coin Offering)
     pub fn new(seed: &[u8; 32]) -> Self {
-        //Self {}
+        Self::with_ico_balance(seed, DEFAULT_ICO_BALANCE)
+    }
+
+    // Initialize state with an ICO crediting `ico_balance` to the account derived from `seed`
+    pub fn with_ico_balance(seed: &[u8; 32], ico_balance: u64) -> Self {
         let mut state = State {
             accounts: HashMap::new(),
         };
 
-        // Initial Coin Offering (ICO): Create one account with a large balance
+        // Initial Coin Offering (ICO): Create one account with the configured balance
         let keypair = Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
         let ico_address = Address::from_public_key_bytes(keypair.public_key().as_ref());
-        state.accounts.insert(ico_address, (0, 1_000_000_000)); // Nonce = 0, Balance = 1,000,000
+        state.accounts.insert(ico_address, (0, ico_balance)); // Nonce = 0
         state
     }
 
+    // Initialize state funding each listed account with its balance, at nonce 0
+    pub fn with_allocations(allocations: &[(Address, u64)]) -> Self {
+        State {
+            accounts: allocations.iter().map(|(address, balance)| (*address, (0, *balance))).collect(),
+        }
+    }
+
     pub fn is_valid_transaction(&self, tx: &SignedTransaction) -> bool {
+        // The stated sender is only debited if it owns the signing key
+        if !tx.has_valid_sender() {
+            return false;
+        }
         let sender = tx.sender_address();
 
         if let Some((nonce, balance)) = self.accounts.get(&sender) {
-            *nonce + 1 == tx.transaction.nonce && *balance >= tx.transaction.value 
+            *nonce + 1 == tx.transaction.nonce
+                && tx.transaction.value.checked_add(tx.transaction.fee).is_some_and(|cost| *balance >= cost)
         } else {
             false // Sender account not found or insufficent balance
         }
@@ -48,7 +67,7 @@ impl State {
         // Update sender account
         if let Some((nonce, balance)) = self.accounts.get_mut(&sender) {
             *nonce += 1; // Increment nonce
-            *balance -= tx.transaction.value; // Deduct value
+            *balance -= tx.transaction.value + tx.transaction.fee; // Deduct value and fee
         }
 
         // Update or create receiver account
@@ -60,9 +79,104 @@ impl State {
     }
 
 
+    /// Credit a coinbase's value to its receiver; nothing is debited, so no balance is checked
+    pub fn apply_coinbase(&mut self, tx: &SignedTransaction) {
+        self.accounts
+            .entry(tx.transaction.receiver)
+            .and_modify(|(_, balance)| *balance += tx.transaction.value)
+            .or_insert((0, tx.transaction.value));
+    }
+
+
     // Get a copy of the current state (for debugging or serialization)
     pub fn get_state_snapshot(&self) -> HashMap<Address, (u64, u64)> {
         self.accounts.clone()
     }
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_allocations_fund_every_account() {
+        let allocations = vec![
+            (Address::from([1u8; 20]), 100),
+            (Address::from([2u8; 20]), 2_000),
+            (Address::from([3u8; 20]), 30_000),
+        ];
+        let snapshot = State::with_allocations(&allocations).get_state_snapshot();
+        assert_eq!(snapshot.len(), 3);
+        for (address, balance) in allocations {
+            assert_eq!(snapshot.get(&address), Some(&(0, balance)));
+        }
+    }
+
+    #[test]
+    fn custom_ico_balance() {
+        let seed = [0u8; 32];
+        let state = State::with_ico_balance(&seed, 500);
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let ico_address = Address::from_public_key_bytes(keypair.public_key().as_ref());
+        assert_eq!(state.accounts.len(), 1);
+        assert_eq!(state.accounts.get(&ico_address), Some(&(0, 500)));
+    }
+
+    #[test]
+    fn fee_is_paid_on_top_of_value() {
+        let seed = [0u8; 32];
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let ico_address = Address::from_public_key_bytes(keypair.public_key().as_ref());
+        let receiver = Address::from([7u8; 20]);
+        let mut state = State::with_ico_balance(&seed, 100);
+
+        // Value alone fits the balance, but value plus fee does not
+        assert!(!state.is_valid_transaction(&SignedTransaction::create_with_fee(receiver, 95, 1, 10, &keypair)));
+
+        let tx = SignedTransaction::create_with_fee(receiver, 90, 1, 10, &keypair);
+        assert!(state.is_valid_transaction(&tx));
+        state.apply_transaction(&tx);
+        assert_eq!(state.accounts.get(&ico_address), Some(&(1, 0)));
+        assert_eq!(state.accounts.get(&receiver), Some(&(0, 90)));
+    }
+
+    #[test]
+    fn spending_from_an_account_needs_its_key() {
+        let seed = [0u8; 32];
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let ico_address = Address::from_public_key_bytes(keypair.public_key().as_ref());
+        let state = State::with_ico_balance(&seed, 100);
+
+        // Another key signs a transaction claiming to come from the ICO account
+        let thief = Ed25519KeyPair::from_seed_unchecked(&[1u8; 32]).unwrap();
+        let mut transaction = SignedTransaction::create(Address::from([7u8; 20]), 10, 1, &thief).transaction;
+        transaction.sender = ico_address;
+        let forged = SignedTransaction {
+            signature: crate::types::transaction::sign(&transaction, &thief).as_ref().to_vec(),
+            transaction,
+            public_key: thief.public_key().as_ref().to_vec(),
+        };
+        assert!(!state.is_valid_transaction(&forged));
+        assert!(state.is_valid_transaction(&SignedTransaction::create(Address::from([7u8; 20]), 10, 1, &keypair)));
+    }
+
+    #[test]
+    fn coinbase_mints_to_receiver() {
+        let mut state = State::with_ico_balance(&[0u8; 32], 500);
+        let miner = Address::from([7u8; 20]);
+        state.apply_coinbase(&SignedTransaction::coinbase(miner, 50, 1));
+        state.apply_coinbase(&SignedTransaction::coinbase(miner, 50, 2));
+        assert_eq!(state.accounts.get(&miner), Some(&(0, 100)));
+        // The ICO account is untouched
+        let total: u64 = state.accounts.values().map(|(_, balance)| *balance).sum();
+        assert_eq!(total, 600);
+    }
+
+    #[test]
+    fn default_ico_balance() {
+        let state = State::new(&[0u8; 32]);
+        let balances: Vec<u64> = state.accounts.values().map(|(_, balance)| *balance).collect();
+        assert_eq!(balances, vec![DEFAULT_ICO_BALANCE]);
+    }
+}
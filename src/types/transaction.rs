@@ -1,23 +1,28 @@
 use serde::{Serialize,Deserialize};
-use ring::signature::{Ed25519KeyPair, Signature, UnparsedPublicKey, ED25519, KeyPair};
 use crate::types::hash::{Hashable, H256};
 use crate::types::address::Address; // Import Address from address.rs
+use crate::types::key_pair::{Ed25519Verifier, Signature, Signer, Verifier};
+use crate::types::state::State;
 
 use rand::Rng;
+use rand::seq::IteratorRandom;
 use bincode;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
-use log::info;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info};
 
 
 // Define Transaction struct with sender, receiver, value fields
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    //pub sender: Address,
+    pub sender: Address, // Must be the address of the signing public key
     pub receiver: Address,
     pub value: u64,
+    pub fee: u64, // Paid by the sender on top of `value`, to whoever mines the transaction
     pub nonce: u64, // Used in state.rs
+    pub data: Vec<u8>, // Arbitrary payload; signed and stored on-chain, but ignored by state
 }
 
 // Define SignedTransaction struct with transaction, signature, public_key fields
@@ -29,9 +34,78 @@ pub struct SignedTransaction {
 }
 
 impl SignedTransaction {
-    // Get sender address by deriving it from the public key
+    /// Build and sign a transaction from `key`, embedding the matching public key
+    pub fn create(receiver: Address, value: u64, nonce: u64, key: &dyn Signer) -> Self {
+        Self::create_with_fee(receiver, value, nonce, 0, key)
+    }
+
+    /// Like `create`, offering `fee` to the miner
+    pub fn create_with_fee(receiver: Address, value: u64, nonce: u64, fee: u64, key: &dyn Signer) -> Self {
+        Self::sign_transaction(Transaction { sender: Address::default(), receiver, value, fee, nonce, data: vec![] }, key)
+    }
+
+    /// Like `create`, attaching `data` to the signed transaction
+    pub fn create_with_data(receiver: Address, value: u64, nonce: u64, data: Vec<u8>, key: &dyn Signer) -> Self {
+        Self::sign_transaction(Transaction { sender: Address::default(), receiver, value, fee: 0, nonce, data }, key)
+    }
+
+    /// Sign `transaction` as sent by `key`, stating the key's address as its sender
+    fn sign_transaction(mut transaction: Transaction, key: &dyn Signer) -> Self {
+        let public_key = key.public_key_bytes();
+        transaction.sender = Address::from_public_key_bytes(&public_key);
+        let signature = sign(&transaction, key);
+        SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            public_key,
+        }
+    }
+
+    /// Unsigned transaction minting `value` to `receiver`, placed first in a block to pay its
+    /// miner. The nonce is the block's height, so coinbases at different heights differ.
+    /// Nobody sends it, so its sender is the zero address.
+    pub fn coinbase(receiver: Address, value: u64, height: u64) -> Self {
+        SignedTransaction {
+            transaction: Transaction { sender: Address::default(), receiver, value, fee: 0, nonce: height, data: vec![] },
+            signature: vec![],
+            public_key: vec![],
+        }
+    }
+
+    /// Whether this is a coinbase, which has no signer
+    pub fn is_coinbase(&self) -> bool {
+        self.public_key.is_empty() && self.signature.is_empty()
+    }
+
+    /// Sender stated in the transaction, or the address of the signing key if it states none;
+    /// only trustworthy once `has_valid_sender` holds
     pub fn sender_address(&self) -> Address {
-        Address::from_public_key_bytes(&self.public_key)
+        if self.transaction.sender == Address::default() && !self.is_coinbase() {
+            return Address::from_public_key_bytes(&self.public_key);
+        }
+        self.transaction.sender
+    }
+
+    /// Whether the stated sender, if there is one, is the address of the public key that
+    /// signed the transaction
+    pub fn has_valid_sender(&self) -> bool {
+        self.transaction.sender == Address::default()
+            || self.transaction.sender == Address::from_public_key_bytes(&self.public_key)
+    }
+
+    /// Check the key and signature are Ed25519-sized and the stated sender owns the key,
+    /// before any cryptographic verification
+    pub fn check_format(&self) -> Result<(), MempoolError> {
+        if self.public_key.len() != Ed25519Verifier.public_key_len() {
+            return Err(MempoolError::MalformedPublicKey);
+        }
+        if self.signature.len() != Ed25519Verifier.signature_len() {
+            return Err(MempoolError::MalformedSignature);
+        }
+        if !self.has_valid_sender() {
+            return Err(MempoolError::SenderMismatch);
+        }
+        Ok(())
     }
 }
 
@@ -43,29 +117,28 @@ impl Hashable for SignedTransaction {
 }
 
 /// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+pub fn sign<S: Signer + ?Sized>(t: &Transaction, key: &S) -> Signature {
     //unimplemented!()
 
     // Serialize transaction using bincode
     let serialized_transaction = bincode::serialize(t).expect("Failed to serialize transaction");
 
     // Sign transaction with provided key 
-    key.sign(&serialized_transaction)
+    key.sign_message(&serialized_transaction)
 
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
-    //unimplemented!()
+    verify_with(&Ed25519Verifier, t, public_key, signature)
+}
 
+/// Like `verify`, checking the signature under `verifier`'s scheme
+pub fn verify_with<V: Verifier + ?Sized>(verifier: &V, t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     // Serialize transaction using bincode
     let serialized_transaction = bincode::serialize(t).expect("Failed to serialize transaction");
 
-    // Create public key verifier
-    let public_key = UnparsedPublicKey::new(&ED25519, public_key);
-
-    // Verify signature
-    public_key.verify(&serialized_transaction, signature).is_ok()
+    verifier.verify_message(&serialized_transaction, public_key, signature)
 }
 
 // Custom Helper Method
@@ -83,16 +156,154 @@ fn generate_random_address() -> Address {
 pub fn generate_random_transaction() -> Transaction {
     //unimplemented!()
     Transaction {
-        //sender: generate_random_address(),
+        sender: Address::default(), // Left unstated, so whichever key signs it is the sender
         receiver: generate_random_address(),
         value: rand::thread_rng().gen_range(1..1000), 
+        fee: rand::thread_rng().gen_range(0..10),
         nonce: rand::thread_rng().gen_range(1..1000),
+        data: vec![],
     }
 }
 
+/// Number of recent rejections kept for inspection
+const MAX_REJECTIONS: usize = 256;
+
+/// Number of recently dropped transactions kept for inspection
+const MAX_DROPPED: usize = 256;
+
+/// Number of pool additions and removals kept for `/mempool/changes`
+const MAX_CHANGES: usize = 1024;
+
+/// Most transactions held back waiting for an earlier nonce of their sender
+const MAX_FUTURE: usize = 1024;
+
+/// Most transactions held back for any one sender, so one sender cannot fill the buffer
+const MAX_FUTURE_PER_SENDER: usize = 16;
+
+/// Default cap on the size of a transaction's data field, in bytes
+pub const DEFAULT_MAX_DATA_LEN: usize = 256;
+
+/// Default number of confirmed transactions remembered after they leave the pool
+pub const DEFAULT_SEEN_CAPACITY: usize = 10_000;
+
+/// Why a transaction was refused by the mempool
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MempoolError {
+    Full,
+    Duplicate,
+    ReplacementFeeTooLow, // A pooled transaction from the same sender uses this nonce with at least this fee
+    InvalidSignature,
+    MalformedPublicKey, // Public key is not 32 bytes
+    MalformedSignature, // Signature is not 64 bytes
+    SenderMismatch, // The stated sender is not the address of the public key
+    PerSenderLimit, // The sender already has the most transactions one sender may pool
+    DataTooLarge, // The data field is longer than the mempool accepts
+    StaleNonce, // The tip state already has this nonce or a later one for the sender
+    NonceGap, // An earlier nonce of the sender is neither in the tip state nor pooled
+    InsufficientBalance, // The sender cannot cover this and its earlier pooled transactions
+    ZeroValue,
+    SelfSend, // The receiver is the sender
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            MempoolError::Full => "Mempool is full",
+            MempoolError::Duplicate => "Duplicate transaction",
+            MempoolError::ReplacementFeeTooLow => "Nonce already used by a pooled transaction paying at least this fee",
+            MempoolError::InvalidSignature => "Invalid Signature",
+            MempoolError::MalformedPublicKey => "Public key is not a 32-byte Ed25519 key",
+            MempoolError::MalformedSignature => "Signature is not a 64-byte Ed25519 signature",
+            MempoolError::SenderMismatch => "Sender does not match the signing public key",
+            MempoolError::PerSenderLimit => "Sender has too many pooled transactions",
+            MempoolError::DataTooLarge => "Transaction data exceeds the size limit",
+            MempoolError::StaleNonce => "Nonce is already used at the tip",
+            MempoolError::NonceGap => "An earlier nonce from this sender is missing",
+            MempoolError::InsufficientBalance => "Sender balance does not cover value and fee",
+            MempoolError::ZeroValue => "Transaction transfers no value",
+            MempoolError::SelfSend => "Transaction sends to its own sender",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// A transaction the mempool refused, and why
+#[derive(Serialize, Debug, Clone)]
+pub struct Rejection {
+    pub tx_hash: H256,
+    pub reason: MempoolError,
+    pub timestamp: u128, // Milliseconds since the UNIX epoch
+}
+
+/// Why a pooled transaction left the mempool without being confirmed
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Expired,
+    Evicted,
+    InvalidatedByReorg, // No longer valid against the state at the new tip
+    Superseded,
+}
+
+/// What the mempool does with a new transaction once it holds its maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    Reject,
+    EvictLowestFee, // Drop the lowest-fee pooled transaction if the new one pays more
+}
+
+/// A transaction that left the pool unconfirmed, and why
+#[derive(Serialize, Debug, Clone)]
+pub struct DroppedTransaction {
+    pub tx_hash: H256,
+    pub reason: DropReason,
+    pub timestamp: u128, // Milliseconds since the UNIX epoch
+}
+
+/// An addition to or removal from the pool
+enum MempoolChange {
+    Added(SignedTransaction),
+    Removed(H256),
+}
+
+/// Net pool changes after a sequence number. With `resync` set the change log no longer
+/// reaches back far enough, and `added` holds the whole pool instead.
+#[derive(Serialize, Debug)]
+pub struct MempoolChanges {
+    pub seq: u64, // Pass as `since` to fetch the next changes
+    pub resync: bool,
+    pub added: Vec<SignedTransaction>,
+    pub removed: Vec<H256>,
+}
+
+/// Size of the pool, for tuning how many transactions go into a block
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub max_size: usize,
+    pub total_bytes: u64, // Bincode-serialized size of every pooled transaction
+}
+
 pub struct Mempool {
     pool: HashMap<H256, SignedTransaction>, // Store transactions by their hash
+    added_at: HashMap<H256, Instant>, // When each pooled transaction entered the pool
+    by_sender_nonce: HashMap<(Address, u64), H256>, // Pooled transaction for each sender and nonce
+    per_sender: HashMap<Address, usize>, // Number of pooled transactions from each sender
+    max_per_sender: usize, // 0 means no limit
+    verify_signatures: bool, // Only turned off for benchmarking
+    max_data_len: usize, // Longest data field accepted, in bytes
+    reject_self_sends: bool, // Refuse transactions whose receiver is their sender
     max_size: usize, // Max number of transactions allowed 
+    eviction_policy: EvictionPolicy, // How a new transaction is handled once `max_size` is reached
+    rejections: VecDeque<Rejection>, // Most recent rejections, oldest first
+    dropped: VecDeque<DroppedTransaction>, // Most recent unconfirmed removals, oldest first
+    seen: HashMap<H256, SignedTransaction>, // Transactions learned from blocks, so peers can still fetch them
+    seen_order: VecDeque<H256>, // Insertion order of `seen`, oldest first
+    seen_capacity: usize,
+    seq: u64, // Sequence number of the latest change
+    changes: VecDeque<(u64, MempoolChange)>, // Most recent changes, oldest first
+    tip_state: Option<State>, // Latest state from `update_with_state`; admissions are checked against it
+    future: HashMap<H256, (SignedTransaction, Instant)>, // Held back until the sender's earlier nonces are pooled or applied, with when they arrived
+    future_by_sender_nonce: HashMap<(Address, u64), H256>,
 }
 
 impl Mempool {
@@ -100,45 +311,464 @@ impl Mempool {
     pub fn new(max_size: usize) -> Self {
         Self {
             pool: HashMap::new(),
+            added_at: HashMap::new(),
+            by_sender_nonce: HashMap::new(),
+            per_sender: HashMap::new(),
+            max_per_sender: 0,
+            verify_signatures: true,
+            max_data_len: DEFAULT_MAX_DATA_LEN,
+            reject_self_sends: true,
             max_size,
+            eviction_policy: EvictionPolicy::Reject,
+            rejections: VecDeque::new(),
+            dropped: VecDeque::new(),
+            seen: HashMap::new(),
+            seen_order: VecDeque::new(),
+            seen_capacity: DEFAULT_SEEN_CAPACITY,
+            seq: 0,
+            changes: VecDeque::new(),
+            tip_state: None,
+            future: HashMap::new(),
+            future_by_sender_nonce: HashMap::new(),
+        }
+
+    }
+
+    // Limit how many confirmed transactions are remembered; 0 disables the cache
+    pub fn set_seen_capacity(&mut self, capacity: usize) {
+        self.seen_capacity = capacity;
+        self.prune_seen();
+    }
+
+    // Limit how many transactions one sender may have pooled; 0 removes the limit
+    pub fn set_max_per_sender(&mut self, max_per_sender: usize) {
+        self.max_per_sender = max_per_sender;
+    }
+
+    // Limit the size of the data field a pooled transaction may carry
+    pub fn set_max_data_len(&mut self, max_data_len: usize) {
+        self.max_data_len = max_data_len;
+    }
+
+    // Whether transactions sending to their own sender are refused; they are by default
+    pub fn set_reject_self_sends(&mut self, reject_self_sends: bool) {
+        self.reject_self_sends = reject_self_sends;
+    }
+
+    // Choose what happens to new transactions once the pool is full; they are rejected by default
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    // UNSAFE: admit transactions without checking their signatures. Only for measuring
+    // throughput without crypto cost; a node doing this must not talk to real peers.
+    pub fn set_verify_signatures(&mut self, verify_signatures: bool) {
+        self.verify_signatures = verify_signatures;
+    }
+
+    // Add a transaction to the mempool if it passes validity checks. A transaction reusing a
+    // pooled sender and nonce replaces the pooled one if it pays a strictly higher fee, and
+    // under `EvictionPolicy::EvictLowestFee` a full pool makes room for a higher-fee one.
+    // One whose sender has an earlier nonce missing is held back until that nonce arrives.
+    pub fn add_transaction(&mut self, tx: SignedTransaction) -> Result<(), MempoolError> {
+        let tx_hash = tx.hash();
+        let displaced = match self.check_admission(&tx, &tx_hash) {
+            Ok(displaced) => displaced,
+            Err(MempoolError::NonceGap) if self.can_hold_back(&tx) => {
+                self.hold_back(tx_hash, tx);
+                return Ok(());
+            }
+            Err(reason) => {
+                self.record_rejection(tx_hash, reason);
+                return Err(reason);
+            }
+        };
+        let (sender, nonce) = (tx.sender_address(), tx.transaction.nonce);
+        self.insert_pooled(tx_hash, tx, displaced);
+
+        // Held-back transactions this one was the missing link for can follow it in
+        let mut next = nonce + 1;
+        while let Some(next_hash) = self.future_by_sender_nonce.get(&(sender, next)).copied() {
+            if !self.promote(next_hash) {
+                break;
+            }
+            next += 1;
         }
+        Ok(())
+    }
+
+    fn insert_pooled(&mut self, tx_hash: H256, tx: SignedTransaction, displaced: Option<(H256, DropReason)>) {
+        if let Some((displaced, reason)) = displaced {
+            self.drop_transactions(vec![displaced], reason);
+        }
+        self.by_sender_nonce.insert((tx.sender_address(), tx.transaction.nonce), tx_hash);
+        *self.per_sender.entry(tx.sender_address()).or_insert(0) += 1;
+        self.record_change(MempoolChange::Added(tx.clone()));
+        self.added_at.insert(tx_hash, Instant::now());
+        self.pool.insert(tx_hash, tx);
+    }
 
+    // Whether there is room to hold `tx` back, overall and among its sender's held-back
+    // transactions; replacing a held-back sender and nonce always is
+    fn can_hold_back(&self, tx: &SignedTransaction) -> bool {
+        let sender = tx.sender_address();
+        if self.future_by_sender_nonce.contains_key(&(sender, tx.transaction.nonce)) {
+            return true;
+        }
+        let held_by_sender = self.future_by_sender_nonce.keys().filter(|(held_sender, _)| *held_sender == sender).count();
+        self.future.len() < MAX_FUTURE && held_by_sender < MAX_FUTURE_PER_SENDER
     }
 
-    // Add a transaction to the mempool if it passes validity checks 
-    pub fn add_transaction(&mut self, tx: SignedTransaction) -> Result<(), &'static str> {
-        if self.pool.len() >= self.max_size {
-            return Err("Mempool is full");
+    // Hold a transaction back until its sender's earlier nonces arrive; a later one for the
+    // same sender and nonce takes its place
+    fn hold_back(&mut self, tx_hash: H256, tx: SignedTransaction) {
+        let key = (tx.sender_address(), tx.transaction.nonce);
+        if let Some(previous) = self.future_by_sender_nonce.insert(key, tx_hash) {
+            self.future.remove(&previous);
         }
+        debug!("Holding back transaction {} until nonce {} of its sender arrives", tx_hash, tx.transaction.nonce - 1);
+        self.future.insert(tx_hash, (tx, Instant::now()));
+    }
+
+    fn take_held_back(&mut self, tx_hash: &H256) -> Option<SignedTransaction> {
+        let (tx, _) = self.future.remove(tx_hash)?;
+        self.future_by_sender_nonce.remove(&(tx.sender_address(), tx.transaction.nonce));
+        Some(tx)
+    }
 
+    // Move a held-back transaction into the pool if it is now admissible, or discard it
+    fn promote(&mut self, tx_hash: H256) -> bool {
+        let tx = match self.take_held_back(&tx_hash) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        match self.check_admission(&tx, &tx_hash) {
+            Ok(displaced) => {
+                self.insert_pooled(tx_hash, tx, displaced);
+                true
+            }
+            Err(reason) => {
+                self.record_rejection(tx_hash, reason);
+                false
+            }
+        }
+    }
+
+    /// Move held-back transactions whose earlier nonces are now all applied in `state`, the
+    /// state at the tip, or pooled into the pool, and forget those `state` has made stale.
+    /// Returns the hashes of the transactions promoted.
+    pub fn promote_ready(&mut self, state: &State) -> Vec<H256> {
+        let mut held: Vec<(H256, Address, u64)> = self
+            .future
+            .iter()
+            .map(|(hash, (tx, _))| (*hash, tx.sender_address(), tx.transaction.nonce))
+            .collect();
+        // Lower nonces first, so a run of held-back nonces goes in within one pass
+        held.sort_by_key(|(_, _, nonce)| *nonce);
+
+        let mut promoted = vec![];
+        for (tx_hash, sender, nonce) in held {
+            let account_nonce = state.accounts.get(&sender).map(|(account_nonce, _)| *account_nonce);
+            if account_nonce.is_some_and(|account_nonce| nonce <= account_nonce) {
+                self.take_held_back(&tx_hash);
+                continue;
+            }
+            let previous = nonce - 1;
+            let follows = account_nonce == Some(previous) || self.by_sender_nonce.contains_key(&(sender, previous));
+            if follows && self.promote(tx_hash) {
+                promoted.push(tx_hash);
+            }
+        }
+        promoted
+    }
+
+    // Number of transactions held back waiting for an earlier nonce
+    pub fn held_back_count(&self) -> usize {
+        self.future.len()
+    }
+
+    // Drop transactions that have been pooled for longer than `ttl`, returning their hashes.
+    // Held-back transactions that have waited that long for their earlier nonces are forgotten too.
+    pub fn expire(&mut self, ttl: Duration) -> Vec<H256> {
+        let stale: Vec<H256> = self
+            .future
+            .iter()
+            .filter(|(_, (_, held_at))| held_at.elapsed() > ttl)
+            .map(|(tx_hash, _)| *tx_hash)
+            .collect();
+        if !stale.is_empty() {
+            debug!("Forgetting {} transaction(s) held back for over {:?}", stale.len(), ttl);
+            for tx_hash in &stale {
+                self.take_held_back(tx_hash);
+            }
+        }
+
+        let expired: Vec<H256> = self
+            .added_at
+            .iter()
+            .filter(|(_, added_at)| added_at.elapsed() > ttl)
+            .map(|(tx_hash, _)| *tx_hash)
+            .collect();
+        if !expired.is_empty() {
+            debug!("Expiring {} transaction(s) pooled for over {:?}", expired.len(), ttl);
+            self.drop_transactions(expired.clone(), DropReason::Expired);
+        }
+        expired
+    }
+
+    fn record_change(&mut self, change: MempoolChange) {
+        if self.changes.len() >= MAX_CHANGES {
+            self.changes.pop_front();
+        }
+        self.seq += 1;
+        self.changes.push_back((self.seq, change));
+    }
+
+    // Net additions and removals after sequence number `since`
+    pub fn changes_since(&self, since: u64) -> MempoolChanges {
+        let oldest = self.changes.front().map_or(self.seq + 1, |(seq, _)| *seq);
+        // A `since` ahead of us comes from before a restart, so it says nothing about this pool
+        if since.saturating_add(1) < oldest || since > self.seq {
+            return MempoolChanges {
+                seq: self.seq,
+                resync: true,
+                added: self.get_all_transactions(),
+                removed: vec![],
+            };
+        }
+
+        let mut added: Vec<SignedTransaction> = vec![];
+        let mut removed: Vec<H256> = vec![];
+        for (_, change) in self.changes.iter().filter(|(seq, _)| *seq > since) {
+            match change {
+                MempoolChange::Added(tx) => added.push(tx.clone()),
+                MempoolChange::Removed(hash) => {
+                    // A transaction added and removed within the window was never seen by the caller
+                    match added.iter().position(|tx| tx.hash() == *hash) {
+                        Some(index) => {
+                            added.remove(index);
+                        }
+                        None => removed.push(*hash),
+                    }
+                }
+            }
+        }
+        MempoolChanges { seq: self.seq, resync: false, added, removed }
+    }
+
+    /// Lowest fee paid by a pooled transaction, or None if the pool is empty
+    pub fn min_fee_in_pool(&self) -> Option<u64> {
+        self.pool.values().map(|tx| tx.transaction.fee).min()
+    }
+
+    /// The pooled transaction to evict for one paying `fee` from `sender`: the lowest-fee one
+    /// that no pooled transaction builds on, so eviction never leaves a nonce gap behind
+    fn eviction_candidate(&self, fee: u64, sender: &Address) -> Option<H256> {
+        self.pool
+            .iter()
+            .filter(|(_, pooled)| {
+                let pooled_sender = pooled.sender_address();
+                pooled_sender != *sender
+                    && pooled.transaction.fee < fee
+                    && !self.by_sender_nonce.contains_key(&(pooled_sender, pooled.transaction.nonce + 1))
+            })
+            .min_by_key(|(_, pooled)| pooled.transaction.fee)
+            .map(|(hash, _)| *hash)
+    }
+
+    /// Check `tx` may enter the pool, returning the pooled transaction it would displace and why
+    fn check_admission(&self, tx: &SignedTransaction, tx_hash: &H256) -> Result<Option<(H256, DropReason)>, MempoolError> {
         // Ensure transaction is not already in mempool
-        let tx_hash = tx.hash();
+        if self.pool.contains_key(tx_hash) || self.future.contains_key(tx_hash) {
+            return Err(MempoolError::Duplicate);
+        }
 
-        if self.pool.contains_key(&tx_hash) {
-            return Err("Duplicate transaction");
+        let replaced = self.by_sender_nonce.get(&(tx.sender_address(), tx.transaction.nonce)).copied();
+        if let Some(pooled) = replaced {
+            if tx.transaction.fee <= self.pool[&pooled].transaction.fee {
+                return Err(MempoolError::ReplacementFeeTooLow);
+            }
+        }
+
+        // A replacement takes the old transaction's place, so it cannot overflow either limit
+        let mut displaced = replaced.map(|hash| (hash, DropReason::Superseded));
+        if replaced.is_none() {
+            if self.pool.len() >= self.max_size {
+                let evicted = match self.eviction_policy {
+                    EvictionPolicy::Reject => None,
+                    EvictionPolicy::EvictLowestFee => self.eviction_candidate(tx.transaction.fee, &tx.sender_address()),
+                };
+                displaced = Some((evicted.ok_or(MempoolError::Full)?, DropReason::Evicted));
+            }
+            let pooled_by_sender = self.per_sender.get(&tx.sender_address()).copied().unwrap_or(0);
+            if self.max_per_sender > 0 && pooled_by_sender >= self.max_per_sender {
+                return Err(MempoolError::PerSenderLimit);
+            }
+        }
+
+        tx.check_format()?;
+
+        // Transfers that move nothing only take up space in the pool and in blocks
+        if tx.transaction.value == 0 {
+            return Err(MempoolError::ZeroValue);
+        }
+        if self.reject_self_sends && tx.transaction.receiver == tx.sender_address() {
+            return Err(MempoolError::SelfSend);
+        }
+
+        if tx.transaction.data.len() > self.max_data_len {
+            return Err(MempoolError::DataTooLarge);
         }
 
         // Verify signature 
-        if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
-            return Err("Invalid Signature");
+        if self.verify_signatures && !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+            return Err(MempoolError::InvalidSignature);
         }
-        
-        // Add transaction to the mempool
-        self.pool.insert(tx_hash, tx);
-        Ok(())
+
+        if let Some(state) = &self.tip_state {
+            self.check_against_state(tx, state)?;
+        }
+        Ok(displaced)
+    }
+
+    /// Check `tx` can follow the sender's pooled transactions on top of `state`: its nonce is
+    /// not yet used, every earlier nonce is pooled, and the balance covers all of them
+    fn check_against_state(&self, tx: &SignedTransaction, state: &State) -> Result<(), MempoolError> {
+        let sender = tx.sender_address();
+        let (account_nonce, balance) = *state.accounts.get(&sender).ok_or(MempoolError::InsufficientBalance)?;
+        if tx.transaction.nonce <= account_nonce {
+            return Err(MempoolError::StaleNonce);
+        }
+        let mut cost = Some(0u64);
+        for nonce in account_nonce + 1..tx.transaction.nonce {
+            let pending = self.by_sender_nonce.get(&(sender, nonce)).ok_or(MempoolError::NonceGap)?;
+            let pending = &self.pool[pending].transaction;
+            cost = cost.and_then(|cost| cost.checked_add(pending.value)?.checked_add(pending.fee));
+        }
+        cost = cost.and_then(|cost| cost.checked_add(tx.transaction.value)?.checked_add(tx.transaction.fee));
+        match cost {
+            Some(cost) if cost <= balance => Ok(()),
+            _ => Err(MempoolError::InsufficientBalance),
+        }
+    }
+
+    fn record_rejection(&mut self, tx_hash: H256, reason: MempoolError) {
+        if self.rejections.len() >= MAX_REJECTIONS {
+            self.rejections.pop_front();
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        self.rejections.push_back(Rejection { tx_hash, reason, timestamp });
+    }
+
+    // Number of pooled transactions, the pool's limit, and their serialized size
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            count: self.pool.len(),
+            max_size: self.max_size,
+            total_bytes: self
+                .pool
+                .values()
+                .map(|tx| bincode::serialized_size(tx).expect("Serialization should not fail"))
+                .sum(),
+        }
+    }
+
+    // Recent rejections, oldest first
+    pub fn recent_rejections(&self) -> Vec<Rejection> {
+        self.rejections.iter().cloned().collect()
+    }
+
+    // Remove pooled transactions that will not be confirmed, remembering why
+    fn drop_transactions(&mut self, tx_hashes: Vec<H256>, reason: DropReason) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        for tx_hash in &tx_hashes {
+            if !self.pool.contains_key(tx_hash) {
+                continue;
+            }
+            if self.dropped.len() >= MAX_DROPPED {
+                self.dropped.pop_front();
+            }
+            self.dropped.push_back(DroppedTransaction { tx_hash: *tx_hash, reason, timestamp });
+        }
+        self.remove_transactions(tx_hashes);
+    }
+
+    // Up to `limit` most recently dropped transactions, newest first
+    pub fn recent_drops(&self, limit: usize) -> Vec<DroppedTransaction> {
+        self.dropped.iter().rev().take(limit).cloned().collect()
     }
 
     // Remove transactions from the mempool that are already in a block
     pub fn remove_transactions(&mut self, tx_hashes: Vec<H256>) {
         for hash in tx_hashes {
-            self.pool.remove(&hash);
+            if let Some(tx) = self.pool.remove(&hash) {
+                self.added_at.remove(&hash);
+                let sender = tx.sender_address();
+                self.by_sender_nonce.remove(&(sender, tx.transaction.nonce));
+                if let Some(count) = self.per_sender.get_mut(&sender) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.per_sender.remove(&sender);
+                    }
+                }
+                self.record_change(MempoolChange::Removed(hash));
+            }
         }
 
     }
 
-    // Get all transactions for block mining up to the limit
+    // Remove a block's transactions from the pool, remembering them so they can still be served
+    pub fn confirm_transactions(&mut self, transactions: &[SignedTransaction]) {
+        let tx_hashes: Vec<H256> = transactions.iter().map(|tx| tx.hash()).collect();
+        self.remove_transactions(tx_hashes.clone());
+        if self.seen_capacity == 0 {
+            return;
+        }
+        for (hash, tx) in tx_hashes.into_iter().zip(transactions) {
+            if self.seen.insert(hash, tx.clone()).is_none() {
+                self.seen_order.push_back(hash);
+            }
+        }
+        self.prune_seen();
+    }
+
+    fn prune_seen(&mut self) {
+        while self.seen_order.len() > self.seen_capacity {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+
+    // Look a transaction up in the pool, then among held-back and recently confirmed ones
+    pub fn lookup(&self, tx_hash: &H256) -> Option<SignedTransaction> {
+        self.pool
+            .get(tx_hash)
+            .or_else(|| self.future.get(tx_hash).map(|(tx, _)| tx))
+            .or_else(|| self.seen.get(tx_hash))
+            .cloned()
+    }
+
+    /// Up to `limit` pooled transactions, highest fee first; equal fees go in nonce order
     pub fn get_transactions_for_block(&self, limit: usize) -> Vec<SignedTransaction> {
-        self.pool.values().cloned().take(limit).collect()
+        let mut transactions: Vec<&SignedTransaction> = self.pool.values().collect();
+        transactions.sort_by(|a, b| {
+            b.transaction.fee
+                .cmp(&a.transaction.fee)
+                .then(a.transaction.nonce.cmp(&b.transaction.nonce))
+        });
+        transactions.into_iter().take(limit).cloned().collect()
+    }
+
+    // Pick up to `count` pooled transaction hashes at random
+    pub fn sample_hashes(&self, count: usize) -> Vec<H256> {
+        self.pool.keys().copied().choose_multiple(&mut rand::thread_rng(), count)
     }
 
     pub fn contains_transactions(&self, tx_hash: &H256) -> bool {
@@ -153,14 +783,36 @@ impl Mempool {
         self.pool.values().cloned().collect()
     }
 
-    pub fn update_with_state(&mut self, state: &crate::types::state::State) {
+    /// Adopt `state`, the state at a new tip, for checking admissions, and drop pooled
+    /// transactions that can no longer be mined on top of it
+    pub fn update_with_state(&mut self, state: &State) {
         let invalid_tx_hashes: Vec<H256> = self
             .pool
-            .values()
-            .filter(|tx|!state.is_valid_transaction(tx))
-            .map(|tx|tx.hash())
+            .iter()
+            .filter(|(_, tx)| self.check_against_state(tx, state).is_err())
+            .map(|(hash, _)| *hash)
             .collect();
-        self.remove_transactions(invalid_tx_hashes);
+        self.drop_transactions(invalid_tx_hashes, DropReason::InvalidatedByReorg);
+        self.tip_state = Some(state.clone());
+        self.promote_ready(state);
+    }
+
+    /// Follow the longest chain onto a new tip with `state`: confirm the `adopted` transactions,
+    /// prune what the tip no longer accepts, and return `abandoned` ones to the pool unless the
+    /// new chain confirms them too. Abandoned transactions should be in chain order, so each
+    /// sender's nonces arrive without gaps.
+    pub fn reorganize(&mut self, abandoned: Vec<SignedTransaction>, adopted: &[SignedTransaction], state: &State) {
+        self.confirm_transactions(adopted);
+        self.update_with_state(state);
+        let adopted: HashSet<H256> = adopted.iter().map(|tx| tx.hash()).collect();
+        for tx in abandoned {
+            if tx.is_coinbase() || adopted.contains(&tx.hash()) {
+                continue;
+            }
+            if let Err(reason) = self.add_transaction(tx) {
+                debug!("Transaction from an abandoned block not returned to the pool: {}", reason);
+            }
+        }
     }
     
 }
@@ -221,4 +873,534 @@ mod tests {
 
 }
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+#[cfg(test)]
+mod mempool_tests {
+    use super::*;
+    use crate::types::key_pair;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed_by(key: &Ed25519KeyPair, transaction: Transaction) -> SignedTransaction {
+        SignedTransaction::create_with_fee(transaction.receiver, transaction.value, transaction.nonce, transaction.fee, key)
+    }
+
+    #[test]
+    fn create_signs_with_embedded_key() {
+        let key = key_pair::random();
+        let receiver = generate_random_address();
+        let tx = SignedTransaction::create(receiver, 7, 3, &key);
+        assert_eq!(tx.transaction.receiver, receiver);
+        assert_eq!(tx.transaction.value, 7);
+        assert_eq!(tx.transaction.nonce, 3);
+        assert_eq!(tx.sender_address(), Address::from_public_key_bytes(key.public_key().as_ref()));
+        assert!(verify(&tx.transaction, &tx.public_key, &tx.signature));
+    }
+
+    #[test]
+    fn sender_must_own_the_signing_key() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let tx = signed_by(&key, generate_random_transaction());
+        assert!(tx.has_valid_sender());
+        assert_eq!(tx.check_format(), Ok(()));
+
+        // Claim someone else sent it and re-sign, so only the sender check can catch it
+        let mut transaction = tx.transaction.clone();
+        transaction.sender = generate_random_address();
+        let impostor = SignedTransaction {
+            signature: sign(&transaction, &key).as_ref().to_vec(),
+            transaction,
+            public_key: key.public_key().as_ref().to_vec(),
+        };
+        assert!(verify(&impostor.transaction, &impostor.public_key, &impostor.signature));
+        assert!(!impostor.has_valid_sender());
+        assert_eq!(mempool.add_transaction(impostor), Err(MempoolError::SenderMismatch));
+        assert_eq!(mempool.add_transaction(tx), Ok(()));
+
+        // A transaction stating no sender is sent by whichever key signed it
+        let transaction = generate_random_transaction();
+        let unstated = SignedTransaction {
+            signature: sign(&transaction, &key).as_ref().to_vec(),
+            transaction,
+            public_key: key.public_key().as_ref().to_vec(),
+        };
+        assert!(unstated.has_valid_sender());
+        assert_eq!(unstated.sender_address(), Address::from_public_key_bytes(key.public_key().as_ref()));
+    }
+
+    #[test]
+    fn rejections_are_recorded_with_reason() {
+        let mut mempool = Mempool::new(2);
+        let key = key_pair::random();
+        let tx = signed_by(&key, generate_random_transaction());
+        assert!(mempool.add_transaction(tx.clone()).is_ok());
+
+        // Same transaction again
+        assert_eq!(mempool.add_transaction(tx.clone()), Err(MempoolError::Duplicate));
+
+        // Different transaction reusing the pooled nonce without raising the fee
+        let mut conflicting = generate_random_transaction();
+        conflicting.nonce = tx.transaction.nonce;
+        conflicting.value = tx.transaction.value + 1;
+        conflicting.fee = tx.transaction.fee;
+        let conflicting = signed_by(&key, conflicting);
+        assert_eq!(mempool.add_transaction(conflicting.clone()), Err(MempoolError::ReplacementFeeTooLow));
+
+        // Signature that doesn't match the transaction
+        let mut forged = signed_by(&key_pair::random(), generate_random_transaction());
+        forged.transaction.value += 1;
+        assert_eq!(mempool.add_transaction(forged.clone()), Err(MempoolError::InvalidSignature));
+
+        let rejections = mempool.recent_rejections();
+        let reasons: Vec<(H256, MempoolError)> = rejections.iter().map(|r| (r.tx_hash, r.reason)).collect();
+        assert_eq!(reasons, vec![
+            (tx.hash(), MempoolError::Duplicate),
+            (conflicting.hash(), MempoolError::ReplacementFeeTooLow),
+            (forged.hash(), MempoolError::InvalidSignature),
+        ]);
+        assert!(rejections.iter().all(|r| r.timestamp > 0));
+    }
+
+    #[test]
+    fn confirmed_transactions_stay_retrievable() {
+        let mut mempool = Mempool::new(10);
+        mempool.set_seen_capacity(2);
+        let key = key_pair::random();
+        let txs: Vec<SignedTransaction> = (0..3).map(|_| signed_by(&key, generate_random_transaction())).collect();
+        assert!(mempool.add_transaction(txs[0].clone()).is_ok());
+
+        mempool.confirm_transactions(&txs);
+        assert!(!mempool.contains_transactions(&txs[0].hash()));
+        // Only the two most recent confirmations are remembered
+        assert!(mempool.lookup(&txs[0].hash()).is_none());
+        assert_eq!(mempool.lookup(&txs[1].hash()).unwrap().hash(), txs[1].hash());
+        assert_eq!(mempool.lookup(&txs[2].hash()).unwrap().hash(), txs[2].hash());
+    }
+
+    #[test]
+    fn stats_count_pooled_transactions_and_bytes() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        assert_eq!(mempool.stats(), MempoolStats { count: 0, max_size: 10, total_bytes: 0 });
+
+        let mut last_bytes = 0;
+        for nonce in 1..=3 {
+            mempool.add_transaction(SignedTransaction::create(generate_random_address(), 10, nonce, &key)).unwrap();
+            let stats = mempool.stats();
+            assert_eq!(stats.count, nonce as usize);
+            assert!(stats.total_bytes > last_bytes);
+            last_bytes = stats.total_bytes;
+        }
+    }
+
+    #[test]
+    fn rejection_log_is_bounded() {
+        let mut mempool = Mempool::new(0);
+        let key = key_pair::random();
+        for _ in 0..MAX_REJECTIONS + 10 {
+            let tx = signed_by(&key, generate_random_transaction());
+            assert_eq!(mempool.add_transaction(tx), Err(MempoolError::Full));
+        }
+        assert_eq!(mempool.recent_rejections().len(), MAX_REJECTIONS);
+    }
+
+    #[test]
+    fn queued_nonces_survive_a_new_tip() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([1u8; 20]);
+        let txs: Vec<SignedTransaction> = (1..=3).map(|nonce| SignedTransaction::create(receiver, 10, nonce, &key)).collect();
+        for tx in &txs {
+            mempool.add_transaction(tx.clone()).unwrap();
+        }
+
+        // A block confirms the first; the pool still holds the rest, which follow on from it
+        let mut state = State { accounts: HashMap::new() };
+        state.accounts.insert(sender, (1, 90));
+        mempool.confirm_transactions(&txs[..1]);
+        mempool.update_with_state(&state);
+        assert!(mempool.recent_drops(10).is_empty());
+        assert_eq!(mempool.get_all_transactions().len(), 2);
+    }
+
+    #[test]
+    fn invalidated_transactions_are_reported_as_dropped() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let next = SignedTransaction::create(Address::from([1u8; 20]), 1, 1, &key);
+        let gapped = SignedTransaction::create(Address::from([1u8; 20]), 1, 3, &key);
+        mempool.add_transaction(next.clone()).unwrap();
+        mempool.add_transaction(gapped.clone()).unwrap();
+
+        let mut state = State { accounts: HashMap::new() };
+        state.accounts.insert(sender, (0, 10));
+        mempool.update_with_state(&state);
+
+        let dropped = mempool.recent_drops(10);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].tx_hash, gapped.hash());
+        assert_eq!(dropped[0].reason, DropReason::InvalidatedByReorg);
+        assert!(mempool.contains_transactions(&next.hash()));
+
+        // Confirmed transactions are not drops
+        mempool.confirm_transactions(std::slice::from_ref(&next));
+        assert_eq!(mempool.recent_drops(10).len(), 1);
+    }
+
+    #[test]
+    fn per_sender_cap_leaves_room_for_others() {
+        let mut mempool = Mempool::new(10);
+        mempool.set_max_per_sender(2);
+        let busy = key_pair::random();
+        let other = key_pair::random();
+        let receiver = Address::from([1u8; 20]);
+
+        let first = SignedTransaction::create(receiver, 1, 1, &busy);
+        mempool.add_transaction(first.clone()).unwrap();
+        mempool.add_transaction(SignedTransaction::create(receiver, 1, 2, &busy)).unwrap();
+        let third = SignedTransaction::create(receiver, 1, 3, &busy);
+        assert_eq!(mempool.add_transaction(third.clone()), Err(MempoolError::PerSenderLimit));
+        mempool.add_transaction(SignedTransaction::create(receiver, 1, 1, &other)).unwrap();
+
+        // Confirming one of the busy sender's transactions frees a slot
+        mempool.confirm_transactions(std::slice::from_ref(&first));
+        mempool.add_transaction(third).unwrap();
+    }
+
+    #[test]
+    fn admission_checks_the_tip_state() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([1u8; 20]);
+        let mut state = State { accounts: HashMap::new() };
+        state.accounts.insert(sender, (2, 100));
+        mempool.update_with_state(&state);
+
+        // Nonces 1 and 2 are already used at the tip
+        let stale = SignedTransaction::create(receiver, 1, 2, &key);
+        assert_eq!(mempool.add_transaction(stale), Err(MempoolError::StaleNonce));
+
+        // Value plus fee beyond the balance, alone or together with earlier pooled transactions
+        let overspend = SignedTransaction::create_with_fee(receiver, 95, 3, 10, &key);
+        assert_eq!(mempool.add_transaction(overspend), Err(MempoolError::InsufficientBalance));
+        mempool.add_transaction(SignedTransaction::create_with_fee(receiver, 60, 3, 5, &key)).unwrap();
+        let second = SignedTransaction::create_with_fee(receiver, 40, 4, 1, &key);
+        assert_eq!(mempool.add_transaction(second), Err(MempoolError::InsufficientBalance));
+        mempool.add_transaction(SignedTransaction::create(receiver, 35, 4, &key)).unwrap();
+
+        // Skipping a nonce holds the transaction back; an account the tip does not know is refused
+        let gapped = SignedTransaction::create(receiver, 1, 6, &key);
+        mempool.add_transaction(gapped.clone()).unwrap();
+        assert!(!mempool.contains_transactions(&gapped.hash()));
+        assert_eq!(mempool.held_back_count(), 1);
+        let unknown = SignedTransaction::create(receiver, 1, 1, &key_pair::random());
+        assert_eq!(mempool.add_transaction(unknown), Err(MempoolError::InsufficientBalance));
+        assert_eq!(mempool.get_all_transactions().len(), 2);
+    }
+
+    #[test]
+    fn held_back_nonce_follows_once_the_gap_is_filled() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([1u8; 20]);
+        let mut state = State { accounts: HashMap::new() };
+        state.accounts.insert(sender, (0, 100));
+        mempool.update_with_state(&state);
+
+        // Nonce 2 arrives first and waits outside the pool, so it is not mined
+        let first = SignedTransaction::create(receiver, 10, 1, &key);
+        let second = SignedTransaction::create(receiver, 10, 2, &key);
+        mempool.add_transaction(second.clone()).unwrap();
+        assert!(!mempool.contains_transactions(&second.hash()));
+        assert!(mempool.get_transactions_for_block(10).is_empty());
+        assert_eq!(mempool.add_transaction(second.clone()), Err(MempoolError::Duplicate));
+
+        // A block applies nonce 1, after which nonce 2 can be spent
+        state.apply_transaction(&first);
+        mempool.confirm_transactions(std::slice::from_ref(&first));
+        mempool.update_with_state(&state);
+        assert!(mempool.contains_transactions(&second.hash()));
+        assert_eq!(mempool.held_back_count(), 0);
+        assert!(state.is_valid_transaction(&second));
+
+        // Nonce 1 arriving through the pool also lets a held-back nonce 2 in
+        let mut mempool = Mempool::new(10);
+        let mut state = State { accounts: HashMap::new() };
+        state.accounts.insert(sender, (0, 100));
+        mempool.update_with_state(&state);
+        mempool.add_transaction(second.clone()).unwrap();
+        mempool.add_transaction(first.clone()).unwrap();
+        assert_eq!(mempool.get_transactions_for_block(10).len(), 2);
+
+        // Held-back transactions the tip has already used are forgotten
+        let mut mempool = Mempool::new(10);
+        state.accounts.insert(sender, (0, 100));
+        mempool.update_with_state(&state);
+        mempool.add_transaction(second.clone()).unwrap();
+        state.accounts.insert(sender, (2, 80));
+        assert!(mempool.promote_ready(&state).is_empty());
+        assert_eq!(mempool.held_back_count(), 0);
+    }
+
+    #[test]
+    fn degenerate_transfers_are_refused() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+
+        let zero_value = SignedTransaction::create_with_fee(Address::from([1u8; 20]), 0, 1, 5, &key);
+        assert_eq!(mempool.add_transaction(zero_value), Err(MempoolError::ZeroValue));
+        let self_send = SignedTransaction::create(sender, 10, 2, &key);
+        assert_eq!(mempool.add_transaction(self_send.clone()), Err(MempoolError::SelfSend));
+        assert_ne!(MempoolError::ZeroValue.to_string(), MempoolError::SelfSend.to_string());
+
+        let normal = SignedTransaction::create(Address::from([1u8; 20]), 10, 1, &key);
+        assert!(mempool.add_transaction(normal).is_ok());
+
+        // Self-sends may be allowed, zero-value transfers may not
+        mempool.set_reject_self_sends(false);
+        assert!(mempool.add_transaction(self_send).is_ok());
+        let zero_self_send = SignedTransaction::create(sender, 0, 3, &key);
+        assert_eq!(mempool.add_transaction(zero_self_send), Err(MempoolError::ZeroValue));
+    }
+
+    #[test]
+    fn transactions_pooled_past_the_ttl_expire() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let receiver = Address::from([1u8; 20]);
+        let old = SignedTransaction::create(receiver, 10, 1, &key);
+        mempool.add_transaction(old.clone()).unwrap();
+        let ttl = Duration::from_millis(50);
+        assert!(mempool.expire(ttl).is_empty());
+
+        std::thread::sleep(ttl * 2);
+        let fresh = SignedTransaction::create(receiver, 10, 2, &key);
+        mempool.add_transaction(fresh.clone()).unwrap();
+        assert_eq!(mempool.expire(ttl), vec![old.hash()]);
+        assert!(!mempool.contains_transactions(&old.hash()));
+        assert!(mempool.contains_transactions(&fresh.hash()));
+        let drops = mempool.recent_drops(1);
+        assert_eq!((drops[0].tx_hash, drops[0].reason), (old.hash(), DropReason::Expired));
+    }
+
+    #[test]
+    fn held_back_transactions_are_capped_per_sender_and_expire() {
+        let mut mempool = Mempool::new(10);
+        let (key, other) = (key_pair::random(), key_pair::random());
+        let receiver = Address::from([1u8; 20]);
+        let mut state = State { accounts: HashMap::new() };
+        for k in [&key, &other] {
+            state.accounts.insert(Address::from_public_key_bytes(k.public_key().as_ref()), (0, 1000));
+        }
+        mempool.update_with_state(&state);
+
+        // Nonce 1 never arrives, so everything after it waits
+        for nonce in 2..2 + MAX_FUTURE_PER_SENDER as u64 {
+            mempool.add_transaction(SignedTransaction::create(receiver, 1, nonce, &key)).unwrap();
+        }
+        let one_too_many = SignedTransaction::create(receiver, 1, 2 + MAX_FUTURE_PER_SENDER as u64, &key);
+        assert_eq!(mempool.add_transaction(one_too_many), Err(MempoolError::NonceGap));
+        mempool.add_transaction(SignedTransaction::create_with_fee(receiver, 1, 2, 5, &key)).unwrap();
+        mempool.add_transaction(SignedTransaction::create(receiver, 1, 2, &other)).unwrap();
+        assert_eq!(mempool.held_back_count(), MAX_FUTURE_PER_SENDER + 1);
+
+        let ttl = Duration::from_millis(50);
+        assert!(mempool.expire(ttl).is_empty());
+        assert_eq!(mempool.held_back_count(), MAX_FUTURE_PER_SENDER + 1);
+        std::thread::sleep(ttl * 2);
+        assert!(mempool.expire(ttl).is_empty());
+        assert_eq!(mempool.held_back_count(), 0);
+    }
+
+    #[test]
+    fn full_pool_evicts_its_lowest_fee_transaction() {
+        let mut mempool = Mempool::new(3);
+        mempool.set_eviction_policy(EvictionPolicy::EvictLowestFee);
+        let (queued, other, newcomer) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let receiver = Address::from([1u8; 20]);
+        let first = SignedTransaction::create_with_fee(receiver, 10, 1, 1, &queued);
+        let second = SignedTransaction::create_with_fee(receiver, 10, 2, 2, &queued);
+        for tx in [first.clone(), second.clone(), SignedTransaction::create_with_fee(receiver, 10, 1, 5, &other)] {
+            mempool.add_transaction(tx).unwrap();
+        }
+        assert_eq!(mempool.min_fee_in_pool(), Some(1));
+
+        // The cheapest transaction has a successor, so its successor goes instead of leaving a gap
+        let paying = SignedTransaction::create_with_fee(receiver, 10, 1, 3, &newcomer);
+        mempool.add_transaction(paying.clone()).unwrap();
+        assert!(mempool.contains_transactions(&paying.hash()));
+        assert!(mempool.contains_transactions(&first.hash()));
+        assert!(!mempool.contains_transactions(&second.hash()));
+        assert_eq!(mempool.stats().count, 3);
+        let drops = mempool.recent_drops(1);
+        assert_eq!((drops[0].tx_hash, drops[0].reason), (second.hash(), DropReason::Evicted));
+    }
+
+    #[test]
+    fn full_pool_rejects_transactions_paying_no_more() {
+        let receiver = Address::from([1u8; 20]);
+        let pooled = SignedTransaction::create_with_fee(receiver, 10, 1, 3, &key_pair::random());
+        let cheap = SignedTransaction::create_with_fee(receiver, 10, 1, 3, &key_pair::random());
+        let generous = SignedTransaction::create_with_fee(receiver, 10, 1, 9, &key_pair::random());
+
+        let mut mempool = Mempool::new(1);
+        mempool.set_eviction_policy(EvictionPolicy::EvictLowestFee);
+        mempool.add_transaction(pooled.clone()).unwrap();
+        assert_eq!(mempool.add_transaction(cheap), Err(MempoolError::Full));
+        assert_eq!(mempool.min_fee_in_pool(), Some(3));
+
+        // Without eviction a full pool refuses even a higher fee
+        let mut mempool = Mempool::new(1);
+        mempool.add_transaction(pooled.clone()).unwrap();
+        assert_eq!(mempool.add_transaction(generous), Err(MempoolError::Full));
+        assert!(mempool.contains_transactions(&pooled.hash()));
+        assert_eq!(Mempool::new(1).min_fee_in_pool(), None);
+    }
+
+    #[test]
+    fn higher_fee_replaces_pooled_transaction() {
+        let mut mempool = Mempool::new(2);
+        let key = key_pair::random();
+        let receiver = Address::from([1u8; 20]);
+        let original = SignedTransaction::create_with_fee(receiver, 10, 1, 2, &key);
+        mempool.add_transaction(original.clone()).unwrap();
+        mempool.add_transaction(SignedTransaction::create_with_fee(receiver, 10, 2, 2, &key)).unwrap();
+
+        // The pool is full, but a replacement only takes the old transaction's place
+        let bumped = SignedTransaction::create_with_fee(receiver, 10, 1, 3, &key);
+        mempool.add_transaction(bumped.clone()).unwrap();
+        assert!(!mempool.contains_transactions(&original.hash()));
+        assert!(mempool.contains_transactions(&bumped.hash()));
+        assert_eq!(mempool.get_all_transactions().len(), 2);
+        let drops = mempool.recent_drops(1);
+        assert_eq!((drops[0].tx_hash, drops[0].reason), (original.hash(), DropReason::Superseded));
+
+        // Matching or lowering the fee is refused and leaves the pooled transaction alone
+        for fee in [3, 1] {
+            let underpriced = SignedTransaction::create_with_fee(receiver, 11, 1, fee, &key);
+            assert_eq!(mempool.add_transaction(underpriced), Err(MempoolError::ReplacementFeeTooLow));
+        }
+        assert!(mempool.contains_transactions(&bumped.hash()));
+    }
+
+    #[test]
+    fn new_nonce_does_not_replace() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let receiver = Address::from([1u8; 20]);
+        let first = SignedTransaction::create_with_fee(receiver, 10, 1, 1, &key);
+        let second = SignedTransaction::create_with_fee(receiver, 10, 2, 5, &key);
+        mempool.add_transaction(first.clone()).unwrap();
+        mempool.add_transaction(second.clone()).unwrap();
+
+        assert!(mempool.contains_transactions(&first.hash()));
+        assert!(mempool.contains_transactions(&second.hash()));
+        assert!(mempool.recent_drops(10).is_empty());
+    }
+
+    #[test]
+    fn block_selection_takes_highest_fees_first() {
+        let mut mempool = Mempool::new(10);
+        let receiver = Address::from([1u8; 20]);
+        for fee in [3, 10, 0, 7, 1] {
+            mempool.add_transaction(SignedTransaction::create_with_fee(receiver, 1, 1, fee, &key_pair::random())).unwrap();
+        }
+
+        let fees = |txs: Vec<SignedTransaction>| -> Vec<u64> { txs.iter().map(|tx| tx.transaction.fee).collect() };
+        assert_eq!(fees(mempool.get_transactions_for_block(10)), vec![10, 7, 3, 1, 0]);
+        assert_eq!(fees(mempool.get_transactions_for_block(3)), vec![10, 7, 3]);
+    }
+
+    #[test]
+    fn transaction_data_is_signed() {
+        let key = key_pair::random();
+        let receiver = Address::from([1u8; 20]);
+        let tx = SignedTransaction::create_with_data(receiver, 1, 1, b"run 42".to_vec(), &key);
+        assert!(verify(&tx.transaction, &tx.public_key, &tx.signature));
+
+        let mut tampered = tx.clone();
+        tampered.transaction.data = b"run 43".to_vec();
+        assert!(!verify(&tampered.transaction, &tampered.public_key, &tampered.signature));
+
+        let mut mempool = Mempool::new(10);
+        mempool.set_max_data_len(6);
+        mempool.add_transaction(tx).unwrap();
+        let too_large = SignedTransaction::create_with_data(receiver, 1, 2, vec![0u8; 7], &key);
+        assert_eq!(mempool.add_transaction(too_large), Err(MempoolError::DataTooLarge));
+    }
+
+    #[test]
+    fn skipping_signature_verification_is_opt_in() {
+        let key = key_pair::random();
+        let mut forged = SignedTransaction::create(Address::from([1u8; 20]), 1, 1, &key);
+        forged.signature = vec![0u8; 64];
+
+        let mut mempool = Mempool::new(10);
+        assert_eq!(mempool.add_transaction(forged.clone()), Err(MempoolError::InvalidSignature));
+
+        mempool.set_verify_signatures(false);
+        mempool.add_transaction(forged).unwrap();
+    }
+
+    #[test]
+    fn malformed_key_and_signature_are_rejected() {
+        let mut mempool = Mempool::new(10);
+        let key = key_pair::random();
+        let tx = SignedTransaction::create(Address::from([1u8; 20]), 1, 1, &key);
+
+        let mut short_key = tx.clone();
+        short_key.public_key.truncate(5);
+        assert_eq!(mempool.add_transaction(short_key), Err(MempoolError::MalformedPublicKey));
+
+        let mut short_signature = tx.clone();
+        short_signature.signature.truncate(63);
+        assert_eq!(mempool.add_transaction(short_signature), Err(MempoolError::MalformedSignature));
+
+        assert!(mempool.add_transaction(tx).is_ok());
+    }
+
+    #[test]
+    fn changes_since_reports_net_diff() {
+        let mut mempool = Mempool::new(MAX_CHANGES * 2);
+        let key = key_pair::random();
+        let txs: Vec<SignedTransaction> = (0..4).map(|nonce| SignedTransaction::create(Address::from([1u8; 20]), 1, nonce, &key)).collect();
+        let hashes: Vec<H256> = txs.iter().map(|tx| tx.hash()).collect();
+        mempool.add_transaction(txs[0].clone()).unwrap();
+        mempool.add_transaction(txs[1].clone()).unwrap();
+        let start = mempool.changes_since(0).seq;
+        assert_eq!(start, 2);
+
+        mempool.add_transaction(txs[2].clone()).unwrap();
+        mempool.add_transaction(txs[3].clone()).unwrap();
+        mempool.remove_transactions(vec![hashes[0], hashes[3]]);
+
+        let changes = mempool.changes_since(start);
+        assert!(!changes.resync);
+        assert_eq!(changes.seq, 6);
+        let added: Vec<H256> = changes.added.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(added, vec![hashes[2]]);
+        assert_eq!(changes.removed, vec![hashes[0]]);
+        assert!(mempool.changes_since(changes.seq).added.is_empty());
+
+        // Once the log has moved past `since`, the whole pool is returned
+        for nonce in 0..MAX_CHANGES as u64 {
+            let tx = SignedTransaction::create(Address::from([1u8; 20]), 1, 1000 + nonce, &key);
+            mempool.add_transaction(tx).unwrap();
+        }
+        let changes = mempool.changes_since(start);
+        assert!(changes.resync);
+        assert_eq!(changes.added.len(), mempool.get_all_transactions().len());
+
+        // So is a `since` the pool has not reached, even the largest one
+        for since in [mempool.changes_since(0).seq + 1, u64::MAX] {
+            let changes = mempool.changes_since(since);
+            assert!(changes.resync);
+            assert_eq!(changes.added.len(), mempool.get_all_transactions().len());
+        }
+    }
+}